@@ -0,0 +1,61 @@
+//! The backends that targets are copied to. A target is copied to the local filesystem, unless its
+//! path starts with a scheme (such as `s3://` or `sftp://`) that selects another backend.
+
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+use futures_util::future::BoxFuture;
+
+use crate::{s3, sftp, sftp::SftpOptions, CopyOutcome, ResolvedTarget};
+
+/// Somewhere that the source of a target can be copied to.
+pub trait Backend: Debug + Send + Sync {
+    /// Copy the source of the target to the backend.
+    fn copy<'a>(
+        &'a self,
+        target: &'a ResolvedTarget,
+    ) -> BoxFuture<'a, std::io::Result<CopyOutcome>>;
+
+    /// Whether the target is a path on the local filesystem. Only local targets support the
+    /// options that change the file at the target, such as its mode and owner.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Choose the backend for the given target path from its scheme.
+pub fn for_target(path: &Path, sftp: &SftpOptions) -> std::io::Result<Arc<dyn Backend>> {
+    match path.to_str() {
+        Some(url) if url.starts_with("s3://") => s3::backend(url),
+        Some(url) if url.starts_with("sftp://") => sftp::backend(url, sftp),
+        _ => Ok(Arc::new(Local)),
+    }
+}
+
+/// Copies targets to the local filesystem.
+#[derive(Debug)]
+pub struct Local;
+
+impl Backend for Local {
+    fn copy<'a>(
+        &'a self,
+        target: &'a ResolvedTarget,
+    ) -> BoxFuture<'a, std::io::Result<CopyOutcome>> {
+        // Copying (and hashing) a large file can take a while, so we do all of the work on the
+        // blocking thread pool rather than stalling the event loop. The blocking thread does not
+        // inherit our span, so we carry it over.
+        let target = target.clone();
+        let span = tracing::Span::current();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || span.in_scope(|| target.copy_file()))
+                .await
+                .unwrap_or_else(|err| {
+                    tracing::error!("Copy task failed: {err:?}");
+                    Err(std::io::Error::other(err))
+                })
+        })
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
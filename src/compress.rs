@@ -0,0 +1,130 @@
+//! Compressing targets on their way from the source, for consumers that want compressed files.
+//! The contents are streamed through the compressor after any transform, and the target is given
+//! the suffix of the format. Compressing with zstd is only possible when the `zstd` feature is
+//! enabled.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+/// The format that a target is compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// The compression of a target, along with the level to compress at.
+#[derive(Debug, Clone, Copy)]
+pub struct Compress {
+    pub format: Compression,
+    pub level: Option<u32>,
+}
+
+impl Compression {
+    // The suffix that is added to targets compressed with the format.
+    fn suffix(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    // The highest level that the format can be compressed at.
+    fn max_level(self) -> u32 {
+        match self {
+            Compression::Gzip => 9,
+            Compression::Zstd => 22,
+        }
+    }
+}
+
+impl Compress {
+    /// Check that the level is one that the format supports, and that this build can compress
+    /// with the format at all.
+    pub fn check(&self, target: &Path) -> std::io::Result<()> {
+        if self.format == Compression::Zstd && !cfg!(feature = "zstd") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("cannot compress {target:?}: zstd compression requires the 'zstd' feature"),
+            ));
+        }
+
+        match self.level {
+            Some(level) if level > self.format.max_level() => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "target {target:?} has a 'compress_level' of {level}, but the highest level \
+                     for {:?} is {}",
+                    self.format,
+                    self.format.max_level()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add the suffix of the format to the target, unless it already has it.
+    pub fn with_suffix(&self, target: PathBuf) -> PathBuf {
+        let suffix = self.format.suffix();
+        if target.as_os_str().to_string_lossy().ends_with(suffix) {
+            return target;
+        }
+
+        let mut target = target.into_os_string();
+        target.push(suffix);
+        PathBuf::from(target)
+    }
+
+    /// Compress everything that the given function writes to the writer.
+    pub fn compress(
+        &self,
+        writer: &mut dyn Write,
+        copy: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        match self.format {
+            Compression::Gzip => {
+                let level = self
+                    .level
+                    .map_or_else(flate2::Compression::default, flate2::Compression::new);
+                let mut encoder = GzEncoder::new(writer, level);
+                copy(&mut encoder)?;
+                encoder.finish()?;
+            }
+            Compression::Zstd => zstd(writer, self.level, copy)?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd(
+    writer: &mut dyn Write,
+    level: Option<u32>,
+    copy: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let level = level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| level as i32);
+    let mut encoder = zstd::Encoder::new(writer, level)?;
+    copy(&mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+// This is never reached, as the target is rejected by 'Compress::check' when it is resolved.
+#[cfg(not(feature = "zstd"))]
+fn zstd(
+    _: &mut dyn Write,
+    _: Option<u32>,
+    _: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "zstd compression requires the 'zstd' feature",
+    ))
+}
@@ -0,0 +1,160 @@
+//! A control socket, through which a running copier can be asked what it is watching, or to copy
+//! a target straight away. Each line written to the socket is a command, and each response is a
+//! single line of JSON.
+
+use std::{os::unix::fs::FileTypeExt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+/// The `[control]` section of the configuration.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ControlConfig {
+    /// The path of the Unix domain socket, such as `/run/cc.sock`.
+    pub socket: PathBuf,
+}
+
+/// A command received on the control socket.
+#[derive(Debug)]
+pub enum Command {
+    /// List each of the active watches.
+    List,
+    /// Copy the targets with the given source straight away.
+    Sync(PathBuf),
+    /// Copy all of the targets straight away.
+    SyncAll,
+}
+
+/// A command that is waiting to be answered by the event loop.
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<String>,
+}
+
+/// An active watch, as reported by the `list` command.
+#[derive(Debug, Serialize)]
+pub struct Watch {
+    /// The watch descriptor, which is absent for a polled source.
+    pub wd: Option<i32>,
+    /// Whether the watch is on a source file, on a directory of a recursive or glob target, on the
+    /// directory of a source that does not exist yet, on the directory containing the sources of
+    /// watched files, or is a source that is polled.
+    pub kind: &'static str,
+    pub path: PathBuf,
+    pub targets: Vec<WatchTarget>,
+}
+
+/// A target of an active watch.
+#[derive(Debug, Serialize)]
+pub struct WatchTarget {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub events: Vec<&'static str>,
+}
+
+/// Respond to a command with the given value, or with an error if it cannot be serialized.
+pub fn respond(request: Request, value: impl Serialize) {
+    let response = serde_json::to_string(&value).unwrap_or_else(|err| error(err.to_string()));
+    let _ = request.reply.send(response);
+}
+
+// The response to a command that failed.
+fn error(message: String) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// Listen on the configured socket, returning the channel on which the commands will be received.
+/// Any existing socket at the path is replaced, as it will have been left behind by an earlier
+/// run, but anything else at the path is left alone.
+pub fn bind(config: &ControlConfig) -> std::io::Result<mpsc::Receiver<Request>> {
+    if let Ok(metadata) = std::fs::symlink_metadata(&config.socket) {
+        if !metadata.file_type().is_socket() {
+            tracing::error!(socket = %config.socket.display(),
+                            "Control socket path exists and is not a socket");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "control socket {:?} already exists and is not a socket",
+                    config.socket
+                ),
+            ));
+        }
+
+        std::fs::remove_file(&config.socket).map_err(|err| {
+            tracing::error!(socket = %config.socket.display(),
+                            "Failed to remove existing control socket: {err:?}");
+            err
+        })?;
+    }
+
+    let listener = UnixListener::bind(&config.socket).map_err(|err| {
+        tracing::error!(socket = %config.socket.display(), "Failed to bind control socket: {err:?}");
+        err
+    })?;
+
+    tracing::info!(socket = %config.socket.display(), "Serving control socket");
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    tracing::warn!("Failed to accept control connection: {err:?}");
+                    continue;
+                }
+            };
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle(stream, sender).await {
+                    tracing::debug!("Control connection failed: {err:?}");
+                }
+            });
+        }
+    });
+
+    Ok(receiver)
+}
+
+// Answer each of the commands on a connection in turn, until it is closed.
+async fn handle(stream: UnixStream, sender: mpsc::Sender<Request>) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let mut response = match parse(&line) {
+            Ok(command) => {
+                let (reply, response) = oneshot::channel();
+                if sender.send(Request { command, reply }).await.is_err() {
+                    return Ok(());
+                }
+
+                response
+                    .await
+                    .unwrap_or_else(|_| error("shutting down".into()))
+            }
+
+            Err(message) => error(message),
+        };
+
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (name, argument) = line.split_once(' ').unwrap_or((line, ""));
+    match (name, argument.trim()) {
+        ("list", "") => Ok(Command::List),
+        ("sync", "") => Ok(Command::SyncAll),
+        ("sync", source) => Ok(Command::Sync(PathBuf::from(source))),
+        _ => Err(format!("unknown command {line:?}")),
+    }
+}
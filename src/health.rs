@@ -0,0 +1,107 @@
+//! A health check endpoint, for use as a liveness or readiness probe. The endpoint reports whether
+//! the event loop is running and every target is in sync with its source, and the status of each
+//! copyset is served as JSON.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::http;
+
+/// The `[health]` section of the configuration.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HealthConfig {
+    /// The address on which to serve the health check, such as `0.0.0.0:8080`.
+    pub listen: SocketAddr,
+}
+
+/// The status of the copysets, as served at `/status`.
+#[derive(Debug, Default, Serialize)]
+pub struct Status {
+    /// Whether the initial copies have completed, and every target is in sync with its source.
+    pub ready: bool,
+    pub copysets: BTreeMap<String, CopysetStatus>,
+}
+
+/// The status of a single copyset.
+#[derive(Debug, Default, Serialize)]
+pub struct CopysetStatus {
+    /// The number of targets, counting each recursive or glob target once.
+    pub targets: usize,
+    /// The sources that do not exist yet.
+    pub missing: Vec<PathBuf>,
+    /// The targets whose most recent copy failed.
+    pub failing: Vec<PathBuf>,
+}
+
+// Whether the event loop is currently running.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Whether the health check is being served, so that the status is worth publishing.
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+// Whether every target was in sync when the status was last published, and the status itself.
+static READY: AtomicBool = AtomicBool::new(false);
+static STATUS: Mutex<String> = Mutex::new(String::new());
+
+/// Record whether the event loop is running.
+pub fn set_running(running: bool) {
+    RUNNING.store(running, Ordering::SeqCst);
+}
+
+/// Whether the health check is being served.
+pub fn is_serving() -> bool {
+    SERVING.load(Ordering::SeqCst)
+}
+
+/// Record the status of the copysets, which is served until it is next published.
+pub fn set_status(status: Status) {
+    READY.store(status.ready, Ordering::SeqCst);
+    let json = serde_json::to_string(&status).unwrap_or_default();
+    *STATUS.lock().unwrap() = json;
+}
+
+/// Serve the health check at the configured address. This never returns, unless the listener
+/// cannot be bound.
+pub async fn serve(config: &HealthConfig) -> std::io::Result<()> {
+    let listener = http::bind("health check", config.listen).await?;
+    SERVING.store(true, Ordering::SeqCst);
+    http::serve(listener, handle).await;
+    Ok(())
+}
+
+fn handle(request: &Request<Incoming>) -> Response<Full<Bytes>> {
+    let running = RUNNING.load(Ordering::SeqCst);
+    let healthy = match request.uri().path() {
+        "/livez" => running,
+        "/healthz" => running && READY.load(Ordering::SeqCst),
+        "/status" => {
+            let status = STATUS.lock().unwrap().clone();
+            return http::response(StatusCode::OK, "application/json", status);
+        }
+        _ => return http::response(StatusCode::NOT_FOUND, "text/plain", "not found\n"),
+    };
+
+    if healthy {
+        http::response(StatusCode::OK, "text/plain", "ok\n")
+    } else {
+        http::response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "text/plain",
+            "unavailable\n",
+        )
+    }
+}
@@ -0,0 +1,65 @@
+//! A minimal HTTP server, used to serve the health check and metrics endpoints.
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+/// A function that produces the response to a request.
+pub type Handler = fn(&Request<Incoming>) -> Response<Full<Bytes>>;
+
+/// Bind a listener to the given address. The name of the server is only used for logging.
+pub async fn bind(name: &str, listen: SocketAddr) -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(listen).await.map_err(|err| {
+        tracing::error!(listen = ?listen, "Failed to bind {name} listener: {err:?}");
+        err
+    })?;
+
+    tracing::info!(listen = ?listen, "Serving {name}");
+    Ok(listener)
+}
+
+/// Accept connections on the listener, answering each request with the given handler. This never
+/// returns.
+pub async fn serve(listener: TcpListener, handler: Handler) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::warn!("Failed to accept connection: {err:?}");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let service =
+                service_fn(|request| async move { Ok::<_, Infallible>(handler(&request)) });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Connection failed: {err:?}");
+            }
+        });
+    }
+}
+
+/// Build a response with the given status, content type and body.
+pub fn response(
+    status: StatusCode,
+    content_type: &'static str,
+    body: impl Into<Bytes>,
+) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .body(Full::new(body.into()))
+        .expect("valid response")
+}
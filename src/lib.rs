@@ -0,0 +1,5807 @@
+//! The engine of `container-copier`, which watches the sources of each copyset and copies them to
+//! their targets when they change. The binary is a thin wrapper around this crate, which can also
+//! be used to embed the copier in another program:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! let config = container_copier::Config::load("/config/container-copier.toml")?;
+//! let env = config.setup(false, Default::default()).await?;
+//! env.run("/config/container-copier.toml".as_ref(), None).await
+//! # }
+//! ```
+//!
+//! As with the binary, a running copier reloads its configuration on `SIGHUP` and stops on
+//! `SIGTERM` or `SIGINT`.
+
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet, VecDeque},
+    ffi::{OsStr, OsString},
+    hash::{BuildHasher, Hasher},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{chown, FileTypeExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use filetime::FileTime;
+use futures_util::{FutureExt, StreamExt};
+use glob::{MatchOptions, Pattern};
+use inotify::{EventMask, EventOwned, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use nix::unistd::{Group, User};
+use serde::{
+    de::{value::StrDeserializer, DeserializeOwned, IgnoredAny, IntoDeserializer},
+    Deserialize, Serialize,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::AsyncWriteExt,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, Semaphore},
+    task::{JoinError, JoinSet},
+    time::Instant,
+};
+use tracing::Instrument;
+
+use crate::{
+    backend::Backend,
+    compress::{Compress, Compression},
+    control::{ControlConfig, Request},
+    health::HealthConfig,
+    metrics::MetricsConfig,
+    otel::OtelConfig,
+    sftp::SftpOptions,
+    throttle::RateLimiter,
+    transform::Transform,
+};
+
+mod backend;
+mod compress;
+pub mod control;
+mod health;
+mod http;
+mod metrics;
+pub mod otel;
+mod s3;
+mod sftp;
+mod systemd;
+mod template;
+mod throttle;
+mod transform;
+
+/// The copysets to run, as selected on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct CopysetFilter {
+    /// When not empty, only the copysets with these names are run.
+    pub only: Vec<String>,
+    /// The names of copysets that are not run.
+    pub exclude: Vec<String>,
+}
+
+impl CopysetFilter {
+    fn is_selected(&self, name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|only| only == name))
+            && !self.exclude.iter().any(|exclude| exclude == name)
+    }
+}
+
+// A function that is called with the source and target of each target once its initial copy has
+// been started. See 'set_initial_copy_hook'.
+static INITIAL_COPY_HOOK: OnceLock<fn(&Path, &Path)> = OnceLock::new();
+
+/// Set a function to be called with the source and target of each target once its initial copy
+/// has been started, before [`Config::setup`] goes on to the next target. This is only for the
+/// tests, so that they can change a source while it is being set up. The hook can only be set
+/// once.
+#[doc(hidden)]
+pub fn set_initial_copy_hook(hook: fn(&Path, &Path)) {
+    let _ = INITIAL_COPY_HOOK.set(hook);
+}
+
+// A function that is called with each target once its contents have been written. See
+// 'set_target_written_hook'.
+static TARGET_WRITTEN_HOOK: OnceLock<fn(&Path)> = OnceLock::new();
+
+/// Set a function to be called with the path of each target once its contents have been written,
+/// before they are read back to verify them. This is only for the tests, so that they can change a
+/// target before it is verified. The hook can only be set once.
+#[doc(hidden)]
+pub fn set_target_written_hook(hook: fn(&Path)) {
+    let _ = TARGET_WRITTEN_HOOK.set(hook);
+}
+
+/// The format in which '--print-config' prints the configuration.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+enum NotifyEvent {
+    #[serde(rename(serialize = "ACCESS"), alias = "ACCESS")]
+    Access,
+    #[serde(rename(serialize = "ATTRIB"), alias = "ATTRIB")]
+    Attrib,
+    #[serde(rename(serialize = "CLOSE_WRITE"), alias = "CLOSE_WRITE")]
+    CloseWrite,
+    #[serde(rename(serialize = "CLOSE_NOWRITE"), alias = "CLOSE_NOWRITE")]
+    CloseNoWrite,
+    #[serde(rename(serialize = "CREATE"), alias = "CREATE")]
+    Create,
+    #[serde(rename(serialize = "DELETE"), alias = "DELETE")]
+    Delete,
+    #[serde(rename(serialize = "DELETE_SELF"), alias = "DELETE_SELF")]
+    DeleteSelf,
+    #[serde(rename(serialize = "MODIFY"), alias = "MODIFY")]
+    Modify,
+    #[serde(rename(serialize = "MOVE_SELF"), alias = "MOVE_SELF")]
+    MoveSelf,
+    #[serde(rename(serialize = "MOVED_FROM"), alias = "MOVED_FROM")]
+    MovedFrom,
+    #[serde(rename(serialize = "MOVED_TO"), alias = "MOVED_TO")]
+    MovedTo,
+    #[serde(rename(serialize = "OPEN"), alias = "OPEN")]
+    Open,
+    #[serde(rename(serialize = "ALL"), alias = "ALL")]
+    All,
+    #[serde(rename(serialize = "MOVE"), alias = "MOVE")]
+    Move,
+    #[serde(rename(serialize = "CLOSE"), alias = "CLOSE")]
+    Close,
+}
+
+/// A flag that changes how a source is watched, rather than being an event to watch for.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+enum WatchFlag {
+    /// Watch a symbolic link itself, rather than the file that it points to.
+    #[serde(rename(serialize = "DONT_FOLLOW"), alias = "DONT_FOLLOW")]
+    DontFollow,
+    /// Stop reporting events for the files in a directory once they have been unlinked.
+    #[serde(rename(serialize = "EXCL_UNLINK"), alias = "EXCL_UNLINK")]
+    ExclUnlink,
+    /// Remove the watch after its first event.
+    #[serde(rename(serialize = "ONESHOT"), alias = "ONESHOT")]
+    Oneshot,
+}
+
+// Parse the name of an event or a flag, which is either as it would be given in a list (such as
+// "CLOSE_WRITE" or "CloseWrite"), or in lower case (such as "close_write").
+fn parse_name<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let name = name.trim();
+    [name.to_string(), name.to_ascii_uppercase()]
+        .iter()
+        .find_map(|name| {
+            let deserializer: StrDeserializer<serde::de::value::Error> =
+                name.as_str().into_deserializer();
+            T::deserialize(deserializer).ok()
+        })
+}
+
+impl NotifyEvent {
+    fn parse(name: &str) -> Result<Self, String> {
+        parse_name(name).ok_or_else(|| match parse_name::<WatchFlag>(name) {
+            Some(_) => format!("{name:?} is a flag rather than an event; give it in 'flags'"),
+            None => format!("unknown event {name:?}"),
+        })
+    }
+}
+
+impl WatchFlag {
+    fn parse(name: &str) -> Result<Self, String> {
+        parse_name(name).ok_or_else(|| match parse_name::<NotifyEvent>(name) {
+            Some(_) => format!("{name:?} is an event rather than a flag; give it in 'events'"),
+            None => format!("unknown flag {name:?}"),
+        })
+    }
+}
+
+/// The events to watch for, which can be given either as a list of events or as a single string of
+/// events separated by '|', such as `"create|delete|modify"`. At least one event must be given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "NamesValue")]
+struct Events(Vec<NotifyEvent>);
+
+/// The flags of a watch, which are given in the same way as the events.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "NamesValue")]
+struct Flags(Vec<WatchFlag>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NamesValue {
+    List(Vec<String>),
+    String(String),
+}
+
+impl NamesValue {
+    fn names(&self) -> Vec<&str> {
+        match self {
+            NamesValue::List(names) => names.iter().map(String::as_str).collect(),
+            NamesValue::String(names) => names.split('|').collect(),
+        }
+    }
+}
+
+impl TryFrom<NamesValue> for Events {
+    type Error = String;
+
+    fn try_from(value: NamesValue) -> Result<Self, Self::Error> {
+        let events = value
+            .names()
+            .into_iter()
+            .map(NotifyEvent::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if events.is_empty() {
+            return Err("at least one event must be given".to_string());
+        }
+
+        Ok(Events(events))
+    }
+}
+
+impl TryFrom<NamesValue> for Flags {
+    type Error = String;
+
+    fn try_from(value: NamesValue) -> Result<Self, Self::Error> {
+        value
+            .names()
+            .into_iter()
+            .map(WatchFlag::parse)
+            .collect::<Result<_, _>>()
+            .map(Flags)
+    }
+}
+
+impl From<NotifyEvent> for WatchMask {
+    fn from(value: NotifyEvent) -> Self {
+        match value {
+            NotifyEvent::Access => WatchMask::ACCESS,
+            NotifyEvent::Attrib => WatchMask::ATTRIB,
+            NotifyEvent::CloseWrite => WatchMask::CLOSE_WRITE,
+            NotifyEvent::CloseNoWrite => WatchMask::CLOSE_NOWRITE,
+            NotifyEvent::Create => WatchMask::CREATE,
+            NotifyEvent::Delete => WatchMask::DELETE,
+            NotifyEvent::DeleteSelf => WatchMask::DELETE_SELF,
+            NotifyEvent::Modify => WatchMask::MODIFY,
+            NotifyEvent::MoveSelf => WatchMask::MOVE_SELF,
+            NotifyEvent::MovedFrom => WatchMask::MOVED_FROM,
+            NotifyEvent::MovedTo => WatchMask::MOVED_TO,
+            NotifyEvent::Open => WatchMask::OPEN,
+            NotifyEvent::All => WatchMask::ALL_EVENTS,
+            NotifyEvent::Move => WatchMask::MOVE,
+            NotifyEvent::Close => WatchMask::CLOSE,
+        }
+    }
+}
+
+impl From<WatchFlag> for WatchMask {
+    fn from(value: WatchFlag) -> Self {
+        match value {
+            WatchFlag::DontFollow => WatchMask::DONT_FOLLOW,
+            WatchFlag::ExclUnlink => WatchMask::EXCL_UNLINK,
+            WatchFlag::Oneshot => WatchMask::ONESHOT,
+        }
+    }
+}
+
+// The names of the individual events that can be watched, used when describing a watch.
+const EVENT_NAMES: &[(&str, WatchMask)] = &[
+    ("ACCESS", WatchMask::ACCESS),
+    ("ATTRIB", WatchMask::ATTRIB),
+    ("CLOSE_WRITE", WatchMask::CLOSE_WRITE),
+    ("CLOSE_NOWRITE", WatchMask::CLOSE_NOWRITE),
+    ("CREATE", WatchMask::CREATE),
+    ("DELETE", WatchMask::DELETE),
+    ("DELETE_SELF", WatchMask::DELETE_SELF),
+    ("MODIFY", WatchMask::MODIFY),
+    ("MOVE_SELF", WatchMask::MOVE_SELF),
+    ("MOVED_FROM", WatchMask::MOVED_FROM),
+    ("MOVED_TO", WatchMask::MOVED_TO),
+    ("OPEN", WatchMask::OPEN),
+];
+
+/// A configuration file that is included by another, which can only contain copysets.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFragment {
+    #[serde(default)]
+    copysets: Vec<Copyset>,
+}
+
+// The smallest buffer that is guaranteed to hold a single inotify event: the fixed size of the event
+// followed by the longest possible file name and its terminating NUL.
+const MIN_BUFFER_SIZE: usize = 16 + 255 + 1;
+
+/// How [`Config::run`] runs a configuration.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Log the copies that would be made, without touching the filesystem.
+    pub dry_run: bool,
+    /// Copy every source to its target once and then return, without watching for changes.
+    pub once: bool,
+    /// Watch the configuration file, and reload it whenever it changes.
+    pub watch_config: bool,
+    /// The copysets to run.
+    pub filter: CopysetFilter,
+}
+
+/// A configuration file, as loaded by [`Config::load`] or parsed from TOML with
+/// [`str::parse`].
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    #[serde(default)]
+    copysets: Vec<Copyset>,
+    // Patterns matching other configuration files, relative to the directory containing this
+    // file, whose copysets are added to those of this file. Once loaded, the included copysets are
+    // among our own, so the patterns are left out of the effective configuration.
+    #[serde(default, skip_serializing)]
+    include: Vec<String>,
+    // The events of any copyset that does not give its own.
+    #[serde(default = "Config::default_events")]
+    default_events: Events,
+    // The number of consecutive times that copying a target can fail before we give up and exit.
+    // When this is not given, failures are logged and we carry on regardless.
+    max_consecutive_failures: Option<u32>,
+    // The maximum number of copies that can be made at the same time.
+    #[serde(default = "Config::default_max_concurrent_copies")]
+    max_concurrent_copies: usize,
+    // The size of the buffer into which inotify events are read.
+    #[serde(default = "Config::default_buffer_size")]
+    buffer_size: ByteSize,
+    // How long to wait before setting up the watches and making the initial copies, along with up
+    // to 'startup_jitter_ms' more chosen at random, so that many copiers that start at the same
+    // time do not all copy at once.
+    #[serde(default)]
+    startup_delay_ms: u64,
+    #[serde(default)]
+    startup_jitter_ms: u64,
+    // Whether the relative source and target paths of each copyset are relative to the directory
+    // containing the file that the copyset is defined in, rather than to the working directory.
+    #[serde(default)]
+    relative_to_config: bool,
+    // How often to copy every target that differs from its source, regardless of any events, as a
+    // safety net for events that were missed, along with up to 'resync_jitter_ms' more chosen at
+    // random each time.
+    resync_interval_ms: Option<u64>,
+    #[serde(default)]
+    resync_jitter_ms: u64,
+    // Where to serve Prometheus metrics, if at all.
+    metrics: Option<MetricsConfig>,
+    // Where to serve the health check, if at all.
+    health: Option<HealthConfig>,
+    // Where to listen for control commands, if at all.
+    control: Option<ControlConfig>,
+    // Where to export the spans of copies to, if at all. This is only read when we start.
+    otel: Option<OtelConfig>,
+}
+
+impl FromStr for Config {
+    type Err = std::io::Error;
+
+    /// Parse a TOML configuration, as though it had been loaded from a file in the current
+    /// directory.
+    fn from_str(buf: &str) -> std::io::Result<Self> {
+        Self::load_str(Path::new("container-copier.toml"), buf)
+    }
+}
+
+impl Config {
+    fn default_events() -> Events {
+        Events(vec![
+            NotifyEvent::Create,
+            NotifyEvent::Delete,
+            NotifyEvent::Modify,
+        ])
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_atomic() -> bool {
+        true
+    }
+
+    fn default_atomic_fallback() -> bool {
+        true
+    }
+
+    fn default_hook_debounce_ms() -> u64 {
+        500
+    }
+
+    fn default_poll_interval_ms() -> u64 {
+        1000
+    }
+
+    fn default_buffer_size() -> ByteSize {
+        ByteSize(16 * 1024)
+    }
+
+    fn default_max_concurrent_copies() -> usize {
+        1
+    }
+
+    // How long to wait before starting, including a random amount of jitter.
+    fn startup_delay(&self) -> Duration {
+        Duration::from_millis(self.startup_delay_ms) + jitter(self.startup_jitter_ms)
+    }
+
+    // How often to resync the targets, and up to how much longer to wait at random each time.
+    fn resync(&self) -> Option<(Duration, u64)> {
+        self.resync_interval_ms
+            .map(|interval| (Duration::from_millis(interval), self.resync_jitter_ms))
+    }
+
+    /// Render the configuration as it is in effect: with the copysets of any included files, the
+    /// environment variables expanded, and each target given the options that it inherits from its
+    /// copyset.
+    pub fn effective(&mut self, format: ConfigFormat) -> std::io::Result<String> {
+        for copyset in &mut self.copysets {
+            copyset.inherit_options();
+        }
+
+        match format {
+            ConfigFormat::Toml => toml::to_string_pretty(self).map_err(std::io::Error::other),
+            ConfigFormat::Json => serde_json::to_string_pretty(self).map_err(std::io::Error::other),
+        }
+    }
+
+    /// Copy every target when it is first watched, regardless of its `copy_on_start` option.
+    pub fn force_copy_on_start(&mut self) {
+        for copyset in &mut self.copysets {
+            copyset.copy_on_start = CopyOnStart::Always;
+            for target in &mut copyset.targets {
+                target.copy_on_start = None;
+            }
+        }
+    }
+
+    /// Remove any copysets that are not selected by the filter, logging each one that is skipped.
+    pub fn select(&mut self, filter: &CopysetFilter) {
+        for name in filter.only.iter().chain(&filter.exclude) {
+            if !self.copysets.iter().any(|copyset| copyset.name == *name) {
+                tracing::warn!(copyset = name, "No copyset has this name");
+            }
+        }
+
+        self.copysets.retain(|copyset| {
+            if !copyset.enabled {
+                tracing::info!(copyset = copyset.name, "Skipping disabled copyset");
+                return false;
+            }
+
+            let selected = filter.is_selected(&copyset.name);
+            if !selected {
+                tracing::info!(copyset = copyset.name, "Skipping copyset");
+            }
+
+            selected
+        });
+    }
+
+    /// Load config from the given path, expanding any environment variables in the paths. The
+    /// format of the file is chosen by its extension, defaulting to TOML. When the path is a
+    /// directory, each of the configuration files in it is loaded, as with [`Config::load_dir`].
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return Self::load_dir(path);
+        }
+
+        Self::load_str(path, &std::fs::read_to_string(path)?)
+    }
+
+    /// Load each of the configuration files in a directory, in order of their names, and merge
+    /// their copysets. The settings at the top of the configuration (such as `[health]`) can only
+    /// be given in the first of the files, and no two files can define a copyset with the same
+    /// name.
+    pub fn load_dir(directory: &Path) -> std::io::Result<Self> {
+        let mut merged: Option<Self> = None;
+        let mut names = HashMap::new();
+        let mut files = HashSet::new();
+        for path in Self::config_files(directory)? {
+            // A file that is included by an earlier file has already been loaded along with it.
+            if files.contains(&canonical(&path)) {
+                tracing::debug!(path = %path.display(), "Configuration file is already included");
+                continue;
+            }
+
+            tracing::info!(path = %path.display(), "Loading configuration file");
+            let buf = std::fs::read_to_string(&path)?;
+            let config = Self::parse_file(&path, &buf, &mut names, &mut files).map_err(|err| {
+                tracing::error!(path = %path.display(), "Failed to load configuration file: {err:?}");
+                err
+            })?;
+
+            let Some(merged) = &mut merged else {
+                merged = Some(config);
+                continue;
+            };
+
+            let keys: BTreeMap<String, IgnoredAny> = Self::parse(&path, &buf)?;
+            if let Some(key) = keys
+                .keys()
+                .find(|key| *key != "copysets" && *key != "include")
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{key:?} in {path:?} can only be set in the first configuration file in \
+                         {directory:?}"
+                    ),
+                ));
+            }
+
+            merged.copysets.extend(config.copysets);
+        }
+
+        let Some(config) = merged else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no configuration files in {directory:?}"),
+            ));
+        };
+
+        config.finish()
+    }
+
+    // The configuration files in a directory, in order of their names. These are the files with
+    // any of the extensions that we can load.
+    fn config_files(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(OsStr::to_str);
+            if matches!(extension, Some("toml" | "json" | "yaml" | "yml")) && path.is_file() {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+
+    // Load config that has been read from the given path, which is used to choose the format and
+    // to find any included files.
+    fn load_str(path: &Path, buf: &str) -> std::io::Result<Self> {
+        Self::parse_file(path, buf, &mut HashMap::new(), &mut HashSet::new())?.finish()
+    }
+
+    // Parse config that has been read from the given path, along with any files that it includes,
+    // recording which file each copyset was defined in. The names of the copysets and the files
+    // that have been loaded are recorded as well, so that no two copysets have the same name and no
+    // file is loaded twice, across all of the files of the configuration.
+    fn parse_file(
+        path: &Path,
+        buf: &str,
+        names: &mut HashMap<String, PathBuf>,
+        files: &mut HashSet<PathBuf>,
+    ) -> std::io::Result<Self> {
+        let mut config: Self = Self::parse(path, buf)?;
+        for copyset in &mut config.copysets {
+            copyset.defined_in = path.to_path_buf();
+        }
+
+        files.insert(canonical(path));
+        record_names(names, &config.copysets, path)?;
+        config.include_files(path, names, files)?;
+        Ok(config)
+    }
+
+    // Finish loading the config once all of its files have been parsed, expanding the environment
+    // variables and filling in the defaults, and check the settings.
+    fn finish(mut self) -> std::io::Result<Self> {
+        self.expand_env()?;
+        if self.relative_to_config {
+            self.resolve_relative_paths()?;
+        }
+
+        for copyset in &mut self.copysets {
+            // Everything else that looks at the sources expects there to be at least one of them.
+            if copyset.sources.paths().is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("copyset {:?} has an empty list of sources", copyset.name),
+                ));
+            }
+
+            if copyset.events.is_none() {
+                copyset.events = Some(self.default_events.clone());
+            }
+        }
+
+        if self.max_concurrent_copies == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "'max_concurrent_copies' must be at least 1",
+            ));
+        }
+
+        if self.resync_interval_ms == Some(0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "'resync_interval_ms' must be greater than zero",
+            ));
+        }
+
+        if self.buffer_size.0 < MIN_BUFFER_SIZE as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("'buffer_size' must be at least {MIN_BUFFER_SIZE} bytes"),
+            ));
+        }
+
+        Ok(self)
+    }
+
+    /// Log how many copysets and targets are in the configuration, and how many watches setting it
+    /// up would add. Recursive and glob targets are each counted once, and targets that cannot be
+    /// resolved are left to be reported when the configuration is set up.
+    pub fn log_summary(&self) {
+        let resolved = self
+            .copysets
+            .iter()
+            .filter_map(|copyset| copyset.resolve().ok())
+            .collect::<Vec<_>>();
+
+        let targets = resolved.iter().map(Vec::len).sum::<usize>();
+        let watches = resolved
+            .iter()
+            .flatten()
+            .flat_map(ResolvedTarget::watch_paths)
+            .collect::<HashSet<_>>()
+            .len();
+
+        tracing::info!(
+            copysets = self.copysets.len(),
+            targets,
+            watches,
+            "Loaded configuration"
+        );
+    }
+
+    /// Load only the `[otel]` section of the configuration, which is needed to set up the log
+    /// before the rest of the configuration is loaded. Any problem with the file is left to be
+    /// reported when it is loaded properly.
+    pub fn load_otel(path: &Path) -> Option<OtelConfig> {
+        #[derive(Deserialize)]
+        struct Section {
+            otel: Option<OtelConfig>,
+        }
+
+        let path = match path.is_dir() {
+            true => Self::config_files(path).ok()?.into_iter().next()?,
+            false => path.to_path_buf(),
+        };
+
+        let buf = std::fs::read_to_string(&path).ok()?;
+        Self::parse::<Section>(&path, &buf).ok()?.otel
+    }
+
+    // Load each of the files that match the 'include' patterns, and add their copysets to ours. The
+    // files matching each pattern are included in order of their paths. No copyset can have the
+    // same name as one that has already been loaded.
+    fn include_files(
+        &mut self,
+        path: &Path,
+        names: &mut HashMap<String, PathBuf>,
+        files: &mut HashSet<PathBuf>,
+    ) -> std::io::Result<()> {
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        // A file that matches more than one pattern (or that has already been loaded, such as
+        // this file itself) is only loaded once.
+        let mut included = Vec::new();
+        for pattern in &self.include {
+            let pattern = base.join(pattern);
+            let mut paths = glob::glob(&pattern.to_string_lossy())
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(std::io::Error::from)?;
+            paths.sort();
+
+            if paths.is_empty() {
+                tracing::warn!(pattern = %pattern.display(), "No configuration files match include");
+            }
+
+            for path in paths {
+                if !files.insert(canonical(&path)) {
+                    tracing::debug!(path = %path.display(), "Configuration is already included");
+                    continue;
+                }
+
+                tracing::info!(path = %path.display(), "Including configuration");
+                let fragment: ConfigFragment = Self::parse(&path, &std::fs::read_to_string(&path)?)
+                    .map_err(|err| {
+                        tracing::error!(path = %path.display(),
+                                        "Failed to load included configuration: {err:?}");
+                        err
+                    })?;
+
+                record_names(names, &fragment.copysets, &path)?;
+                for copyset in fragment.copysets {
+                    included.push(Copyset {
+                        defined_in: path.clone(),
+                        ..copyset
+                    });
+                }
+            }
+        }
+
+        self.copysets.extend(included);
+        Ok(())
+    }
+
+    // Make the relative source and target paths of each copyset relative to the directory of the
+    // file that it was defined in. Targets that are URLs (such as 's3://') are left as they are.
+    fn resolve_relative_paths(&mut self) -> std::io::Result<()> {
+        for copyset in &mut self.copysets {
+            let directory = copyset
+                .defined_in
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let base = std::path::absolute(directory)?;
+            for path in copyset.sources.paths_mut() {
+                *path = base.join(&*path);
+            }
+
+            if !copyset.target.to_string_lossy().contains("://") {
+                copyset.target = base.join(&copyset.target);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse<T: DeserializeOwned>(path: &Path, buf: &str) -> std::io::Result<T> {
+        match path.extension().and_then(OsStr::to_str) {
+            #[cfg(feature = "json")]
+            Some("json") => serde_json::from_str(buf).map_err(std::io::Error::other),
+            #[cfg(feature = "yaml")]
+            Some("yaml" | "yml") => serde_yaml::from_str(buf).map_err(std::io::Error::other),
+            #[cfg(not(feature = "json"))]
+            Some("json") => Err(Self::unsupported(path, "json")),
+            #[cfg(not(feature = "yaml"))]
+            Some("yaml" | "yml") => Err(Self::unsupported(path, "yaml")),
+            _ => toml::from_str(buf).map_err(std::io::Error::other),
+        }
+    }
+
+    #[cfg(not(all(feature = "json", feature = "yaml")))]
+    fn unsupported(path: &Path, feature: &str) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("cannot load {path:?}, as this build does not include the '{feature}' feature"),
+        )
+    }
+
+    // Expand any references to environment variables in the source and target paths of each
+    // copyset and target, and in the paths of the SFTP options.
+    fn expand_env(&mut self) -> std::io::Result<()> {
+        for copyset in &mut self.copysets {
+            for path in copyset.sources.paths_mut() {
+                *path = expand_path(path)?;
+            }
+            copyset.target = expand_path(&copyset.target)?;
+            let sftp = &mut copyset.sftp;
+            for path in sftp.identity.iter_mut().chain(sftp.known_hosts.iter_mut()) {
+                *path = expand_path(path)?;
+            }
+            for target in &mut copyset.targets {
+                target.source = expand_path(&target.source)?;
+                for path in target.target.iter_mut().flat_map(Paths::paths_mut) {
+                    *path = expand_path(path)?;
+                }
+
+                if let Some(latest) = &mut target.latest {
+                    *latest = expand_path(latest)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the configuration for problems without setting up any watches, returning a
+    /// description of each problem found.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut resolved = Vec::new();
+
+        for copyset in &self.copysets {
+            let name = &copyset.name;
+            if !copyset.source_exists() {
+                problems.push(format!(
+                    "copyset {name:?}: source {:?} does not exist",
+                    copyset.source()
+                ));
+            }
+
+            let targets = match copyset.resolve() {
+                Ok(targets) => targets,
+                Err(err) => {
+                    problems.push(format!("copyset {name:?}: {err}"));
+                    continue;
+                }
+            };
+
+            for target in &targets {
+                let source = &target.source;
+                if target.options.recursive {
+                    if !source.is_dir() {
+                        problems.push(format!(
+                            "copyset {name:?}: recursive source {source:?} is not a directory"
+                        ));
+                    }
+                } else if target.options.glob.is_some() {
+                    // Files matching the glob may appear later, but the directories that would
+                    // contain them need to exist now.
+                    let parent = source.parent().unwrap_or(Path::new("/"));
+                    let directories =
+                        glob::glob_with(&parent.to_string_lossy(), glob_match_options())
+                            .map(|paths| {
+                                paths
+                                    .filter_map(Result::ok)
+                                    .filter(|path| path.is_dir())
+                                    .count()
+                            })
+                            .unwrap_or(0);
+                    if directories == 0 {
+                        problems.push(format!(
+                            "copyset {name:?}: no directories match the glob {source:?}"
+                        ));
+                    }
+                } else if source.is_dir() {
+                    problems.push(format!(
+                        "copyset {name:?}: source {source:?} is a directory, but the target is \
+                         not marked as 'directory' or 'recursive'"
+                    ));
+                } else if !source.is_file() {
+                    problems.push(format!("copyset {name:?}: source {source:?} is not a file"));
+                }
+            }
+
+            resolved.push((name.as_str(), targets));
+        }
+
+        let resolved = resolved
+            .iter()
+            .map(|(name, targets)| (*name, targets.as_slice()));
+        for (destination, name, other) in Self::overlapping_targets(resolved.clone()) {
+            problems.push(format!(
+                "copyset {name:?}: target {destination:?} is also copied to by copyset {other:?}"
+            ));
+        }
+
+        for (source, name, other) in Self::shared_sources(resolved) {
+            problems.push(format!(
+                "copyset {name:?}: source {source:?} is also watched by copyset {other:?}"
+            ));
+        }
+
+        problems
+    }
+
+    // Find each source that is watched by more than one copyset, given the targets of each
+    // copyset. The targets of one copyset can share a source, as can polled targets, which are not
+    // watched. Each source is given along with the name of the copyset of the target that watches
+    // it, and of the copyset of the earlier target that also does.
+    fn shared_sources<'a>(
+        resolved: impl IntoIterator<Item = (&'a str, &'a [ResolvedTarget])>,
+    ) -> Vec<(&'a Path, &'a str, &'a str)> {
+        let mut watched = HashMap::<&Path, &str>::new();
+        let mut shared = Vec::new();
+        for (name, targets) in resolved {
+            for target in targets {
+                if target.options.poll_interval.is_some() {
+                    continue;
+                }
+
+                let other = *watched.entry(&target.source).or_insert(name);
+                if other != name {
+                    shared.push((target.source.as_path(), name, other));
+                }
+            }
+        }
+
+        shared
+    }
+
+    // Find each path that is copied to by more than one target, given the targets of each
+    // copyset, as those targets will overwrite each other. Any number of targets can share the
+    // same source, though. Each path is given along with the name of the copyset of the target
+    // that copies to it, and of the copyset of the earlier target that also does.
+    fn overlapping_targets<'a>(
+        resolved: impl IntoIterator<Item = (&'a str, &'a [ResolvedTarget])>,
+    ) -> Vec<(&'a Path, &'a str, &'a str)> {
+        let mut written = HashMap::<&Path, &str>::new();
+        let mut overlaps = Vec::new();
+        for (name, targets) in resolved {
+            for target in targets {
+                if let Some(other) = written.insert(&target.target, name) {
+                    overlaps.push((target.target.as_path(), name, other));
+                }
+            }
+        }
+
+        overlaps
+    }
+
+    /// Run the configuration until we are asked to stop, serving the metrics, health check and
+    /// control socket that it asks for. With `once`, each target is copied and we return once the
+    /// copies are complete, without watching for changes.
+    pub async fn run(mut self, config_path: &Path, options: RunOptions) -> std::io::Result<()> {
+        let RunOptions {
+            dry_run,
+            once,
+            watch_config,
+            filter,
+        } = options;
+
+        if once {
+            self.force_copy_on_start();
+            return self.setup(dry_run, filter).await?.once().await;
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics::serve(metrics).await?;
+        }
+
+        let control = self.control.as_ref().map(control::bind).transpose()?;
+        let run = async {
+            let mut env = self.setup(dry_run, filter).await?;
+            if watch_config {
+                env.watch_config(config_path)?;
+            }
+
+            env.run(config_path, control).await
+        };
+        let Some(health) = &self.health else {
+            return run.await;
+        };
+
+        // The health check is served for as long as we are running, reporting that we are
+        // unavailable until the watches have been set up.
+        tokio::select! {
+            result = run => result,
+            result = health::serve(health) => result,
+        }
+    }
+
+    /// Add the targets of each copyset, making any initial copies, ready to watch for changes. The
+    /// filter is used to select the copysets again whenever the configuration is reloaded.
+    pub async fn setup(&self, dry_run: bool, filter: CopysetFilter) -> std::io::Result<Env> {
+        tracing::info!("Creating inotify");
+        let inotify = Inotify::init()?;
+        let mut env = Env {
+            watches: inotify.watches(),
+            stream: inotify.into_event_stream(vec![0; self.buffer_size.0 as usize])?,
+            buffer_size: self.buffer_size.0,
+            targets: HashMap::new(),
+            directories: HashMap::new(),
+            polled: Vec::new(),
+            awaiting: Vec::new(),
+            missing: HashMap::new(),
+            parents: HashMap::new(),
+            pending: HashMap::new(),
+            created_directories: HashSet::new(),
+            history: HashMap::new(),
+            hooks: HashMap::new(),
+            batches: HashMap::new(),
+            copies: JoinSet::new(),
+            copy_limit: Arc::new(Semaphore::new(self.max_concurrent_copies)),
+            copy_limit_size: self.max_concurrent_copies,
+            in_flight: HashMap::new(),
+            config_watch: None,
+            failures: HashMap::new(),
+            retries: HashMap::new(),
+            totals: CopyTotals::default(),
+            max_failures: self.max_consecutive_failures,
+            resync: self.resync(),
+            next_resync: None,
+            startup: None,
+            unwatched: Vec::new(),
+            filter,
+            dry_run,
+        };
+
+        if dry_run {
+            tracing::info!("Dry run: no changes will be made to the filesystem");
+        }
+
+        // Wait for the sources of all of the copysets together, so that the longest wait is the
+        // only one that delays startup.
+        futures_util::future::try_join_all(
+            self.copysets
+                .iter()
+                .map(|copyset| copyset.wait_for_source()),
+        )
+        .await?;
+
+        let delay = self.startup_delay();
+        if !delay.is_zero() {
+            tracing::info!(delay_ms = delay.as_millis() as u64, "Delaying startup");
+            tokio::time::sleep(delay).await;
+        }
+
+        // Resolve the targets of every copyset before watching any of them, so that we can warn
+        // about any targets that will overwrite each other first.
+        let mut resolved = Vec::new();
+        for copyset in &self.copysets {
+            let targets = copyset.resolve().map_err(|err| {
+                tracing::error!(copyset = copyset.name, "Failed to resolve copyset: {err:?}");
+                err
+            })?;
+
+            resolved.push((copyset, targets));
+        }
+
+        let overlaps = Self::overlapping_targets(
+            resolved
+                .iter()
+                .map(|(copyset, targets)| (copyset.name.as_str(), targets.as_slice())),
+        );
+        for (target, copyset, other) in overlaps {
+            tracing::warn!(target = %target.display(), copyset, other,
+                           "Target is also copied to by another target; they will overwrite each other");
+        }
+
+        tracing::info!("Setting up inotify watches");
+        let started = Instant::now();
+        for (copyset, targets) in resolved {
+            let failed = copyset.add_to_watch(&mut env, targets).await;
+            env.unwatched.extend(failed);
+        }
+
+        // List all of the targets that could not be added together, so that they are easy to find.
+        if !env.unwatched.is_empty() {
+            let sources = env
+                .unwatched
+                .iter()
+                .map(|source| source.display().to_string())
+                .collect::<Vec<_>>();
+            tracing::error!(count = sources.len(), sources = ?sources,
+                            "Some targets could not be added, and are not being watched");
+        }
+
+        // The initial copies run in the background while the remaining watches are added, and
+        // then alongside the event loop. We log how long they took when they have all completed.
+        if !env.copies.is_empty() {
+            tracing::info!(copies = env.copies.len(), "Started initial copies");
+            env.startup = Some((started, env.copies.len()));
+        }
+
+        env.schedule_resync();
+        Ok(env)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Copyset {
+    name: String,
+    #[serde(default = "Config::default_enabled")]
+    enabled: bool,
+    // The events of the copyset, which are the 'default_events' of the configuration when not
+    // given.
+    events: Option<Events>,
+    // The flags of the watches of the copyset, such as 'ONESHOT'.
+    flags: Option<Flags>,
+    // The base paths of the sources, in order of preference. The source of each target is found
+    // under the first of them where it exists.
+    #[serde(rename = "source")]
+    sources: Paths,
+    target: PathBuf,
+    #[serde(default)]
+    preserve: bool,
+    #[serde(default = "Config::default_atomic")]
+    atomic: bool,
+    #[serde(default = "Config::default_atomic_fallback")]
+    atomic_fallback: bool,
+    #[serde(default)]
+    skip_if_unchanged: bool,
+    #[serde(default)]
+    debounce_ms: u64,
+    #[serde(default)]
+    mirror_delete: bool,
+    #[serde(default)]
+    on_error: ErrorPolicy,
+    on_copy: Option<Vec<String>>,
+    // The command to run once after a burst of copies of any of the targets, which is given the
+    // paths of all of the targets that were copied.
+    on_batch: Option<Vec<String>>,
+    #[serde(default)]
+    copy_on_start: CopyOnStart,
+    #[serde(default = "Config::default_hook_debounce_ms")]
+    hook_debounce_ms: u64,
+    #[serde(default)]
+    exclude: Vec<String>,
+    rate_limit: Option<ByteSize>,
+    max_size: Option<ByteSize>,
+    #[serde(default)]
+    check_space: bool,
+    dir_mode: Option<FileMode>,
+    #[serde(default)]
+    quiet: bool,
+    wait_for_source_ms: Option<u64>,
+    #[serde(default)]
+    watch_mode: WatchMode,
+    #[serde(default = "Config::default_poll_interval_ms")]
+    poll_interval_ms: u64,
+    #[serde(default)]
+    sftp: SftpOptions,
+    targets: Vec<Target>,
+    // The configuration file that the copyset was loaded from.
+    #[serde(skip)]
+    defined_in: PathBuf,
+}
+
+impl Copyset {
+    // Give each target the options of the copyset that it does not set itself.
+    fn inherit_options(&mut self) {
+        for target in &mut self.targets {
+            target.events = target.events.take().or_else(|| self.events.clone());
+            target.flags = target.flags.take().or_else(|| self.flags.clone());
+            target.preserve.get_or_insert(self.preserve);
+            target.atomic.get_or_insert(self.atomic);
+            target.atomic_fallback.get_or_insert(self.atomic_fallback);
+            target
+                .skip_if_unchanged
+                .get_or_insert(self.skip_if_unchanged);
+            target.mirror_delete.get_or_insert(self.mirror_delete);
+            target.on_error.get_or_insert(self.on_error);
+            target.quiet.get_or_insert(self.quiet);
+            target.copy_on_start.get_or_insert(self.copy_on_start);
+            target.on_copy = target.on_copy.take().or_else(|| self.on_copy.clone());
+            target.max_size = target.max_size.or(self.max_size);
+            target.check_space.get_or_insert(self.check_space);
+            target.dir_mode = target.dir_mode.or(self.dir_mode);
+        }
+    }
+
+    // The preferred base path of the sources.
+    fn source(&self) -> &Path {
+        &self.sources.paths()[0]
+    }
+
+    // Whether any of the base paths of the sources exist.
+    fn source_exists(&self) -> bool {
+        self.sources.paths().iter().any(|source| source.exists())
+    }
+
+    // Resolve each of the targets in this copyset into a 'ResolvedTarget', without touching the
+    // filesystem or inotify.
+    fn resolve(&self) -> std::io::Result<Vec<ResolvedTarget>> {
+        if self.on_batch.as_ref().is_some_and(Vec::is_empty) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("copyset {:?} has an empty 'on_batch' command", self.name),
+            ));
+        }
+
+        let copyset = Arc::<str>::from(self.name.as_str());
+        let exclude = Arc::new(Exclude::new(self.sources.paths(), &self.exclude)?);
+        let rate_limit = match self.rate_limit {
+            Some(ByteSize(0)) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "'rate_limit' must be greater than zero",
+                ))
+            }
+            Some(ByteSize(rate)) => Some(Arc::new(RateLimiter::new(rate))),
+            None => None,
+        };
+
+        let mut resolved = Vec::new();
+        for target_spec in &self.targets {
+            if !target_spec.enabled {
+                tracing::info!(copyset = self.name, source = %target_spec.source.display(),
+                               "Skipping disabled target");
+                continue;
+            }
+
+            tracing::debug!(target_spec = ?target_spec, source = %target_spec.source.display(),
+                            "Resolving target");
+
+            // Append the target's paths (source and target) to the corresponding paths in the
+            // copyset. Note that if the 'target' path is not specified, we assume the value of the
+            // 'source' path. When the source is a glob, the target is a directory, and so we only
+            // assume the part of the source before the first wildcard. When the copyset has several
+            // sources, we use the first one that has the target's source.
+            let sources = self
+                .sources
+                .paths()
+                .iter()
+                .map(|base| base.join(&target_spec.source))
+                .collect::<Vec<_>>();
+            let mut source = sources
+                .iter()
+                .find(|source| source.exists())
+                .unwrap_or(&sources[0])
+                .clone();
+            if target_spec.directory {
+                if is_glob(&target_spec.source) || target_spec.recursive {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "directory target {:?} cannot also be a glob or recursive",
+                            target_spec.source
+                        ),
+                    ));
+                }
+
+                // A directory target copies each file directly within the directory, which is
+                // the same as a glob matching everything in the directory.
+                source = PathBuf::from(Pattern::escape(&source.to_string_lossy())).join("*");
+            }
+
+            let glob = if target_spec.directory || is_glob(&target_spec.source) {
+                Some(compile_glob(&source)?)
+            } else {
+                None
+            };
+
+            if target_spec.flatten && (target_spec.target.is_some() || target_spec.recursive) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} cannot be flattened if it is recursive or has a 'target'",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            // When the target is flattened, any directories in the source path are dropped, so
+            // that the file (or the files matching a glob) are placed directly in the target
+            // directory of the copyset.
+            let mut targets = match &target_spec.target {
+                Some(Paths::One(target)) => vec![self.target.join(target)],
+                Some(Paths::Many(targets)) => targets
+                    .iter()
+                    .map(|target| self.target.join(target))
+                    .collect(),
+                None if glob.is_some() && target_spec.flatten => vec![self.target.clone()],
+                None if glob.is_some() => vec![self.target.join(glob_prefix(&target_spec.source))],
+                None if target_spec.flatten => match target_spec.source.file_name() {
+                    Some(name) => vec![self.target.join(name)],
+                    None => vec![self.target.join(&target_spec.source)],
+                },
+                None => vec![self.target.join(&target_spec.source)],
+            };
+
+            // A single file that is copied to a directory (either one that exists, or any path
+            // that ends in a '/') is placed in the directory under its own name, as with 'cp'.
+            if glob.is_none() && !target_spec.recursive {
+                if let Some(name) = source.file_name() {
+                    for target in &mut targets {
+                        if target.as_os_str().as_bytes().ends_with(b"/") || target.is_dir() {
+                            target.push(name);
+                        }
+                    }
+                }
+            }
+
+            if targets.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} has an empty list of targets",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            // A source can only be copied to several targets when it is a single file, as the
+            // watched directories of recursive and glob targets each belong to a single target.
+            if targets.len() > 1 && (glob.is_some() || target_spec.recursive) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} can only have a list of targets if it is a single file",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            if target_spec.direction != Direction::SourceToTarget
+                && (glob.is_some() || target_spec.recursive)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} can only set 'direction' if it is a single file",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            if self.watch_mode == WatchMode::Poll && (glob.is_some() || target_spec.recursive) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} can only be polled if it is a single file",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            if glob.is_some() && target_spec.recursive {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} cannot be both a glob and recursive",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            if target_spec.latest.is_some()
+                && (targets.len() > 1 || glob.is_some() || target_spec.recursive)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} can only have a 'latest' link if it is a single file with a \
+                         single target",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            let on_copy = target_spec.on_copy.as_ref().or(self.on_copy.as_ref());
+            if on_copy.is_some_and(Vec::is_empty) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} has an empty 'on_copy' command",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            // If the target specifies it's own events then we want to use those events instead of
+            // those of the copyset; otherwise the events are those of the copyset.
+            let events: WatchMask = if let Some(Events(events)) = &target_spec.events {
+                events.iter()
+            } else {
+                self.events
+                    .as_ref()
+                    .map_or(&[][..], |Events(events)| events)
+                    .iter()
+            }
+            .copied()
+            .map(WatchMask::from)
+            .collect();
+
+            // The flags are given in the same way, and are added to the mask of the watch.
+            let events = target_spec
+                .flags
+                .as_ref()
+                .or(self.flags.as_ref())
+                .map_or(&[][..], |Flags(flags)| flags)
+                .iter()
+                .copied()
+                .map(WatchMask::from)
+                .fold(events, |mask, flag| mask | flag);
+
+            let single_file = glob.is_none() && !target_spec.recursive && sources.len() > 1;
+            let options = TargetOptions {
+                preserve: target_spec.preserve.unwrap_or(self.preserve),
+                preserve_xattrs: target_spec.preserve_xattrs,
+                atomic: target_spec.atomic.unwrap_or(self.atomic),
+                atomic_fallback: target_spec.atomic_fallback.unwrap_or(self.atomic_fallback),
+                skip_if_unchanged: target_spec
+                    .skip_if_unchanged
+                    .unwrap_or(self.skip_if_unchanged),
+                debounce: Duration::from_millis(self.debounce_ms),
+                recursive: target_spec.recursive,
+                glob,
+                mirror_delete: target_spec.mirror_delete.unwrap_or(self.mirror_delete),
+                on_error: target_spec.on_error.unwrap_or(self.on_error),
+                mode: target_spec.mode,
+                dir_mode: target_spec.dir_mode.or(self.dir_mode),
+                quiet: target_spec.quiet.unwrap_or(self.quiet),
+                owner: target_spec
+                    .owner
+                    .as_ref()
+                    .map(Principal::resolve_user)
+                    .transpose()?,
+                group: target_spec
+                    .group
+                    .as_ref()
+                    .map(Principal::resolve_group)
+                    .transpose()?,
+                on_copy: on_copy.cloned(),
+                on_batch: self.on_batch.clone(),
+                copy_on_start: target_spec.copy_on_start.unwrap_or(self.copy_on_start),
+                only_if: target_spec.only_if,
+                latest: target_spec
+                    .latest
+                    .as_ref()
+                    .map(|latest| self.target.join(latest)),
+                keep: target_spec.keep,
+                exclude: exclude.clone(),
+                checksum_sidecar: target_spec.checksum_sidecar,
+                verify: target_spec.verify,
+                transform: target_spec.transform,
+                compress: target_spec.compress.map(|format| Compress {
+                    format,
+                    level: target_spec.compress_level,
+                }),
+                range: (target_spec.offset.is_some() || target_spec.length.is_some()).then(|| {
+                    ByteRange {
+                        offset: target_spec.offset.map_or(0, |ByteSize(offset)| offset),
+                        length: target_spec.length.map(|ByteSize(length)| length),
+                    }
+                }),
+                method: target_spec.method,
+                rate_limit: rate_limit.clone(),
+                max_size: target_spec
+                    .max_size
+                    .or(self.max_size)
+                    .map(|ByteSize(size)| size),
+                check_space: target_spec.check_space.unwrap_or(self.check_space),
+                symlinks: target_spec.symlinks,
+                hook_debounce: Duration::from_millis(self.hook_debounce_ms),
+                sources: if single_file { sources } else { Vec::new() },
+                poll_interval: (self.watch_mode == WatchMode::Poll)
+                    .then(|| Duration::from_millis(self.poll_interval_ms)),
+                bidirectional: None,
+                backend: Arc::new(backend::Local),
+                copyset: copyset.clone(),
+            };
+
+            if target_spec.compress_level.is_some() && target_spec.compress.is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "target {:?} can only set 'compress_level' along with 'compress'",
+                        target_spec.source
+                    ),
+                ));
+            }
+
+            for mut target in targets {
+                // A compressed file is given the suffix of its format. The files of recursive and
+                // glob targets are given the suffix as each of them is found.
+                if let Some(compress) = &options.compress {
+                    compress.check(&target)?;
+                    if options.glob.is_none() && !options.recursive {
+                        target = compress.with_suffix(target);
+                    }
+                }
+
+                let mut options = options.clone();
+                options.backend = backend::for_target(&target, &self.sftp)?;
+                if !options.backend.is_local() {
+                    options.check_remote(&target)?;
+                }
+
+                // A templated target is copied to a different file each time, so there is no one
+                // file to remove when the source is deleted.
+                if template::is_templated(&target) && options.mirror_delete {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("templated target {target:?} cannot set 'mirror_delete'"),
+                    ));
+                }
+
+                options.check_method(&target)?;
+
+                match options.keep {
+                    Some(0) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("target {target:?} must keep at least one copy"),
+                        ))
+                    }
+                    Some(_) if !template::is_templated(&target) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("target {target:?} can only set 'keep' if it is templated"),
+                        ))
+                    }
+                    _ => {}
+                }
+
+                if target_spec.direction != Direction::SourceToTarget {
+                    if !options.backend.is_local() || template::is_templated(&target) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "target {target:?} can only set 'direction' if it is a file on \
+                                 the local filesystem"
+                            ),
+                        ));
+                    }
+
+                    if options.mirror_delete {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "target {target:?} cannot set both 'direction' and 'mirror_delete'"
+                            ),
+                        ));
+                    }
+
+                    // The copy back to the source would need the reverse of the transform, and would
+                    // replace the whole of the source with the part that was copied.
+                    if options.changes_contents() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "target {target:?} cannot set 'direction' along with 'transform', \
+                                 'compress', 'offset' or 'length'"
+                            ),
+                        ));
+                    }
+                }
+
+                match target_spec.direction {
+                    Direction::SourceToTarget => {
+                        resolved.push(ResolvedTarget::new(source.clone(), target, events, options))
+                    }
+                    Direction::TargetToSource => {
+                        resolved.push(ResolvedTarget::new(target, source.clone(), events, options))
+                    }
+
+                    // Each copy writes to the path that is watched for the other direction, so the
+                    // two directions share a record of what they have written, to stop the copies
+                    // bouncing back and forth. The target is only copied back on start if the
+                    // source is missing.
+                    Direction::Both => {
+                        let mut options = options;
+                        options.bidirectional = Some(Arc::default());
+
+                        let mut reverse = options.clone();
+                        reverse.copy_on_start = CopyOnStart::IfMissing;
+                        resolved.push(ResolvedTarget::new(
+                            target.clone(),
+                            source.clone(),
+                            events,
+                            reverse,
+                        ));
+                        resolved.push(ResolvedTarget::new(source.clone(), target, events, options));
+                    }
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    // If the copyset is configured to wait for its source, then wait until the source exists by
+    // polling for it, giving up once the timeout has elapsed. This covers volumes that are mounted
+    // a little after we start.
+    async fn wait_for_source(&self) -> std::io::Result<()> {
+        let Some(timeout) = self.wait_for_source_ms.map(Duration::from_millis) else {
+            return Ok(());
+        };
+
+        if self.source_exists() {
+            return Ok(());
+        }
+
+        tracing::info!(copyset = self.name, source = %self.source().display(),
+                       timeout_ms = timeout.as_millis() as u64, "Waiting for source to exist");
+
+        let deadline = Instant::now() + timeout;
+        while !self.source_exists() {
+            let now = Instant::now();
+            if now >= deadline {
+                tracing::error!(copyset = self.name, source = %self.source().display(),
+                                "Timed out waiting for source to exist");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "source {:?} of copyset {:?} did not exist within {}ms",
+                        self.source(),
+                        self.name,
+                        timeout.as_millis()
+                    ),
+                ));
+            }
+
+            tokio::time::sleep(SOURCE_POLL_INTERVAL.min(deadline - now)).await;
+        }
+
+        tracing::info!(copyset = self.name, source = %self.source().display(), "Source now exists");
+        Ok(())
+    }
+
+    // Add a watch for each of the resolved targets of this copyset, returning the sources of any
+    // targets that could not be added.
+    #[tracing::instrument(name = "copyset", skip_all, fields(copyset = %self.name))]
+    async fn add_to_watch(&self, env: &mut Env, targets: Vec<ResolvedTarget>) -> Vec<PathBuf> {
+        tracing::info!(copyset = self.name, source = %self.source().display(), target = %self.target.display(),
+                       "Adding watch for copyset");
+
+        // A target that cannot be added (such as one whose source is a directory) is skipped, so
+        // that it does not prevent the rest of the targets from being copied.
+        let mut failed = Vec::new();
+        for target in targets {
+            let source = target.source.clone();
+            if let Err(err) = env.add_target(target).await {
+                tracing::error!(copyset = self.name, source = %source.display(),
+                                "Failed to add target; skipping: {err:?}");
+                failed.push(source);
+            }
+        }
+
+        failed
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Target {
+    #[serde(default = "Config::default_enabled")]
+    enabled: bool,
+    events: Option<Events>,
+    flags: Option<Flags>,
+    source: PathBuf,
+    target: Option<Paths>,
+    latest: Option<PathBuf>,
+    keep: Option<usize>,
+    preserve: Option<bool>,
+    #[serde(default)]
+    preserve_xattrs: bool,
+    atomic: Option<bool>,
+    atomic_fallback: Option<bool>,
+    skip_if_unchanged: Option<bool>,
+    #[serde(default)]
+    recursive: bool,
+    #[serde(default)]
+    directory: bool,
+    #[serde(default)]
+    flatten: bool,
+    checksum_sidecar: Option<ChecksumAlgorithm>,
+    #[serde(default)]
+    verify: bool,
+    transform: Option<Transform>,
+    compress: Option<Compression>,
+    compress_level: Option<u32>,
+    offset: Option<ByteSize>,
+    length: Option<ByteSize>,
+    #[serde(default)]
+    method: CopyMethod,
+    #[serde(default)]
+    symlinks: SymlinkMode,
+    max_size: Option<ByteSize>,
+    check_space: Option<bool>,
+    mirror_delete: Option<bool>,
+    on_error: Option<ErrorPolicy>,
+    mode: Option<FileMode>,
+    dir_mode: Option<FileMode>,
+    quiet: Option<bool>,
+    owner: Option<Principal>,
+    group: Option<Principal>,
+    on_copy: Option<Vec<String>>,
+    copy_on_start: Option<CopyOnStart>,
+    #[serde(default)]
+    only_if: CopyCondition,
+    #[serde(default)]
+    direction: Direction,
+}
+
+/// One or more paths, given in the configuration as a single path or as a list: the sources of a
+/// copyset, or the targets of a target.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Paths {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+impl Paths {
+    fn paths(&self) -> &[PathBuf] {
+        match self {
+            Paths::One(path) => std::slice::from_ref(path),
+            Paths::Many(paths) => paths,
+        }
+    }
+
+    fn paths_mut(&mut self) -> impl Iterator<Item = &mut PathBuf> {
+        match self {
+            Paths::One(path) => std::slice::from_mut(path).iter_mut(),
+            Paths::Many(paths) => paths.iter_mut(),
+        }
+    }
+}
+
+/// What to do with a target in response to an event on its source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum EventAction {
+    /// Copy the source to the target.
+    Copy,
+    /// The source has been removed, so remove the target if deletions are mirrored.
+    Remove,
+    /// Only the attributes of the source have changed, so re-apply them to the target.
+    Attributes,
+}
+
+/// What to do with a target once the copy of it that is in progress has completed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum AfterCopy {
+    Nothing,
+    /// The source changed during the copy, so copy it again.
+    CopyAgain,
+    /// The source was deleted during the copy, so remove the target.
+    Remove,
+}
+
+/// How the contents of the source are copied to the target.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CopyMethod {
+    /// Copy the contents into a new file.
+    #[default]
+    Copy,
+    /// Clone the source, sharing its data until either of them is changed, falling back to a copy
+    /// if the filesystem does not support it.
+    Reflink,
+    /// Create a hard link to the source, so that the target is the same file as the source.
+    Hardlink,
+}
+
+/// How to copy a source that is a symbolic link.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SymlinkMode {
+    /// Copy the contents of the file that the link points to.
+    #[default]
+    Follow,
+    /// Create a symbolic link at the target that points to the same place as the source.
+    Preserve,
+}
+
+/// The hash algorithm used for a checksum sidecar file.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ChecksumAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    // The extension that is added to the name of the target to give the name of the sidecar.
+    fn extension(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    // The path of the sidecar file for the given target.
+    fn sidecar_path(&self, target: &Path) -> PathBuf {
+        let mut path = target.to_path_buf().into_os_string();
+        path.push(".");
+        path.push(self.extension());
+        PathBuf::from(path)
+    }
+
+    fn hash_file(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        match self {
+            ChecksumAlgorithm::Sha256 => hash_file(path),
+            ChecksumAlgorithm::Blake3 => {
+                let mut file = std::fs::File::open(path).map_err(|err| {
+                    tracing::error!(path = %path.display(), "Failed to open file for hashing: {err:?}");
+                    err
+                })?;
+
+                let mut hasher = blake3::Hasher::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                Ok(hasher.finalize().as_bytes().to_vec())
+            }
+        }
+    }
+}
+
+/// When to copy a target as it is first watched (at startup, or when it is added by a reload).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CopyOnStart {
+    /// Always copy the source, overwriting whatever is at the target.
+    Always,
+    /// Only copy the source if the target does not exist.
+    #[default]
+    IfMissing,
+    /// Never copy the source; wait for it to change.
+    Never,
+}
+
+/// What to do when copying a target fails.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorPolicy {
+    /// Log the failure, and wait for the source to change again.
+    #[default]
+    Skip,
+    /// Copy the target again after a delay, which doubles with each consecutive failure.
+    Retry,
+    /// Stop with an error.
+    Fatal,
+}
+
+/// Which way a target is copied.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Direction {
+    /// Copy the source to the target.
+    #[default]
+    SourceToTarget,
+    /// Copy the target back to the source.
+    TargetToSource,
+    /// Copy whichever of the source and the target changes to the other.
+    Both,
+}
+
+/// How the sources of a copyset are watched for changes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum WatchMode {
+    /// Watch the sources with inotify.
+    #[default]
+    Inotify,
+    /// Check the modification time and size of the sources periodically, for filesystems (such
+    /// as NFS) on which inotify does not report changes.
+    Poll,
+}
+
+/// When to copy a source over a target that already exists.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CopyCondition {
+    /// Always copy the source.
+    #[default]
+    Always,
+    /// Only copy the source if it is newer than the target, or has the same modification time but
+    /// a different size.
+    Newer,
+    /// Only copy the source if the target does not exist.
+    Missing,
+}
+
+/// A user or group, given in the configuration either as a numeric ID or as a name.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Principal {
+    Id(u32),
+    Name(String),
+}
+
+impl Principal {
+    // Resolve the numeric ID of a user, looking up the user by name if needed.
+    fn resolve_user(&self) -> std::io::Result<u32> {
+        self.resolve(|name| Ok(User::from_name(name)?.map(|user| user.uid.as_raw())))
+    }
+
+    // Resolve the numeric ID of a group, looking up the group by name if needed.
+    fn resolve_group(&self) -> std::io::Result<u32> {
+        self.resolve(|name| Ok(Group::from_name(name)?.map(|group| group.gid.as_raw())))
+    }
+
+    fn resolve<F>(&self, lookup: F) -> std::io::Result<u32>
+    where
+        F: FnOnce(&str) -> std::io::Result<Option<u32>>,
+    {
+        match self {
+            Principal::Id(id) => Ok(*id),
+            Principal::Name(name) => match name.parse() {
+                Ok(id) => Ok(id),
+                Err(_) => lookup(name)?.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("no user or group named {name:?}"),
+                    )
+                }),
+            },
+        }
+    }
+}
+
+/// A file mode, given in the configuration as an octal string such as `"0600"`.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+struct FileMode(u32);
+
+impl From<FileMode> for String {
+    fn from(FileMode(mode): FileMode) -> Self {
+        format!("{mode:04o}")
+    }
+}
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let digits = value.strip_prefix("0o").unwrap_or(&value);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(FileMode(mode)),
+            _ => Err(format!(
+                "invalid file mode {value:?}: expected an octal string such as \"0600\""
+            )),
+        }
+    }
+}
+
+/// A number of bytes, given in the configuration either as a number or as a string with a unit,
+/// such as `"10MB"` or `"512KiB"`.
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[serde(try_from = "ByteSizeValue")]
+struct ByteSize(u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ByteSizeValue {
+    Number(u64),
+    String(String),
+}
+
+impl TryFrom<ByteSizeValue> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: ByteSizeValue) -> Result<Self, Self::Error> {
+        let value = match value {
+            ByteSizeValue::Number(bytes) => return Ok(ByteSize(bytes)),
+            ByteSizeValue::String(value) => value,
+        };
+
+        let trimmed = value.trim();
+        let split = trimmed
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "kib" => 1 << 10,
+            "m" | "mb" => 1000 * 1000,
+            "mib" => 1 << 20,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "gib" => 1 << 30,
+            _ => return Err(format!("invalid size {value:?}: unknown unit {unit:?}")),
+        };
+
+        match number.parse::<f64>() {
+            Ok(number) if number >= 0.0 => Ok(ByteSize((number * multiplier as f64) as u64)),
+            _ => Err(format!(
+                "invalid size {value:?}: expected a number of bytes such as \"10MB\""
+            )),
+        }
+    }
+}
+
+/// The part of a source that is copied to the target: the bytes from the offset up to the given
+/// length, or up to the end of the source when there is no length.
+#[derive(Debug, Clone, Copy)]
+struct ByteRange {
+    offset: u64,
+    length: Option<u64>,
+}
+
+/// The watches and copies of a configuration that has been set up, ready to process events.
+pub struct Env {
+    watches: Watches,
+    stream: EventStream<Vec<u8>>,
+    // The size of the buffer used by the event stream, which cannot be changed once it is created.
+    buffer_size: u64,
+    // The targets of each watched file. A file can be copied to several targets.
+    targets: HashMap<WatchDescriptor, Vec<ResolvedTarget>>,
+    // Directories that are being watched as part of a recursive or glob target. As with files,
+    // a directory can be watched on behalf of several targets.
+    directories: HashMap<WatchDescriptor, Vec<WatchedDirectory>>,
+    // Targets whose sources are polled for changes rather than watched with inotify.
+    polled: Vec<PolledTarget>,
+    // Targets that are copied in both directions whose source does not exist yet, which are
+    // watched once the copy in the other direction has created it.
+    awaiting: Vec<ResolvedTarget>,
+    // Targets whose source did not exist when they were added, by the watch on the directory that
+    // will contain the source. Each is watched and copied once its source is created.
+    missing: HashMap<WatchDescriptor, Vec<ResolvedTarget>>,
+    // The directories containing the sources of watched files, so that we hear about files that
+    // are moved into the place of a source.
+    parents: HashMap<WatchDescriptor, PathBuf>,
+    // Targets with a debounce window that are waiting to be copied, along with the time at which
+    // the copy should take place. Files within a watched directory are identified by their name.
+    pending: HashMap<(WatchDescriptor, Option<OsString>), Instant>,
+    // Directories that we created to hold targets, which we may remove again once they are empty.
+    created_directories: HashSet<PathBuf>,
+    // The copies that we have made of each templated target with a 'keep' limit, oldest first.
+    history: HashMap<PathBuf, VecDeque<PathBuf>>,
+    // Hook commands that are waiting to be run, along with the most recent copy that triggered
+    // each of them.
+    hooks: HashMap<Vec<String>, PendingHook>,
+    // The batch hooks of each copyset that are waiting to be run, along with the targets that have
+    // been copied since the batch started.
+    batches: HashMap<Arc<str>, PendingBatch>,
+    // The copies that are in progress, each of which needs a permit from the copy limit.
+    copies: JoinSet<(ResolvedTarget, std::io::Result<CopyOutcome>)>,
+    copy_limit: Arc<Semaphore>,
+    copy_limit_size: usize,
+    // The targets that are being copied, and what to do with each once the copy in progress has
+    // completed. Only one copy of a target is made at a time, so that copies never race on the
+    // same target (or its temporary file).
+    in_flight: HashMap<PathBuf, AfterCopy>,
+    // The watch on the directory containing the configuration file, when '--watch-config' is given.
+    config_watch: Option<ConfigWatch>,
+    // The number of consecutive times that copying each target has failed, along with its copyset.
+    failures: HashMap<PathBuf, (Arc<str>, u32)>,
+    // The targets whose copy failed that are waiting to be copied again, by their target path.
+    retries: HashMap<PathBuf, PendingRetry>,
+    // What has been copied over the whole run, which is logged when we exit.
+    totals: CopyTotals,
+    // The number of consecutive failures of a copy after which we give up.
+    max_failures: Option<u32>,
+    // How often to resync the targets (and the most jitter to add each time), and when to next do
+    // so.
+    resync: Option<(Duration, u64)>,
+    next_resync: Option<Instant>,
+    // When the initial copies were started, and how many there were, until they have completed.
+    startup: Option<(Instant, usize)>,
+    // The sources of the targets that could not be added when we started.
+    unwatched: Vec<PathBuf>,
+    // The copysets that were selected on the command line, which also applies when reloading.
+    filter: CopysetFilter,
+    // When set, copies and removals are only logged rather than actually being made.
+    dry_run: bool,
+}
+
+/// The number of copies that have been made over the whole run, and of those that failed.
+#[derive(Debug, Default)]
+struct CopyTotals {
+    copies: u64,
+    failures: u64,
+    bytes: u64,
+}
+
+// Record the name of each of the copysets defined in the given file, failing if any of them has
+// already been defined, whether in another file or earlier in the same one.
+fn record_names(
+    names: &mut HashMap<String, PathBuf>,
+    copysets: &[Copyset],
+    path: &Path,
+) -> std::io::Result<()> {
+    for copyset in copysets {
+        match names.get(&copyset.name) {
+            Some(other) if other == path => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "copyset {:?} is defined more than once in {path:?}",
+                        copyset.name
+                    ),
+                ))
+            }
+            Some(other) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "copyset {:?} in {path:?} is also defined in {other:?}",
+                        copyset.name
+                    ),
+                ))
+            }
+            None => {
+                names.insert(copyset.name.clone(), path.to_path_buf());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The canonical form of a path, so that the same file is recognised whichever way it is named. A
+// path that cannot be canonicalized (such as one that does not exist) is left as it is.
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The contents of the configuration file, or the names and contents of each of the configuration
+// files when the configuration is a directory.
+fn config_contents(config_path: &Path) -> std::io::Result<Vec<u8>> {
+    if !config_path.is_dir() {
+        return std::fs::read(config_path);
+    }
+
+    let mut contents = Vec::new();
+    for path in Config::config_files(config_path)? {
+        contents.extend(path.as_os_str().as_bytes());
+        contents.push(0);
+        contents.extend(std::fs::read(&path)?);
+    }
+
+    Ok(contents)
+}
+
+/// The watch on the directory containing the configuration file.
+struct ConfigWatch {
+    wd: WatchDescriptor,
+    // The contents of the configuration file when it was last loaded, so that we only reload when
+    // the contents have actually changed.
+    contents: Vec<u8>,
+    // When to check the configuration file for changes, once events have stopped arriving for it.
+    deadline: Option<Instant>,
+}
+
+/// A hook command that is waiting for its debounce window to elapse before being run.
+struct PendingHook {
+    deadline: Instant,
+    // The source and target of the most recent copy that triggered the hook.
+    source: PathBuf,
+    target: PathBuf,
+}
+
+/// A copy of a target that failed, which is waiting to be tried again.
+struct PendingRetry {
+    deadline: Instant,
+    target: ResolvedTarget,
+}
+
+/// The batch hook of a copyset that is waiting for a burst of copies to settle before being run.
+struct PendingBatch {
+    command: Vec<String>,
+    deadline: Instant,
+    // The targets that have been copied during the batch, in the order that they were first copied.
+    targets: Vec<PathBuf>,
+}
+
+/// A directory that is watched on behalf of a recursive or glob target.
+struct WatchedDirectory {
+    // The recursive or glob target that this directory belongs to.
+    root: ResolvedTarget,
+    // The path of this directory.
+    path: PathBuf,
+}
+
+impl WatchedDirectory {
+    // Create the target for a file with the given name in this directory. For recursive targets
+    // the file is placed at the same relative path under the target; for glob targets the file is
+    // placed directly in the target directory, provided that it matches the glob.
+    fn child(&self, name: &OsStr) -> Option<ResolvedTarget> {
+        let source = self.path.join(name);
+        if self.root.options.exclude.is_excluded(&source, false) {
+            tracing::debug!(source = %source.display(), "Ignoring source as it is excluded");
+            return None;
+        }
+
+        match &self.root.options.glob {
+            Some(pattern) if !pattern.matches_path_with(&source, glob_match_options()) => None,
+            Some(_) => Some(self.root.child(source, self.root.target.join(name))),
+            None => {
+                let relative = source.strip_prefix(&self.root.source).ok()?;
+                let target = self.root.target.join(relative);
+                Some(self.root.child(source, target))
+            }
+        }
+    }
+}
+
+/// A target whose source is polled for changes, rather than watched with inotify.
+struct PolledTarget {
+    target: ResolvedTarget,
+    // The modification time and size of the source when it was last checked, or 'None' if it did
+    // not exist.
+    state: Option<(SystemTime, u64)>,
+    // When the source is next due to be checked.
+    next_poll: Instant,
+}
+
+impl PolledTarget {
+    fn new(target: ResolvedTarget) -> Self {
+        Self {
+            state: source_state(&target.source),
+            next_poll: Instant::now() + target.options.poll_interval.unwrap_or_default(),
+            target,
+        }
+    }
+}
+
+// The modification time and size of a source, or 'None' if it does not exist.
+fn source_state(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// Expand references to environment variables in a path. See 'expand_vars'.
+fn expand_path(path: &Path) -> std::io::Result<PathBuf> {
+    let Some(value) = path.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+
+    expand_vars(value).map(PathBuf::from).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("failed to expand {value:?}: {err}"),
+        )
+    })
+}
+
+// Expand references to environment variables in a string, which can take the forms '$VAR',
+// '${VAR}' and '${VAR:-default}' (where the default is used when the variable is unset or empty).
+// A '$$' is replaced with a single '$'. Referring to a variable that is not set, without giving a
+// default, is an error.
+fn expand_vars(value: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable reference in {value:?}"))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+
+            match (std::env::var(name), default) {
+                (Ok(var), Some(default)) if var.is_empty() => result.push_str(default),
+                (Ok(var), _) => result.push_str(&var),
+                (Err(_), Some(default)) => result.push_str(default),
+                (Err(_), None) => return Err(format!("environment variable {name:?} is not set")),
+            }
+
+            rest = &after[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                result.push('$');
+                continue;
+            }
+
+            let name = &rest[..end];
+            let var = std::env::var(name)
+                .map_err(|_| format!("environment variable {name:?} is not set"))?;
+            result.push_str(&var);
+            rest = &rest[end..];
+        }
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Check whether the given path contains any glob wildcards.
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+// Get the leading components of a path that do not contain any glob wildcards.
+fn glob_prefix(path: &Path) -> PathBuf {
+    path.components()
+        .take_while(|component| !is_glob(Path::new(component.as_os_str())))
+        .collect()
+}
+
+fn compile_glob(path: &Path) -> std::io::Result<Pattern> {
+    let pattern = path.to_str().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("glob {path:?} is not valid UTF-8"),
+        )
+    })?;
+
+    Pattern::new(pattern).map_err(|err| {
+        tracing::error!(pattern = ?pattern, "Invalid glob pattern: {err}");
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    })
+}
+
+// Wildcards in a glob should never match across directories.
+fn glob_match_options() -> MatchOptions {
+    MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    }
+}
+
+/// Options that control how a `ResolvedTarget` is watched and copied, resolved from the target and
+/// its copyset.
+#[derive(Debug, Clone)]
+struct TargetOptions {
+    preserve: bool,
+    preserve_xattrs: bool,
+    atomic: bool,
+    // Whether an atomic copy may fall back to copying directly onto the target when the target is
+    // on a different filesystem to its directory.
+    atomic_fallback: bool,
+    skip_if_unchanged: bool,
+    debounce: Duration,
+    recursive: bool,
+    glob: Option<Pattern>,
+    mirror_delete: bool,
+    on_error: ErrorPolicy,
+    mode: Option<FileMode>,
+    // The mode of any directories that are created to hold the target.
+    dir_mode: Option<FileMode>,
+    owner: Option<u32>,
+    group: Option<u32>,
+    // The command to run after the target has been copied, and how long to wait for any further
+    // copies before running it.
+    on_copy: Option<Vec<String>>,
+    // The command to run once after a burst of copies of any of the targets in the copyset.
+    on_batch: Option<Vec<String>>,
+    hook_debounce: Duration,
+    copy_on_start: CopyOnStart,
+    only_if: CopyCondition,
+    // A symbolic link to update to point to the most recent copy of a templated target.
+    latest: Option<PathBuf>,
+    // The number of copies of a templated target to keep, removing any older copies.
+    keep: Option<usize>,
+    exclude: Arc<Exclude>,
+    checksum_sidecar: Option<ChecksumAlgorithm>,
+    // Whether to read the target back after copying it, to check that it matches the source.
+    verify: bool,
+    // How the contents of the source are transformed on their way to the target.
+    transform: Option<Transform>,
+    // How the target is compressed, after any transform.
+    compress: Option<Compress>,
+    // The part of the source that is copied, rather than the whole file.
+    range: Option<ByteRange>,
+    method: CopyMethod,
+    // The rate limiter shared by all of the targets in the copyset, if the copies are throttled.
+    rate_limit: Option<Arc<RateLimiter>>,
+    // The largest source that will be copied, in bytes.
+    max_size: Option<u64>,
+    // Whether to check that the source will fit on the filesystem of the target before copying.
+    check_space: bool,
+    symlinks: SymlinkMode,
+    // Whether each copy is only logged at the 'debug' level, for targets that change often.
+    quiet: bool,
+    // The source under each of the sources of the copyset, in order of preference, when there are
+    // several of them. If the source disappears then we fall back to the first of these that
+    // exists.
+    sources: Vec<PathBuf>,
+    // How often to check the source for changes, when it is polled rather than watched with
+    // inotify.
+    poll_interval: Option<Duration>,
+    // What has been written by each direction of a target that is copied in both directions, whose
+    // source may also be created by the copy in the other direction.
+    bidirectional: Option<Arc<Written>>,
+    // Where the target is copied to.
+    backend: Arc<dyn Backend>,
+    // The name of the copyset that the target belongs to.
+    copyset: Arc<str>,
+}
+
+impl TargetOptions {
+    // Check that none of the options that only apply to targets on the local filesystem are set
+    // for a target with another backend, where they would otherwise be silently ignored.
+    fn check_remote(&self, target: &Path) -> std::io::Result<()> {
+        let options = [
+            ("preserve", self.preserve),
+            ("preserve_xattrs", self.preserve_xattrs),
+            ("skip_if_unchanged", self.skip_if_unchanged),
+            ("mirror_delete", self.mirror_delete),
+            ("mode", self.mode.is_some()),
+            ("dir_mode", self.dir_mode.is_some()),
+            ("owner", self.owner.is_some()),
+            ("group", self.group.is_some()),
+            ("checksum_sidecar", self.checksum_sidecar.is_some()),
+            ("verify", self.verify),
+            ("transform", self.transform.is_some()),
+            ("compress", self.compress.is_some()),
+            ("offset", self.range.is_some_and(|range| range.offset > 0)),
+            (
+                "length",
+                self.range.is_some_and(|range| range.length.is_some()),
+            ),
+            ("rate_limit", self.rate_limit.is_some()),
+            ("check_space", self.check_space),
+            ("method", self.method != CopyMethod::Copy),
+            ("symlinks", self.symlinks != SymlinkMode::Follow),
+            ("only_if", self.only_if != CopyCondition::Always),
+            ("latest", self.latest.is_some()),
+            ("keep", self.keep.is_some()),
+            ("a '{n}' counter", template::has_counter(target)),
+        ];
+
+        match options.iter().find(|(_, set)| *set) {
+            Some((name, _)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("target {target:?} is not on the local filesystem, so cannot set {name:?}"),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Whether the target has different contents to the source: only part of it, or transformed or
+    // compressed.
+    fn changes_contents(&self) -> bool {
+        self.transform.is_some() || self.compress.is_some() || self.range.is_some()
+    }
+
+    // Check that the copy method can be used along with the other options. A clone or a link can
+    // only be made of the whole of the source, as it is, and changing the mode or the ownership of
+    // a hard link would change those of the source as well.
+    fn check_method(&self, target: &Path) -> std::io::Result<()> {
+        if self.method == CopyMethod::Copy {
+            return Ok(());
+        }
+
+        if self.changes_contents() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "target {target:?} cannot set 'method' along with 'transform', 'compress', \
+                     'offset' or 'length'"
+                ),
+            ));
+        }
+
+        if self.method == CopyMethod::Hardlink
+            && (self.mode.is_some() || self.owner.is_some() || self.group.is_some())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "target {target:?} is a hard link to its source, so cannot set 'mode', \
+                     'owner' or 'group'"
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The hash of the contents that were last copied to each side of a target that is copied in both
+/// directions. A source that still holds what we copied to it has not been changed by anyone else,
+/// and so is not copied back.
+#[derive(Debug, Default)]
+struct Written(Mutex<HashMap<PathBuf, Vec<u8>>>);
+
+impl Written {
+    fn is_own_write(&self, path: &Path, hash: &[u8]) -> bool {
+        let written = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        written.get(path).is_some_and(|written| written == hash)
+    }
+
+    fn record(&self, path: &Path, hash: Vec<u8>) {
+        let mut written = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        written.insert(path.to_path_buf(), hash);
+    }
+}
+
+/// The paths to exclude from recursive, glob and directory targets, which are never watched or
+/// copied. These are given as glob patterns that are matched against the path relative to the
+/// source of the copyset.
+#[derive(Debug)]
+struct Exclude {
+    bases: Vec<PathBuf>,
+    patterns: Vec<ExcludePattern>,
+}
+
+#[derive(Debug)]
+struct ExcludePattern {
+    pattern: Pattern,
+    // Whether the pattern ended with a '/', and so only matches directories.
+    directory_only: bool,
+    // Whether the pattern has no '/', and so matches the name of a file or directory at any depth.
+    anywhere: bool,
+}
+
+impl Exclude {
+    fn new(bases: &[PathBuf], patterns: &[String]) -> std::io::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let trimmed = pattern.trim_end_matches('/');
+                Ok(ExcludePattern {
+                    pattern: Pattern::new(trimmed).map_err(|err| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("invalid exclude pattern {pattern:?}: {err}"),
+                        )
+                    })?,
+                    directory_only: trimmed.len() < pattern.len(),
+                    anywhere: !trimmed.contains('/'),
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self {
+            bases: bases.to_vec(),
+            patterns,
+        })
+    }
+
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let Some(relative) = self
+            .bases
+            .iter()
+            .find_map(|base| path.strip_prefix(base).ok())
+        else {
+            return false;
+        };
+
+        self.patterns.iter().any(|exclude| {
+            if exclude.directory_only && !is_dir {
+                return false;
+            }
+
+            if exclude.anywhere {
+                relative.file_name().is_some_and(|name| {
+                    exclude
+                        .pattern
+                        .matches_path_with(Path::new(name), glob_match_options())
+                })
+            } else {
+                exclude
+                    .pattern
+                    .matches_path_with(relative, glob_match_options())
+            }
+        })
+    }
+}
+
+/// The outcome of a successful `ResolvedTarget::copy`.
+#[derive(Default)]
+struct CopyOutcome {
+    // Any directories that were created to hold the target.
+    created: Vec<PathBuf>,
+    // The path that was copied to, when the target is templated.
+    target: Option<PathBuf>,
+    // The number of bytes that were copied, or 'None' if the copy was skipped.
+    bytes: Option<u64>,
+}
+
+// Log the progress of a copy of a target at the 'info' level, or at the 'debug' level if the
+// target is quiet. Warnings and errors are always logged as usual.
+macro_rules! copy_info {
+    ($target:expr, $($arg:tt)+) => {
+        if $target.options.quiet {
+            tracing::debug!($($arg)+)
+        } else {
+            tracing::info!($($arg)+)
+        }
+    };
+}
+
+#[derive(Clone)]
+struct ResolvedTarget {
+    source: PathBuf,
+    target: PathBuf,
+    events: WatchMask,
+    options: TargetOptions,
+}
+
+impl ResolvedTarget {
+    fn new(source: PathBuf, target: PathBuf, events: WatchMask, options: TargetOptions) -> Self {
+        Self {
+            source,
+            target,
+            events,
+            options,
+        }
+    }
+
+    // Check whether this target describes the same watch as another: that is, the same source,
+    // target and events, watched in the same way.
+    fn is_same_watch(&self, other: &ResolvedTarget) -> bool {
+        self.source == other.source
+            && self.target == other.target
+            && self.events == other.events
+            && self.options.recursive == other.options.recursive
+            && self.options.poll_interval.is_some() == other.options.poll_interval.is_some()
+    }
+
+    // The mask used when watching the source. In addition to the configured events, we always
+    // want to know when the source is moved, as the watch would then follow the moved file rather
+    // than the path that we were asked to watch.
+    fn watch_mask(&self) -> WatchMask {
+        // When preserving symbolic links, we need to watch the link itself rather than the file
+        // that it points to, so that we notice when the link is changed.
+        if self.is_preserved_symlink() {
+            self.events | WatchMask::MOVE_SELF | WatchMask::DONT_FOLLOW
+        } else {
+            self.events | WatchMask::MOVE_SELF
+        }
+    }
+
+    // Decide what to do in response to an event with the given mask on the source. A change to the
+    // attributes of a source that has not been copied yet (or that is a preserved symbolic link)
+    // is treated as a change to its contents.
+    fn action(&self, mask: EventMask) -> EventAction {
+        if mask.intersects(EventMask::DELETE | EventMask::DELETE_SELF | EventMask::MOVED_FROM) {
+            EventAction::Remove
+        } else if mask.contains(EventMask::ATTRIB)
+            && !self.is_preserved_symlink()
+            && self.target.exists()
+        {
+            EventAction::Attributes
+        } else {
+            EventAction::Copy
+        }
+    }
+
+    // The span that the logs of each copy of the target are recorded in, so that they carry the
+    // name of its copyset. The span has no parent, as copies outlive whatever triggered them.
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!(parent: None, "copyset", copyset = %self.options.copyset)
+    }
+
+    // The first of the other sources of the target that exists, when the copyset has several
+    // sources and so we can fall back to another when this one disappears.
+    fn fallback(&self) -> Option<&PathBuf> {
+        self.options
+            .sources
+            .iter()
+            .find(|source| **source != self.source && source.exists())
+    }
+
+    // Whether the source is a symbolic link that should be recreated at the target.
+    fn is_preserved_symlink(&self) -> bool {
+        self.options.symlinks == SymlinkMode::Preserve && self.source.is_symlink()
+    }
+
+    // Whether the source is something that we can copy: a regular file, or a symbolic link that we
+    // are preserving (which may point to something that does not exist).
+    fn is_copyable(&self) -> bool {
+        self.source.is_file() || self.is_preserved_symlink()
+    }
+
+    // The paths that setting up the target would watch, found without watching them: the source
+    // and the directory that it is in (or only the directory while the source is missing), each
+    // directory of a recursive target, or each directory that matches the glob of a glob target.
+    // Paths watched for more than one target share the one watch.
+    fn watch_paths(&self) -> Vec<PathBuf> {
+        if self.options.poll_interval.is_some() {
+            return Vec::new();
+        }
+
+        if self.options.recursive {
+            if !self.source.is_dir() {
+                return Vec::new();
+            }
+
+            let mut paths = Vec::new();
+            let mut stack = vec![self.source.clone()];
+            while let Some(path) = stack.pop() {
+                for entry in std::fs::read_dir(&path).into_iter().flatten().flatten() {
+                    if entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+                        && !self.options.exclude.is_excluded(&entry.path(), true)
+                    {
+                        stack.push(entry.path());
+                    }
+                }
+
+                paths.push(path);
+            }
+
+            return paths;
+        }
+
+        let parent = self.source.parent().unwrap_or(Path::new("/"));
+        if self.options.glob.is_some() {
+            return glob::glob_with(&parent.to_string_lossy(), glob_match_options())
+                .map(|paths| {
+                    paths
+                        .filter_map(Result::ok)
+                        .filter(|path| path.is_dir())
+                        .collect()
+                })
+                .unwrap_or_default();
+        }
+
+        if self.source.exists() {
+            vec![self.source.clone(), parent.to_path_buf()]
+        } else if self.options.bidirectional.is_some() {
+            // The source is waited for from the other direction, rather than being watched.
+            Vec::new()
+        } else {
+            vec![parent.to_path_buf()]
+        }
+    }
+
+    // The mask used when watching a directory for a recursive or glob target. We need to know
+    // when files and directories are created in (or moved into) the directory, so that we can
+    // copy them and watch any new subdirectories.
+    fn directory_mask(&self) -> WatchMask {
+        self.watch_mask() | WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::ONLYDIR
+    }
+
+    // Create the target for a file within a recursive or glob target.
+    fn child(&self, source: PathBuf, target: PathBuf) -> ResolvedTarget {
+        let mut options = self.options.clone();
+        options.recursive = false;
+        options.glob = None;
+
+        let target = match &options.compress {
+            Some(compress) => compress.with_suffix(target),
+            None => target,
+        };
+
+        ResolvedTarget::new(source, target, self.events, options)
+    }
+
+    // The path of the checksum sidecar file for the target.
+    fn sidecar_path(&self, algorithm: ChecksumAlgorithm) -> PathBuf {
+        algorithm.sidecar_path(&self.target)
+    }
+
+    // Each copy is recorded in a span, which is exported to OpenTelemetry when it is configured.
+    #[tracing::instrument(level = "debug", name = "copy", skip_all,
+                          fields(source = %self.source.display(), target = %self.target.display(),
+                                 bytes = tracing::field::Empty))]
+    async fn copy(&self) -> std::io::Result<CopyOutcome> {
+        let started = Instant::now();
+
+        // The placeholders in a templated target are expanded for each copy, which is then made
+        // to the expanded path.
+        let templated = template::is_templated(&self.target);
+        let mut target = self.clone();
+        if templated {
+            target.target = template::expand(&self.target, &self.source, SystemTime::now());
+        }
+
+        let mut result = target.options.backend.copy(&target).await;
+        if let Ok(outcome) = &mut result {
+            if templated && outcome.bytes.is_some() {
+                outcome.target = Some(target.target.clone());
+                if let Some(latest) = &self.options.latest {
+                    if let Err(err) = target.link_latest(latest) {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+
+        // Skipped copies have already been logged by 'copy_file', and failures are logged by the
+        // caller.
+        let elapsed = started.elapsed();
+        match &result {
+            Ok(CopyOutcome {
+                bytes: Some(bytes), ..
+            }) => {
+                copy_info!(self, source = %self.source.display(), target = %target.target.display(),
+                               bytes, duration_ms = elapsed.as_millis() as u64, "Copied");
+                tracing::Span::current().record("bytes", *bytes);
+                metrics::copy_succeeded(*bytes, elapsed);
+            }
+            Ok(_) => {}
+            Err(_) => metrics::copy_failed(),
+        }
+
+        result
+    }
+
+    fn copy_file(&self) -> std::io::Result<CopyOutcome> {
+        if self.is_preserved_symlink() {
+            return self.copy_symlink();
+        }
+
+        // A special file can also turn up in a watched directory, or replace the source of a
+        // single file target, after the target has been added.
+        if let Some(kind) = special_file_kind(&self.source) {
+            tracing::warn!(source = %self.source.display(), "Skipped (source is a {kind})");
+            return Ok(CopyOutcome::default());
+        }
+
+        if self.is_too_large()? || self.is_too_short()? || self.is_out_of_space()? {
+            return Ok(CopyOutcome::default());
+        }
+
+        if !self.is_condition_met()? {
+            copy_info!(self, target = %self.target.display(), only_if = ?self.options.only_if,
+                           "Skipped (condition not met)");
+            return Ok(CopyOutcome::default());
+        }
+
+        if self.options.skip_if_unchanged && self.is_unchanged()? {
+            copy_info!(self, target = %self.target.display(), "Skipped (unchanged)");
+            return Ok(CopyOutcome::default());
+        }
+
+        // Copying back what the other direction has just copied here would undo any changes that
+        // were made to the other side since.
+        let written = match &self.options.bidirectional {
+            Some(written) => {
+                let hash = hash_file(&self.source)?;
+                if written.is_own_write(&self.source, &hash) {
+                    tracing::debug!(source = %self.source.display(),
+                                    "Skipped (copied from the other direction)");
+                    return Ok(CopyOutcome::default());
+                }
+
+                Some((written, hash))
+            }
+            None => None,
+        };
+
+        copy_info!(self, source = %self.source.display(), target = %self.target.display(), "Copying");
+
+        let mut outcome = CopyOutcome {
+            created: self.create_parent()?,
+            ..CopyOutcome::default()
+        };
+
+        outcome.bytes = Some(self.write_target()?);
+
+        // Read the target back to check that it matches the source, copying it once more if it
+        // does not.
+        if self.options.verify && !self.is_verified()? {
+            tracing::warn!(target = %self.target.display(),
+                           "Target does not match the source after copying; copying again");
+            outcome.bytes = Some(self.write_target()?);
+
+            if !self.is_verified()? {
+                tracing::error!(target = %self.target.display(),
+                                "Target still does not match the source after copying again");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("target {:?} does not match its source", self.target),
+                ));
+            }
+        }
+
+        if let Some(algorithm) = self.options.checksum_sidecar {
+            self.write_sidecar(algorithm)?;
+        }
+
+        if let Some((written, hash)) = written {
+            written.record(&self.target, hash);
+        }
+
+        Ok(outcome)
+    }
+
+    // Write the contents of the source to the target, atomically if configured to do so, returning
+    // the number of bytes that were copied.
+    fn write_target(&self) -> std::io::Result<u64> {
+        let bytes = if self.options.atomic {
+            self.copy_atomic()?
+        } else {
+            self.copy_to(&self.target)?
+        };
+
+        if let Some(hook) = TARGET_WRITTEN_HOOK.get() {
+            hook(&self.target);
+        }
+
+        Ok(bytes)
+    }
+
+    // Whether the target has the same contents as the source.
+    fn is_verified(&self) -> std::io::Result<bool> {
+        Ok(self.source_hash()? == hash_file(&self.target)?)
+    }
+
+    // The hash of the contents that the source is copied to the target as: only its range (if it
+    // has one), after any transform and compression.
+    fn source_hash(&self) -> std::io::Result<Vec<u8>> {
+        if !self.options.changes_contents() {
+            return hash_file(&self.source);
+        }
+
+        let (mut source, _) = self.open_source().map_err(|err| {
+            tracing::error!(path = %self.source.display(), "Failed to open file for hashing: {err:?}");
+            err
+        })?;
+
+        let mut hasher = Sha256::new();
+        self.transform(&mut source, &mut hasher, None)?;
+        Ok(hasher.finalize().to_vec())
+    }
+
+    // Copy the contents of the source through the transform and the compression of the target (if
+    // it has them), through the rate limiter (if any).
+    fn transform(
+        &self,
+        source: &mut dyn Read,
+        writer: &mut dyn Write,
+        limiter: Option<&RateLimiter>,
+    ) -> std::io::Result<()> {
+        let mut copy = |writer: &mut dyn Write| match self.options.transform {
+            Some(transform) => transform.copy(source, writer, limiter),
+            None => transform::pipe(source, writer, limiter).map(|_| ()),
+        };
+
+        match &self.options.compress {
+            Some(compress) => compress.compress(writer, copy),
+            None => copy(writer),
+        }
+    }
+
+    // Open the source for reading from the start of its range (if it has one), along with its
+    // metadata. Reading stops at the end of the range.
+    fn open_source(&self) -> std::io::Result<(std::io::Take<std::fs::File>, std::fs::Metadata)> {
+        let mut source = std::fs::File::open(&self.source)?;
+        let metadata = source.metadata()?;
+        let Some(range) = self.options.range else {
+            return Ok((source.take(u64::MAX), metadata));
+        };
+
+        source.seek(SeekFrom::Start(range.offset))?;
+        Ok((source.take(range.length.unwrap_or(u64::MAX)), metadata))
+    }
+
+    // The directory that holds the target. A relative target with a single component is in the
+    // current directory, and the root of the filesystem has no parent at all.
+    fn parent(&self) -> Option<&Path> {
+        self.target.parent().map(|parent| {
+            if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            }
+        })
+    }
+
+    // Make sure that the parent directory of the target exists. If it does not exist, then create
+    // it, returning each of the directories that we needed to create. Any 'dir_mode' is only
+    // applied to those directories, and not to any that already existed.
+    fn create_parent(&self) -> std::io::Result<Vec<PathBuf>> {
+        let Some(parent) = self.parent() else {
+            return Ok(Vec::new());
+        };
+
+        // Otherwise we would go on to fail to create the target (or the directories within this
+        // one) with a much less helpful error.
+        if let Some(existing) = parent.ancestors().find(|ancestor| ancestor.exists()) {
+            if !existing.is_dir() {
+                tracing::error!(target = %self.target.display(), parent = %existing.display(),
+                                "Target parent is not a directory");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotADirectory,
+                    format!("target parent {existing:?} is not a directory"),
+                ));
+            }
+        }
+
+        if parent.exists() {
+            return Ok(Vec::new());
+        }
+
+        copy_info!(self, directory = %parent.display(), "Creating parent directory");
+        let created = parent
+            .ancestors()
+            .take_while(|ancestor| !ancestor.exists())
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+
+        std::fs::create_dir_all(parent).map_err(|err| {
+            tracing::error!(parent = %parent.display(), "Failed to create directory: {err:?}");
+            err
+        })?;
+
+        // The innermost directory comes first, so that a restrictive mode on one directory does
+        // not stop us from changing the mode of the directories within it.
+        if let Some(FileMode(mode)) = self.options.dir_mode {
+            for directory in &created {
+                std::fs::set_permissions(directory, std::fs::Permissions::from_mode(mode))
+                    .map_err(|err| {
+                        tracing::error!(directory = %directory.display(), mode = format!("{mode:04o}"),
+                                        "Failed to set directory mode: {err:?}");
+                        err
+                    })?;
+            }
+        }
+
+        Ok(created)
+    }
+
+    // Point the 'latest' link at the target, which has just been copied. The link is relative when
+    // it is in the same directory as the target. Like a copy, the link is created next to where it
+    // belongs and then renamed into place.
+    fn link_latest(&self, latest: &Path) -> std::io::Result<()> {
+        let points_to = if self.target.parent() == latest.parent() {
+            PathBuf::from(self.target.file_name().unwrap_or_default())
+        } else {
+            self.target.clone()
+        };
+
+        copy_info!(self, latest = %latest.display(), target = %self.target.display(),
+                       "Updating latest link");
+
+        let temp = temp_path(latest);
+        let result = std::os::unix::fs::symlink(&points_to, &temp)
+            .and_then(|_| std::fs::rename(&temp, latest))
+            .map_err(|err| {
+                tracing::error!(latest = %latest.display(), "Failed to update latest link: {err:?}");
+                err
+            });
+
+        if result.is_err() {
+            if let Err(err) = std::fs::remove_file(&temp) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!(temp = %temp.display(), "Failed to remove temporary file: {err:?}");
+                }
+            }
+        }
+
+        result
+    }
+
+    // Check whether the source is larger than the 'max_size' of the target, in which case the copy
+    // is skipped with a warning. This guards against a misconfigured source filling the target.
+    fn is_too_large(&self) -> std::io::Result<bool> {
+        let Some(max_size) = self.options.max_size else {
+            return Ok(false);
+        };
+
+        let size = std::fs::metadata(&self.source)
+            .map_err(|err| {
+                tracing::error!(source = %self.source.display(), "Failed to read source metadata: {err:?}");
+                err
+            })?
+            .len();
+
+        if size > max_size {
+            tracing::warn!(source = %self.source.display(), target = %self.target.display(),
+                           size, max_size, "Skipped (source is larger than 'max_size')");
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    // Check whether there is enough space on the filesystem of the target for the source, when
+    // 'check_space' is set, in which case the copy is skipped with a warning rather than failing
+    // part way through and leaving a truncated target. A copy that is made directly onto the
+    // target can also reuse the space of the existing target.
+    fn is_out_of_space(&self) -> std::io::Result<bool> {
+        if !self.options.check_space {
+            return Ok(false);
+        }
+
+        let size = std::fs::metadata(&self.source)?.len();
+        let size = match self.options.range {
+            Some(ByteRange {
+                offset,
+                length: Some(length),
+            }) => length.min(size.saturating_sub(offset)),
+            Some(ByteRange { offset, .. }) => size.saturating_sub(offset),
+            None => size,
+        };
+
+        // The directory of the target may not have been created yet, in which case the target
+        // will be on the filesystem of the closest directory that does exist.
+        let directory = self
+            .target
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| ancestor.is_dir())
+            .unwrap_or(Path::new("/"));
+        let stats = nix::sys::statvfs::statvfs(directory).map_err(|err| {
+            tracing::error!(directory = %directory.display(),
+                            "Failed to read free space of target filesystem: {err:?}");
+            std::io::Error::from(err)
+        })?;
+
+        let mut available = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+        if !self.options.atomic {
+            available += std::fs::metadata(&self.target).map_or(0, |metadata| metadata.len());
+        }
+
+        if size > available {
+            tracing::warn!(source = %self.source.display(), target = %self.target.display(),
+                           size, available, "Skipped (not enough space for target)");
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    // Whether the source is too short to hold the range that is copied from it. The source may be
+    // growing (such as a log file), so we check again for each copy rather than failing.
+    fn is_too_short(&self) -> std::io::Result<bool> {
+        let Some(range) = self.options.range else {
+            return Ok(false);
+        };
+
+        let size = std::fs::metadata(&self.source)
+            .map_err(|err| {
+                tracing::error!(source = %self.source.display(), "Failed to read source metadata: {err:?}");
+                err
+            })?
+            .len();
+
+        let end = range
+            .offset
+            .saturating_add(range.length.unwrap_or_default());
+        if end > size {
+            tracing::warn!(source = %self.source.display(), target = %self.target.display(),
+                           size, offset = range.offset, length = range.length,
+                           "Skipped (source is shorter than 'offset' and 'length')");
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    // Recreate the symbolic link at the source as a link at the target, pointing to the same place.
+    // The link is created next to the target and then renamed into place, replacing the target.
+    fn copy_symlink(&self) -> std::io::Result<CopyOutcome> {
+        let link = std::fs::read_link(&self.source).map_err(|err| {
+            tracing::error!(source = %self.source.display(), "Failed to read symbolic link: {err:?}");
+            err
+        })?;
+
+        if self.options.skip_if_unchanged
+            && std::fs::read_link(&self.target).is_ok_and(|existing| existing == link)
+        {
+            copy_info!(self, target = %self.target.display(), "Skipped (unchanged)");
+            return Ok(CopyOutcome::default());
+        }
+
+        copy_info!(self, source = %self.source.display(), target = %self.target.display(),
+                       link = %link.display(), "Copying symbolic link");
+
+        let created = self.create_parent()?;
+        let temp = temp_path(&self.target);
+        let result = std::os::unix::fs::symlink(&link, &temp)
+            .and_then(|_| std::fs::rename(&temp, &self.target))
+            .map_err(|err| {
+                tracing::error!(target = %self.target.display(),
+                                "Failed to create symbolic link: {err:?}");
+                err
+            });
+
+        if result.is_err() {
+            if let Err(err) = std::fs::remove_file(&temp) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!(temp = %temp.display(), "Failed to remove temporary file: {err:?}");
+                }
+            }
+        }
+
+        result?;
+        Ok(CopyOutcome {
+            created,
+            bytes: Some(0),
+            ..CopyOutcome::default()
+        })
+    }
+
+    // Copy the source to a temporary file next to the target, and then rename it into place. This
+    // ensures that readers of the target never observe a partially written file.
+    fn copy_atomic(&self) -> std::io::Result<u64> {
+        // The temporary file is created in the same directory as the target, which is usually on
+        // the same filesystem. The exception is a target that is itself a mount point (such as a
+        // file that is bind-mounted into a container), which cannot be renamed over.
+        if self.is_target_on_other_device() {
+            return self.copy_fallback();
+        }
+
+        let temp = temp_path(&self.target);
+        let result = self.copy_to(&temp).and_then(|bytes| {
+            std::fs::rename(&temp, &self.target)
+                .map(|_| bytes)
+                .map_err(|err| {
+                    tracing::error!(temp = %temp.display(), target = %self.target.display(),
+                                "Failed to rename temporary file to target: {err:?}");
+                    err
+                })
+        });
+
+        if result.is_err() {
+            if let Err(err) = std::fs::remove_file(&temp) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!(temp = %temp.display(), "Failed to remove temporary file: {err:?}");
+                }
+            }
+        }
+
+        match result {
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => self.copy_fallback(),
+            result => result,
+        }
+    }
+
+    // Whether the target exists and is on a different device to the directory that contains it.
+    fn is_target_on_other_device(&self) -> bool {
+        let Ok(target) = std::fs::symlink_metadata(&self.target) else {
+            return false;
+        };
+
+        let Some(parent) = self.parent() else {
+            return false;
+        };
+
+        std::fs::metadata(parent).is_ok_and(|parent| parent.dev() != target.dev())
+    }
+
+    // Copy directly onto the target when it cannot be replaced atomically, unless the target does
+    // not allow falling back to a non-atomic copy.
+    fn copy_fallback(&self) -> std::io::Result<u64> {
+        if !self.options.atomic_fallback {
+            tracing::error!(target = %self.target.display(),
+                            "Target is on a different filesystem to its directory, and cannot be copied atomically");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::CrossesDevices,
+                format!(
+                    "target {:?} cannot be copied atomically, and 'atomic_fallback' is disabled",
+                    self.target
+                ),
+            ));
+        }
+
+        tracing::warn!(target = %self.target.display(),
+                       "Target is on a different filesystem to its directory; copying directly");
+        self.copy_to(&self.target)
+    }
+
+    // Write the checksum of the target to a sidecar file next to it, in the format used by tools
+    // such as 'sha256sum'. Like the target, the sidecar is written to a temporary file that is
+    // then renamed into place, so that readers never observe a partially written checksum.
+    fn write_sidecar(&self, algorithm: ChecksumAlgorithm) -> std::io::Result<()> {
+        let hash = algorithm.hash_file(&self.target)?;
+        let hex = hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let name = self
+            .target
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        let sidecar = self.sidecar_path(algorithm);
+        let temp = temp_path(&sidecar);
+        let result = std::fs::write(&temp, format!("{hex}  {name}\n"))
+            .and_then(|_| std::fs::rename(&temp, &sidecar))
+            .map_err(|err| {
+                tracing::error!(sidecar = %sidecar.display(), "Failed to write checksum: {err:?}");
+                err
+            });
+
+        if result.is_err() {
+            if let Err(err) = std::fs::remove_file(&temp) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!(temp = %temp.display(), "Failed to remove temporary file: {err:?}");
+                }
+            }
+        }
+
+        result
+    }
+
+    // Log the copy that would be made, without touching the filesystem.
+    fn dry_run(&self) {
+        if let Some(parent) = self.parent() {
+            if !parent.exists() {
+                tracing::info!(directory = %parent.display(), "Dry run: would create directory");
+            }
+        }
+
+        match std::fs::metadata(&self.source) {
+            Ok(metadata) => tracing::info!(
+                source = %self.source.display(),
+                target = %self.target.display(),
+                bytes = metadata.len(),
+                "Dry run: would copy"
+            ),
+            Err(err) => tracing::warn!(
+                source = %self.source.display(),
+                "Dry run: would fail to copy, as the source cannot be read: {err:?}"
+            ),
+        }
+
+        if let Some(algorithm) = self.options.checksum_sidecar {
+            tracing::info!(sidecar = %self.sidecar_path(algorithm).display(),
+                           "Dry run: would write checksum");
+        }
+    }
+
+    // Check whether the target already has the same contents as the source. A target that does not
+    // exist, or that differs in size, is always considered changed; otherwise we compare hashes.
+    fn is_unchanged(&self) -> std::io::Result<bool> {
+        let target_metadata = match std::fs::metadata(&self.target) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => {
+                tracing::error!(target = %self.target.display(), "Failed to read target metadata: {err:?}");
+                return Err(err);
+            }
+        };
+
+        let source_metadata = std::fs::metadata(&self.source).map_err(|err| {
+            tracing::error!(source = %self.source.display(), "Failed to read source metadata: {err:?}");
+            err
+        })?;
+
+        // A transform, compression or a range changes the size of the contents, so we can only
+        // compare their hashes.
+        let is_whole = !self.options.changes_contents();
+        if is_whole && source_metadata.len() != target_metadata.len() {
+            return Ok(false);
+        }
+
+        Ok(self.source_hash()? == hash_file(&self.target)?)
+    }
+
+    // Check the 'only_if' condition of the target against the existing target (if any). This lets
+    // a target that was updated downstream keep its changes, rather than being clobbered.
+    fn is_condition_met(&self) -> std::io::Result<bool> {
+        if self.options.only_if == CopyCondition::Always {
+            return Ok(true);
+        }
+
+        let target_metadata = match std::fs::metadata(&self.target) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+            Err(err) => {
+                tracing::error!(target = %self.target.display(), "Failed to read target metadata: {err:?}");
+                return Err(err);
+            }
+        };
+
+        if self.options.only_if == CopyCondition::Missing {
+            return Ok(false);
+        }
+
+        let source_metadata = std::fs::metadata(&self.source).map_err(|err| {
+            tracing::error!(source = %self.source.display(), "Failed to read source metadata: {err:?}");
+            err
+        })?;
+
+        let source_modified = source_metadata.modified()?;
+        let target_modified = target_metadata.modified()?;
+        Ok(source_modified > target_modified
+            || (source_modified == target_modified
+                && source_metadata.len() != target_metadata.len()))
+    }
+
+    // Copy the source to the given destination, which is either the target itself or a temporary
+    // file that will be renamed over the target.
+    fn copy_to(&self, dest: &Path) -> std::io::Result<u64> {
+        let bytes = self.copy_contents(dest).map_err(|err| {
+            tracing::error!(source = %self.source.display(), target = %dest.display(),
+                          "Failed to copy from source to target: {err:?}");
+            err
+        })?;
+
+        self.apply_attributes(dest)?;
+        Ok(bytes)
+    }
+
+    // Re-apply the attributes of the source to the target, without copying its contents, after
+    // only the attributes of the source have changed.
+    fn update_attributes(&self) -> std::io::Result<()> {
+        copy_info!(self, source = %self.source.display(), target = %self.target.display(),
+                       "Updating attributes");
+
+        let permissions = std::fs::metadata(&self.source)?.permissions();
+        std::fs::set_permissions(&self.target, permissions).map_err(|err| {
+            tracing::error!(target = %self.target.display(), "Failed to set permissions: {err:?}");
+            err
+        })?;
+
+        self.apply_attributes(&self.target)
+    }
+
+    // Apply the metadata, ownership and mode that are configured for the target to the destination.
+    fn apply_attributes(&self, dest: &Path) -> std::io::Result<()> {
+        if self.options.preserve {
+            self.preserve_metadata(dest)?;
+        }
+
+        if self.options.preserve_xattrs {
+            self.copy_xattrs(dest)?;
+        }
+
+        if self.options.owner.is_some() || self.options.group.is_some() {
+            self.set_ownership(dest)?;
+        }
+
+        // Set the mode of the target last, as changing the ownership can clear some of the mode
+        // bits (such as setuid).
+        if let Some(FileMode(mode)) = self.options.mode {
+            std::fs::set_permissions(dest, std::fs::Permissions::from_mode(mode)).map_err(
+                |err| {
+                    tracing::error!(target = %dest.display(), mode = format!("{mode:04o}"),
+                                "Failed to set file mode: {err:?}");
+                    err
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Copy the contents and permissions of the source to the destination, only copying its range
+    // and through the transform and compression if it has them. If the copyset has a rate limit
+    // then the contents are copied in chunks through the limiter, unless the source is small enough
+    // that throttling it would make no difference.
+    fn copy_contents(&self, dest: &Path) -> std::io::Result<u64> {
+        match self.options.method {
+            CopyMethod::Copy => {}
+            CopyMethod::Reflink => {
+                if let Some(bytes) = self.reflink(dest)? {
+                    return Ok(bytes);
+                }
+            }
+            CopyMethod::Hardlink => {
+                if let Some(bytes) = self.hard_link(dest)? {
+                    return Ok(bytes);
+                }
+            }
+        }
+
+        if self.options.changes_contents() {
+            let (mut source, metadata) = self.open_source()?;
+            let mut target = std::fs::File::create(dest)?;
+            self.transform(&mut source, &mut target, self.options.rate_limit.as_deref())?;
+
+            target.set_permissions(metadata.permissions())?;
+            return Ok(target.metadata()?.len());
+        }
+
+        let Some(limiter) = &self.options.rate_limit else {
+            return std::fs::copy(&self.source, dest);
+        };
+
+        let mut source = std::fs::File::open(&self.source)?;
+        let metadata = source.metadata()?;
+        if metadata.len() < throttle::CHUNK_SIZE as u64 {
+            return std::fs::copy(&self.source, dest);
+        }
+
+        let mut target = std::fs::File::create(dest)?;
+        let bytes = limiter.copy(&mut source, &mut target)?;
+        target.set_permissions(metadata.permissions())?;
+        Ok(bytes)
+    }
+
+    // Clone the source to the destination, returning the size of the clone, or None if the
+    // filesystem does not support it and the source should be copied instead.
+    fn reflink(&self, dest: &Path) -> std::io::Result<Option<u64>> {
+        if !clear_for_link(dest) {
+            return Ok(None);
+        }
+
+        match reflink_copy::reflink(&self.source, dest) {
+            Ok(()) => {
+                let metadata = std::fs::metadata(&self.source)?;
+                std::fs::set_permissions(dest, metadata.permissions())?;
+                Ok(Some(metadata.len()))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(err),
+            Err(err) => {
+                tracing::debug!(source = %self.source.display(), target = %dest.display(),
+                                "Unable to clone source; copying it instead: {err:?}");
+                Ok(None)
+            }
+        }
+    }
+
+    // Link the destination to the source, returning the size of the source, or None if they are on
+    // different filesystems and the source should be copied instead.
+    fn hard_link(&self, dest: &Path) -> std::io::Result<Option<u64>> {
+        if !clear_for_link(dest) {
+            return Ok(None);
+        }
+
+        match std::fs::hard_link(&self.source, dest) {
+            Ok(()) => Ok(Some(std::fs::metadata(dest)?.len())),
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                tracing::warn!(source = %self.source.display(), target = %dest.display(),
+                               "Unable to link target to a source on another filesystem; \
+                                copying it instead");
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Change the ownership of the target to the configured owner and group. As with preserving the
+    // ownership, failing to do so for lack of privileges only results in a warning.
+    fn set_ownership(&self, dest: &Path) -> std::io::Result<()> {
+        let (uid, gid) = (self.options.owner, self.options.group);
+        if let Err(err) = chown(dest, uid, gid) {
+            if err.kind() == std::io::ErrorKind::PermissionDenied {
+                tracing::warn!(target = %dest.display(), uid = ?uid, gid = ?gid,
+                               "Unable to change ownership of target; this requires running as \
+                                root or with the CAP_CHOWN capability: {err:?}");
+            } else {
+                tracing::error!(target = %dest.display(), "Failed to set ownership: {err:?}");
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy the extended attributes of the source (such as SELinux labels and file capabilities)
+    // over to the destination. A filesystem that does not support extended attributes, or an
+    // attribute that we lack the privileges to set, only results in a warning.
+    fn copy_xattrs(&self, dest: &Path) -> std::io::Result<()> {
+        let names = match xattr::list_deref(&self.source) {
+            Ok(names) => names,
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+                tracing::warn!(source = %self.source.display(),
+                               "Source does not support extended attributes: {err:?}");
+                return Ok(());
+            }
+            Err(err) => {
+                tracing::error!(source = %self.source.display(),
+                                "Failed to list extended attributes: {err:?}");
+                return Err(err);
+            }
+        };
+
+        for name in names {
+            let Some(value) = xattr::get_deref(&self.source, &name)? else {
+                continue;
+            };
+
+            match xattr::set(dest, &name, &value) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+                    tracing::warn!(target = %dest.display(),
+                                   "Target does not support extended attributes: {err:?}");
+                    return Ok(());
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                    tracing::warn!(target = %dest.display(), name = ?name,
+                                   "Insufficient privileges to set extended attribute: {err:?}");
+                }
+                Err(err) => {
+                    tracing::error!(target = %dest.display(), name = ?name,
+                                    "Failed to set extended attribute: {err:?}");
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy the access and modification times, along with the ownership, of the source over to the
+    // target. Changing the ownership usually requires privileges that we may not have, so failing
+    // to do so only results in a warning.
+    fn preserve_metadata(&self, dest: &Path) -> std::io::Result<()> {
+        let metadata = std::fs::metadata(&self.source)?;
+
+        let atime = FileTime::from_last_access_time(&metadata);
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        filetime::set_file_times(dest, atime, mtime).map_err(|err| {
+            tracing::error!(target = %dest.display(), "Failed to set file times: {err:?}");
+            err
+        })?;
+
+        if let Err(err) = chown(dest, Some(metadata.uid()), Some(metadata.gid())) {
+            if err.kind() == std::io::ErrorKind::PermissionDenied {
+                tracing::warn!(target = %dest.display(), uid = metadata.uid(), gid = metadata.gid(),
+                               "Insufficient privileges to preserve ownership: {err:?}");
+            } else {
+                tracing::error!(target = %dest.display(), "Failed to set ownership: {err:?}");
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Remove any event that repeats the previous event for the same file, so that (for example) a run
+// of 'MODIFY' events while a file is being written is handled once. The events are otherwise
+// kept in order, as different events for the same file may need different handling.
+fn coalesce(events: Vec<EventOwned>) -> Vec<EventOwned> {
+    let mut previous = HashMap::new();
+    let coalesced = events
+        .iter()
+        .filter(|event| previous.insert((&event.wd, &event.name), event.mask) != Some(event.mask))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if coalesced.len() < events.len() {
+        tracing::trace!(
+            events = events.len(),
+            coalesced = coalesced.len(),
+            "Coalesced repeated events"
+        );
+    }
+
+    coalesced
+}
+
+// Whether a watch with the given mask asked for an event with the given mask. Several targets can
+// share one watch, each of which may have asked for different events.
+fn is_watching(mask: WatchMask, event: EventMask) -> bool {
+    mask.intersects(WatchMask::from_bits_truncate(event.bits()) & WatchMask::ALL_EVENTS)
+}
+
+// The kind of special file (such as a named pipe or a device) that is at the given path, following
+// any symbolic links, or None if it is a regular file or a directory (or does not exist).
+fn special_file_kind(path: &Path) -> Option<&'static str> {
+    let file_type = std::fs::metadata(path).ok()?.file_type();
+    if file_type.is_fifo() {
+        Some("named pipe")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+// A random duration of up to the given number of milliseconds.
+fn jitter(max_ms: u64) -> Duration {
+    match max_ms {
+        0 => Duration::ZERO,
+        max_ms => Duration::from_millis(RandomState::new().build_hasher().finish() % (max_ms + 1)),
+    }
+}
+
+// Build the path of a temporary file in the same directory as the given path, so that renaming it
+// over the path stays on the one filesystem. The PID and a counter are included in the name so
+// that concurrent copies never collide.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    path.with_file_name(name)
+}
+
+// Remove any existing file at the path, so that a clone of or a link to the source can be created
+// in its place. Some files cannot be removed (such as a file that is bind-mounted into a
+// container), in which case the source has to be copied onto the file instead.
+fn clear_for_link(path: &Path) -> bool {
+    match std::fs::remove_file(path) {
+        Ok(()) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => true,
+        Err(err) => {
+            tracing::debug!(target = %path.display(),
+                            "Unable to remove target; copying onto it instead: {err:?}");
+            false
+        }
+    }
+}
+
+// Compute the SHA-256 hash of the contents of the file at the given path.
+fn hash_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(path).map_err(|err| {
+        tracing::error!(path = %path.display(), "Failed to open file for hashing: {err:?}");
+        err
+    })?;
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+// The largest number of events that have already been read that are handled together.
+const MAX_EVENT_BATCH: usize = 1024;
+
+// How often to check whether the source of a copyset exists, while waiting for it to appear.
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// How long to wait for events in the configuration directory to stop before checking whether the
+// configuration file has changed.
+const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// How long to wait before retrying a failed copy for the first time, and the longest that we wait
+// as the delay doubles with each further failure.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Run a hook command, with the given variables in its environment and the given input (if any) on
+// its standard input. A hook that fails is only logged, as the copies themselves have already been
+// made. The command is run directly rather than through a shell, and the paths of the copies are
+// only ever passed in the environment or on the standard input: they are never substituted into
+// the command or its arguments, so paths containing spaces, newlines or shell metacharacters cannot
+// change what is run.
+async fn run_hook(command: Vec<String>, env: Vec<(&str, OsString)>, input: Option<Vec<u8>>) {
+    tracing::info!(command = ?command, "Running hook");
+    let mut process = tokio::process::Command::new(&command[0]);
+    process
+        .args(&command[1..])
+        .envs(env)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let output = match process.spawn() {
+        Ok(mut child) => {
+            // The input is written alongside reading the output, so that a command that writes a
+            // lot before reading its input cannot block us. A command that exits without reading
+            // all of its input is not an error in itself.
+            if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+                tokio::spawn(async move {
+                    let _ = stdin.write_all(&input).await;
+                });
+            }
+
+            child.wait_with_output().await
+        }
+        Err(err) => Err(err),
+    };
+
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            tracing::warn!(command = ?command, "Failed to run hook: {err:?}");
+            return;
+        }
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        tracing::info!(command = ?command, "Hook stdout: {line}");
+    }
+
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        tracing::info!(command = ?command, "Hook stderr: {line}");
+    }
+
+    if !output.status.success() {
+        tracing::warn!(command = ?command, "Hook failed: {}", output.status);
+    }
+}
+
+impl Env {
+    /// Process events until we are asked to stop, reloading the configuration from the given path
+    /// when asked to, and answering any commands received from the control socket.
+    pub async fn run(
+        self,
+        config_path: &Path,
+        control: Option<mpsc::Receiver<Request>>,
+    ) -> std::io::Result<()> {
+        tracing::info!(
+            watches = self.watch_count(),
+            polled = self.polled.len(),
+            "Processing inotify events"
+        );
+        health::set_running(true);
+        systemd::ready();
+        let result = self.process_events(config_path, control).await;
+        health::set_running(false);
+        result
+    }
+
+    async fn process_events(
+        mut self,
+        config_path: &Path,
+        mut control: Option<mpsc::Receiver<Request>>,
+    ) -> std::io::Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut sigusr1 = signal(SignalKind::user_defined1())?;
+        let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
+
+        loop {
+            // If there are any debounced copies waiting, then we also want to wake up when the
+            // earliest of them is due.
+            let deadline = self
+                .pending
+                .values()
+                .chain(self.hooks.values().map(|hook| &hook.deadline))
+                .chain(self.batches.values().map(|batch| &batch.deadline))
+                .chain(self.retries.values().map(|retry| &retry.deadline))
+                .chain(self.polled.iter().map(|polled| &polled.next_poll))
+                .chain(
+                    self.config_watch
+                        .iter()
+                        .filter_map(|watch| watch.deadline.as_ref()),
+                )
+                .min()
+                .copied();
+            metrics::set_watches(self.watch_count());
+            self.publish_status();
+
+            tokio::select! {
+                event_or_error = self.stream.next() => {
+                    let Some(event_or_error) = event_or_error else {
+                        break;
+                    };
+
+                    // Any further events that have already been read are handled along with this
+                    // one, so that repeats of the same event only cause one copy.
+                    let mut events = vec![event_or_error?];
+                    while events.len() < MAX_EVENT_BATCH {
+                        match self.stream.next().now_or_never() {
+                            Some(Some(event_or_error)) => events.push(event_or_error?),
+                            Some(None) | None => break,
+                        }
+                    }
+
+                    for event in coalesce(events) {
+                        self.handle_event(event).await?;
+                    }
+                }
+
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => {
+                    self.copy_pending();
+                    self.copy_retries();
+                    self.poll_sources()?;
+                    self.run_hooks(false).await;
+                    self.reload_if_changed(config_path).await?;
+                }
+
+                _ = tokio::time::sleep_until(self.next_resync.unwrap_or_else(Instant::now)),
+                    if self.next_resync.is_some() => {
+                    self.resync_all();
+                    self.schedule_resync();
+                }
+
+                _ = async { watchdog.as_mut().unwrap().tick().await }, if watchdog.is_some() => {
+                    systemd::watchdog();
+                }
+
+                Some(joined) = self.copies.join_next() => {
+                    self.finish_copy(joined)?;
+                    self.finish_startup();
+                }
+
+                request = async { control.as_mut().unwrap().recv().await }, if control.is_some() => {
+                    match request {
+                        Some(request) => self.handle_control(request),
+                        None => control = None,
+                    }
+                }
+
+                // Any copies that are in progress when a signal arrives are allowed to complete
+                // during the shutdown.
+                _ = sigterm.recv() => {
+                    tracing::info!("Received SIGTERM; shutting down");
+                    return self.shutdown().await;
+                }
+
+                _ = sigint.recv() => {
+                    tracing::info!("Received SIGINT; shutting down");
+                    return self.shutdown().await;
+                }
+
+                _ = sighup.recv() => {
+                    tracing::info!("Received SIGHUP");
+                    self.reload(config_path).await?;
+                }
+
+                _ = sigusr1.recv() => {
+                    tracing::info!("Received SIGUSR1");
+                    self.copy_all();
+                }
+            }
+        }
+
+        tracing::info!("Inotify stream ended");
+        self.finish_copies().await?;
+        self.log_totals();
+        Ok(())
+    }
+
+    /// Wait for the initial copies to complete, along with any hooks that they trigger, without
+    /// processing any events. If any of the targets could not be added or copied, then the whole
+    /// run fails.
+    pub async fn once(mut self) -> std::io::Result<()> {
+        self.finish_copies().await?;
+
+        // Targets that retry their failed copies are copied again until they succeed (or have
+        // failed too many times), as we have nothing else to wait for.
+        while let Some(deadline) = self.retries.values().map(|retry| retry.deadline).min() {
+            tokio::time::sleep_until(deadline).await;
+            self.copy_retries();
+            self.finish_copies().await?;
+        }
+
+        self.run_hooks(true).await;
+        self.log_totals();
+
+        if !self.unwatched.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "failed to add {} target(s)",
+                self.unwatched.len()
+            )));
+        }
+
+        let missing = self.missing.values().map(Vec::len).sum::<usize>();
+        if missing > 0 {
+            return Err(std::io::Error::other(format!(
+                "the sources of {missing} target(s) do not exist"
+            )));
+        }
+
+        if !self.failures.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "failed to copy {} target(s)",
+                self.failures.len()
+            )));
+        }
+
+        tracing::info!("All targets copied");
+        Ok(())
+    }
+
+    // Complete any debounced copies that are still waiting to be made, as the changes that they
+    // represent have already been observed, and then stop.
+    async fn shutdown(mut self) -> std::io::Result<()> {
+        health::set_running(false);
+        systemd::stopping();
+        if !self.pending.is_empty() {
+            tracing::info!("Completing {} pending copies", self.pending.len());
+            for ((wd, name), _) in std::mem::take(&mut self.pending) {
+                for target in self.event_targets(&wd, name.as_deref()) {
+                    self.copy_target(&target);
+                }
+            }
+        }
+
+        if !self.copies.is_empty() {
+            tracing::info!("Waiting for {} copies to complete", self.copies.len());
+        }
+
+        self.finish_copies().await?;
+        self.run_hooks(true).await;
+        self.log_totals();
+
+        tracing::info!("Shutdown complete");
+        Ok(())
+    }
+
+    // Log a summary of what was copied over the whole run, as we exit.
+    fn log_totals(&self) {
+        let CopyTotals {
+            copies,
+            failures,
+            bytes,
+        } = self.totals;
+        tracing::info!(copies, failures, bytes, "Run summary");
+    }
+
+    async fn handle_event(&mut self, event: EventOwned) -> std::io::Result<()> {
+        tracing::trace!(wd = ?event.wd, mask = ?event.mask, name = ?event.name, "Received event");
+
+        // The kernel's event queue filled up before we read from it, so some events were dropped.
+        // We cannot tell which sources changed, so we copy all of them again.
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            tracing::warn!(
+                "Inotify event queue overflowed and events were lost; copying all targets again"
+            );
+            self.copy_all();
+            return Ok(());
+        }
+
+        if let Some(watch) = &mut self.config_watch {
+            if watch.wd == event.wd {
+                if event.mask.contains(EventMask::IGNORED) {
+                    tracing::warn!("Configuration directory is no longer being watched");
+                    self.config_watch = None;
+                } else {
+                    watch.deadline = Some(Instant::now() + CONFIG_WATCH_DEBOUNCE);
+                }
+
+                if !self.targets.contains_key(&event.wd)
+                    && !self.directories.contains_key(&event.wd)
+                    && !self.missing.contains_key(&event.wd)
+                    && !self.parents.contains_key(&event.wd)
+                {
+                    return Ok(());
+                }
+            }
+        }
+
+        // The directory may be watched for files moved into the place of a source, as well as for
+        // the sources that do not exist yet or for a recursive or glob target.
+        if self.parents.contains_key(&event.wd) {
+            self.handle_parent_event(&event);
+            if !self.missing.contains_key(&event.wd) && !self.directories.contains_key(&event.wd) {
+                return Ok(());
+            }
+        }
+
+        // The directory may also be watched for a recursive or glob target.
+        if self.missing.contains_key(&event.wd) {
+            self.handle_missing_event(&event);
+            if !self.directories.contains_key(&event.wd) {
+                return Ok(());
+            }
+        }
+
+        if self.directories.contains_key(&event.wd) {
+            return self.handle_directory_event(event).await;
+        }
+
+        // The kernel removes a watch when the watched file is deleted (including when another
+        // file is renamed over it, as many editors and tools do when saving). When that happens,
+        // we try to watch whatever file is now at the same path.
+        if event.mask.contains(EventMask::IGNORED) {
+            let pending = self.pending.remove(&(event.wd.clone(), None)).is_some();
+            let mut fired = false;
+            for target in self.targets.remove(&event.wd).unwrap_or_default() {
+                // The kernel also removes a one-shot watch after its first event. Those targets
+                // are not watched again, but any copy that the event triggered is still made.
+                if target.events.contains(WatchMask::ONESHOT) {
+                    if pending {
+                        self.copy_target(&target);
+                    }
+
+                    tracing::info!(source = %target.source.display(),
+                                   "One-shot watch has fired; source is no longer watched");
+                    fired = true;
+                    continue;
+                }
+
+                self.rearm(target).await?;
+            }
+
+            // The directory of a source that is no longer watched may not need watching either.
+            if fired {
+                self.prune_parents();
+            }
+
+            return Ok(());
+        }
+
+        // If the source has been moved, then the watch will follow it to its new location. Remove
+        // the watch: the kernel then sends us an 'IGNORED' event, at which point we re-arm the
+        // watch on the original path.
+        if event.mask.contains(EventMask::MOVE_SELF) {
+            if let Some(target) = self
+                .targets
+                .get(&event.wd)
+                .and_then(|targets| targets.first())
+            {
+                tracing::info!(source = %target.source.display(), "Source was moved; removing watch");
+
+                // The kernel may already have removed the watch (such as when the moved source has
+                // since been deleted), in which case the 'IGNORED' event is on its way anyway.
+                if let Err(err) = self.watches.remove(event.wd) {
+                    tracing::warn!(source = %target.source.display(),
+                                   "Failed to remove watch of moved source: {err:?}");
+                }
+            }
+
+            return Ok(());
+        }
+
+        // The source has been deleted, and the kernel will follow up with an 'IGNORED' event.
+        if event.mask.contains(EventMask::DELETE_SELF) {
+            return Ok(());
+        }
+
+        let Some(targets) = self.targets.get(&event.wd) else {
+            tracing::warn!(wd = ?event.wd, "Unknown watch descriptor");
+            return Ok(());
+        };
+
+        // Only the targets that asked for this type of event are interested in it.
+        let targets = targets
+            .iter()
+            .filter(|target| is_watching(target.watch_mask(), event.mask))
+            .cloned()
+            .collect();
+
+        self.dispatch((event.wd, None), targets, event.mask)
+    }
+
+    async fn handle_directory_event(&mut self, event: EventOwned) -> std::io::Result<()> {
+        // The directory has been removed, so there is nothing left to watch.
+        if event.mask.contains(EventMask::IGNORED) {
+            for directory in self.directories.remove(&event.wd).unwrap_or_default() {
+                tracing::info!(directory = %directory.path.display(), "Directory is no longer being watched");
+            }
+
+            self.pending.retain(|(wd, _), _| *wd != event.wd);
+            return Ok(());
+        }
+
+        // The directory has been moved out from under us. Stop watching it: if it has been moved
+        // elsewhere within the source, the parent directory will tell us about its new location.
+        if event.mask.contains(EventMask::MOVE_SELF) {
+            // As for a moved source, the kernel may already have removed the watch.
+            if let Err(err) = self.watches.remove(event.wd) {
+                tracing::warn!("Failed to remove watch of moved directory: {err:?}");
+            }
+
+            return Ok(());
+        }
+
+        // We're only interested in the contents of the directory, not the directory itself.
+        let Some(name) = event.name else {
+            return Ok(());
+        };
+
+        // A directory has been created in (or moved into) this directory. For each recursive
+        // target that this directory is part of, we need to watch it and copy its contents.
+        if event.mask.contains(EventMask::ISDIR) {
+            if event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                let additions = self.directories[&event.wd]
+                    .iter()
+                    .filter(|directory| directory.root.options.recursive)
+                    .map(|directory| (directory.root.clone(), directory.path.join(&name)))
+                    .filter(|(root, path)| !root.options.exclude.is_excluded(path, true))
+                    .collect::<Vec<_>>();
+
+                for (root, path) in additions {
+                    self.add_directory(&root, path).await?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        // We ignore anything that is not a regular file, such as a file that has already been
+        // removed again, unless the event is telling us that the file has been removed.
+        let mut targets = self.directory_children(&event.wd, &name, event.mask);
+        if !event
+            .mask
+            .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+        {
+            targets.retain(ResolvedTarget::is_copyable);
+        }
+
+        self.dispatch((event.wd, Some(name)), targets, event.mask)
+    }
+
+    // Watch and copy the targets whose source has been replaced by a file moved into its place, as
+    // tools that deploy files atomically do. The watch on the source stays with the file that was
+    // replaced, and the kernel only removes it (and tells us to re-arm it) once nothing else has
+    // that file open, so we cannot wait for that.
+    fn handle_parent_event(&mut self, event: &EventOwned) {
+        if event.mask.contains(EventMask::IGNORED) {
+            self.parents.remove(&event.wd);
+            return;
+        }
+
+        let (Some(name), Some(directory)) = (&event.name, self.parents.get(&event.wd)) else {
+            return;
+        };
+
+        if event.mask.contains(EventMask::ISDIR) || !event.mask.contains(EventMask::MOVED_TO) {
+            return;
+        }
+
+        let source = directory.join(name);
+        let watched = self
+            .targets
+            .iter()
+            .filter(|(_, targets)| targets.iter().any(|target| target.source == source))
+            .map(|(wd, _)| wd.clone())
+            .collect::<Vec<_>>();
+
+        for wd in watched {
+            let (moved, mut remaining) = self
+                .targets
+                .remove(&wd)
+                .unwrap_or_default()
+                .into_iter()
+                .partition::<Vec<_>, _>(|target| target.source == source);
+
+            for target in moved {
+                match self.add_watch(&target.source, target.watch_mask()) {
+                    // The watch has already been re-armed on the file that was moved into place.
+                    Ok(added) if added == wd => remaining.push(target),
+                    Ok(added) => {
+                        tracing::info!(source = %target.source.display(),
+                                       "Source was replaced by a moved file; re-armed watch");
+                        self.copy_target(&target);
+                        self.targets.entry(added).or_default().push(target);
+                    }
+                    Err(err) => {
+                        tracing::error!(source = %target.source.display(),
+                                        "Failed to re-add watch: {err:?}");
+                    }
+                }
+            }
+
+            if !remaining.is_empty() {
+                self.targets.insert(wd, remaining);
+                continue;
+            }
+
+            // The kernel may already have removed the watch on the replaced file, in which case
+            // there is nothing left for us to remove.
+            self.pending.remove(&(wd.clone(), None));
+            if !self.is_watched(&wd) {
+                if let Err(err) = self.watches.remove(wd) {
+                    tracing::debug!("Failed to remove watch of replaced source: {err:?}");
+                }
+            }
+        }
+    }
+
+    // Watch and copy any of the targets waiting in a directory for their source to be created, once
+    // it has been created in (or moved into) the directory.
+    fn handle_missing_event(&mut self, event: &EventOwned) {
+        if event.mask.contains(EventMask::IGNORED) {
+            for target in self.missing.remove(&event.wd).unwrap_or_default() {
+                tracing::warn!(source = %target.source.display(),
+                               "Directory of source was removed; it will no longer be watched");
+            }
+
+            return;
+        }
+
+        let Some(name) = &event.name else {
+            return;
+        };
+
+        if event.mask.contains(EventMask::ISDIR)
+            || !event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+        {
+            return;
+        }
+
+        let Some(targets) = self.missing.get_mut(&event.wd) else {
+            return;
+        };
+
+        let (ready, waiting) = std::mem::take(targets)
+            .into_iter()
+            .partition::<Vec<_>, _>(|target| target.source.file_name() == Some(name.as_os_str()));
+        if waiting.is_empty() {
+            self.missing.remove(&event.wd);
+            self.release_watch(event.wd.clone());
+        } else {
+            self.missing.insert(event.wd.clone(), waiting);
+        }
+
+        // The source is watched before it is copied, so that we do not miss any changes that are
+        // made to it in the meantime.
+        for target in ready {
+            tracing::info!(source = %target.source.display(), "Source now exists; watching it");
+            let source = target.source.clone();
+            match self.watch_file(target.clone()) {
+                Ok(()) => self.copy_target(&target),
+                Err(err) => {
+                    tracing::error!(source = %source.display(), "Failed to watch source: {err:?}")
+                }
+            }
+        }
+    }
+
+    // Watch the directory that will contain the source of a target whose source does not exist
+    // yet, so that we can start watching the source once it has been created.
+    fn watch_missing(&mut self, target: ResolvedTarget) -> std::io::Result<()> {
+        let directory = target.source.parent().unwrap_or(Path::new("/"));
+        tracing::info!(directory = %directory.display(),
+                       "  Source does not exist yet; watching its directory for it");
+
+        let mask = WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::ONLYDIR;
+        let wd = self.add_watch(directory, mask).map_err(|err| {
+            tracing::error!(directory = %directory.display(), "  Failed to add watch: {err:?}");
+            err
+        })?;
+
+        self.missing.entry(wd).or_default().push(target);
+        Ok(())
+    }
+
+    // Find the targets for the file with the given name in a watched directory, from each of the
+    // recursive or glob targets that the directory is watched for that asked for the event.
+    fn directory_children(
+        &self,
+        wd: &WatchDescriptor,
+        name: &OsStr,
+        mask: EventMask,
+    ) -> Vec<ResolvedTarget> {
+        self.directories
+            .get(wd)
+            .into_iter()
+            .flatten()
+            .filter(|directory| is_watching(directory.root.directory_mask(), mask))
+            .filter_map(|directory| directory.child(name))
+            .collect()
+    }
+
+    // Act on an event for the given targets, according to the type of the event: either copying
+    // each target, removing it (if deletions are mirrored), or updating its attributes.
+    fn dispatch(
+        &mut self,
+        key: (WatchDescriptor, Option<OsString>),
+        targets: Vec<ResolvedTarget>,
+        mask: EventMask,
+    ) -> std::io::Result<()> {
+        let mut copies = Vec::new();
+        for target in targets {
+            match target.action(mask) {
+                EventAction::Copy => copies.push(target),
+                // When the target can fall back to another source, it is copied from there as the
+                // watch is re-armed, rather than being removed.
+                EventAction::Remove => {
+                    if target.options.mirror_delete && target.fallback().is_none() {
+                        self.remove_target(&target)?;
+                    }
+                }
+                EventAction::Attributes => self.update_attributes(&key, &target),
+            }
+        }
+
+        if !copies.is_empty() {
+            self.trigger_copy(key, copies);
+        }
+
+        Ok(())
+    }
+
+    // Update the attributes of a target after only the attributes of its source have changed. If
+    // the target is waiting to be copied (or is being copied), then the copy applies them anyway.
+    fn update_attributes(
+        &self,
+        key: &(WatchDescriptor, Option<OsString>),
+        target: &ResolvedTarget,
+    ) {
+        if self.pending.contains_key(key) || self.in_flight.contains_key(&target.target) {
+            return;
+        }
+
+        if self.dry_run {
+            tracing::info!(source = %target.source.display(), target = %target.target.display(),
+                           "Dry run: would update attributes");
+            return;
+        }
+
+        // As with a failed copy, a failure here is logged and otherwise ignored.
+        if let Err(err) = target.update_attributes() {
+            tracing::error!(source = %target.source.display(), target = %target.target.display(),
+                            "Failed to update attributes of target: {err:?}");
+        }
+    }
+
+    // Find the targets to copy for an event on the given watch descriptor. For watched
+    // directories, the name identifies the file within the directory; we ignore anything that is
+    // not a regular file, such as a file that has already been removed again.
+    fn event_targets(&self, wd: &WatchDescriptor, name: Option<&OsStr>) -> Vec<ResolvedTarget> {
+        if let Some(targets) = self.targets.get(wd) {
+            return targets.clone();
+        }
+
+        let Some(name) = name else {
+            return Vec::new();
+        };
+
+        self.directories
+            .get(wd)
+            .into_iter()
+            .flatten()
+            .filter_map(|directory| directory.child(name))
+            .filter(|target| {
+                let copyable = target.is_copyable();
+                if !copyable {
+                    tracing::debug!(source = %target.source.display(), "Ignoring source as it is not a file");
+                }
+
+                copyable
+            })
+            .collect()
+    }
+
+    // Copy the given target. If the target has a debounce window, then (re)schedule the copy for
+    // when the window has elapsed rather than copying straight away. Any further events that
+    // arrive within the window push the copy back again.
+    fn trigger_copy(
+        &mut self,
+        key: (WatchDescriptor, Option<OsString>),
+        targets: Vec<ResolvedTarget>,
+    ) {
+        let debounce = targets
+            .iter()
+            .map(|target| target.options.debounce)
+            .max()
+            .unwrap_or_default();
+
+        if !debounce.is_zero() {
+            self.pending.insert(key, Instant::now() + debounce);
+            return;
+        }
+
+        for target in &targets {
+            self.copy_target(target);
+        }
+    }
+
+    // Answer a command received on the control socket.
+    fn handle_control(&mut self, request: Request) {
+        match &request.command {
+            control::Command::List => {
+                let watches = self.list_watches();
+                control::respond(request, watches);
+            }
+
+            control::Command::Sync(source) => {
+                let targets = self.source_targets(source);
+                if targets.is_empty() {
+                    let error = format!("no target has the source {source:?}");
+                    control::respond(request, serde_json::json!({ "error": error }));
+                    return;
+                }
+
+                tracing::info!(source = %source.display(), targets = targets.len(),
+                               "Copying targets on request");
+                for target in &targets {
+                    self.copy_target(target);
+                }
+
+                control::respond(request, serde_json::json!({ "copying": targets.len() }));
+            }
+
+            control::Command::SyncAll => {
+                let copying = self.copy_all();
+                control::respond(request, serde_json::json!({ "copying": copying }));
+            }
+        }
+    }
+
+    // Describe each of the active watches, ordered by their watch descriptors, followed by the
+    // polled targets.
+    fn list_watches(&self) -> Vec<control::Watch> {
+        let describe = |target: &ResolvedTarget| control::WatchTarget {
+            source: target.source.clone(),
+            target: target.target.clone(),
+            events: EVENT_NAMES
+                .iter()
+                .filter(|(_, mask)| target.events.contains(*mask))
+                .map(|(name, _)| *name)
+                .collect(),
+        };
+
+        let files = self.targets.iter().map(|(wd, targets)| control::Watch {
+            wd: Some(wd.get_watch_descriptor_id()),
+            kind: "file",
+            path: targets[0].source.clone(),
+            targets: targets.iter().map(describe).collect(),
+        });
+
+        let directories = self
+            .directories
+            .iter()
+            .map(|(wd, directories)| control::Watch {
+                wd: Some(wd.get_watch_descriptor_id()),
+                kind: "directory",
+                path: directories[0].path.clone(),
+                targets: directories
+                    .iter()
+                    .map(|directory| describe(&directory.root))
+                    .collect(),
+            });
+
+        let missing = self.missing.iter().map(|(wd, targets)| control::Watch {
+            wd: Some(wd.get_watch_descriptor_id()),
+            kind: "missing",
+            path: targets[0]
+                .source
+                .parent()
+                .unwrap_or(Path::new("/"))
+                .to_path_buf(),
+            targets: targets.iter().map(describe).collect(),
+        });
+
+        let parents = self.parents.iter().map(|(wd, directory)| control::Watch {
+            wd: Some(wd.get_watch_descriptor_id()),
+            kind: "parent",
+            path: directory.clone(),
+            targets: self
+                .targets
+                .values()
+                .flatten()
+                .filter(|target| target.source.parent() == Some(directory.as_path()))
+                .map(describe)
+                .collect(),
+        });
+
+        let polled = self.polled.iter().map(|polled| control::Watch {
+            wd: None,
+            kind: "poll",
+            path: polled.target.source.clone(),
+            targets: vec![describe(&polled.target)],
+        });
+
+        let mut watches = files
+            .chain(directories)
+            .chain(missing)
+            .chain(parents)
+            .collect::<Vec<_>>();
+        watches.sort_by_key(|watch| watch.wd);
+        watches.extend(polled);
+        watches
+    }
+
+    // Find the targets with the given source, which may be a file within one of the directories
+    // watched for a recursive or glob target.
+    fn source_targets(&self, source: &Path) -> Vec<ResolvedTarget> {
+        let files = self
+            .targets
+            .values()
+            .flatten()
+            .chain(self.polled.iter().map(|polled| &polled.target))
+            .filter(|target| target.source == source)
+            .cloned();
+
+        let children = source
+            .parent()
+            .zip(source.file_name())
+            .map(|(parent, name)| {
+                self.directories
+                    .values()
+                    .flatten()
+                    .filter(|directory| directory.path == parent)
+                    .filter_map(|directory| directory.child(name))
+                    .collect::<Vec<_>>()
+            });
+
+        files
+            .chain(children.into_iter().flatten())
+            .filter(|target| target.is_copyable())
+            .collect()
+    }
+
+    // Copy every target again, including each of the files that are currently in the directories
+    // watched for recursive and glob targets. This is used when events may have been lost, and
+    // returns the number of targets that are being copied.
+    fn copy_all(&mut self) -> usize {
+        let targets = self.all_targets();
+        tracing::info!(targets = targets.len(), "Copying all targets");
+        for target in &targets {
+            self.copy_target(target);
+        }
+
+        targets.len()
+    }
+
+    // Copy each of the targets again whose contents differ from those of its source. Templated
+    // targets are left out, as each copy of them would be a new file. Targets on other backends
+    // cannot tell whether they are unchanged, so they are always copied.
+    fn resync_all(&mut self) {
+        let targets = self
+            .all_targets()
+            .into_iter()
+            .filter(|target| !template::is_templated(&target.target))
+            .map(|mut target| {
+                if target.options.backend.is_local() {
+                    target.options.skip_if_unchanged = true;
+                }
+
+                target
+            })
+            .collect::<Vec<_>>();
+
+        tracing::info!(targets = targets.len(), "Resyncing all targets");
+        for target in &targets {
+            self.copy_target(target);
+        }
+    }
+
+    // Schedule the next resync of the targets, if they are resynced.
+    fn schedule_resync(&mut self) {
+        self.next_resync = self
+            .resync
+            .map(|(interval, max_jitter)| Instant::now() + interval + jitter(max_jitter));
+    }
+
+    // Each of the targets that we are watching, including each of the files that are currently in
+    // the directories watched for recursive and glob targets.
+    fn all_targets(&self) -> Vec<ResolvedTarget> {
+        let mut targets = self
+            .targets
+            .values()
+            .flatten()
+            .chain(self.polled.iter().map(|polled| &polled.target))
+            .filter(|target| target.is_copyable())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for directory in self.directories.values().flatten() {
+            let entries = match std::fs::read_dir(&directory.path) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    tracing::warn!(directory = %directory.path.display(),
+                                   "Failed to read directory: {err:?}");
+                    continue;
+                }
+            };
+
+            targets.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| directory.child(&entry.file_name()))
+                    .filter(|target| target.is_copyable()),
+            );
+        }
+
+        targets
+    }
+
+    // Start copying the given target in the background. The copy waits for a permit from the
+    // copy limit before it begins. If the target is already being copied, then it is copied again
+    // once that copy completes, so that the latest changes to the source are not missed.
+    fn copy_target(&mut self, target: &ResolvedTarget) {
+        if self.dry_run {
+            target.span().in_scope(|| target.dry_run());
+            self.schedule_hook(target, &target.target);
+            return;
+        }
+
+        if let Some(after) = self.in_flight.get_mut(&target.target) {
+            *after = AfterCopy::CopyAgain;
+            return;
+        }
+
+        self.in_flight
+            .insert(target.target.clone(), AfterCopy::Nothing);
+        let limit = self.copy_limit.clone();
+        let target = target.clone();
+        let span = target.span();
+        self.copies.spawn(
+            async move {
+                let result = match limit.acquire_owned().await {
+                    Ok(_permit) => target.copy().await,
+                    Err(err) => Err(std::io::Error::other(err)),
+                };
+
+                (target, result)
+            }
+            .instrument(span),
+        );
+    }
+
+    // Handle the completion of a copy, keeping track of any directories that were created for it.
+    // A failed copy is logged and otherwise ignored, so that one bad file does not stop us from
+    // copying everything else, unless the target has failed too many times in a row.
+    fn finish_copy(
+        &mut self,
+        joined: Result<(ResolvedTarget, std::io::Result<CopyOutcome>), JoinError>,
+    ) -> std::io::Result<()> {
+        let (target, result) = joined.map_err(|err| {
+            tracing::error!("Copy task failed: {err:?}");
+            std::io::Error::other(err)
+        })?;
+
+        let _span = target.span().entered();
+
+        let after = self.in_flight.remove(&target.target);
+        if after == Some(AfterCopy::CopyAgain) {
+            self.copy_target(&target);
+        }
+
+        // The source was deleted while it was being copied, so whatever the copy left at the
+        // target is removed now that it has completed, and the outcome of the copy no longer
+        // matters.
+        if after == Some(AfterCopy::Remove) {
+            if let Ok(outcome) = result {
+                self.created_directories.extend(outcome.created);
+            }
+
+            self.failures.remove(&target.target);
+            return self.remove_target(&target);
+        }
+
+        let err = match result {
+            Ok(outcome) => {
+                self.failures.remove(&target.target);
+                self.retries.remove(&target.target);
+                self.created_directories.extend(outcome.created);
+                if let Some(bytes) = outcome.bytes {
+                    self.totals.copies += 1;
+                    self.totals.bytes += bytes;
+                }
+
+                if !self.awaiting.is_empty() {
+                    self.watch_awaiting(&target.target);
+                }
+
+                if outcome.bytes.is_some() {
+                    let path = outcome.target.as_ref().unwrap_or(&target.target);
+                    self.schedule_hook(&target, path);
+                }
+
+                if let (Some(keep), Some(path)) = (target.options.keep, outcome.target) {
+                    self.prune_history(&target, path, keep);
+                }
+
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        self.totals.failures += 1;
+        let (_, failures) = self
+            .failures
+            .entry(target.target.clone())
+            .or_insert_with(|| (target.options.copyset.clone(), 0));
+        *failures += 1;
+        if err.kind() == std::io::ErrorKind::ReadOnlyFilesystem {
+            tracing::error!(source = %target.source.display(), target = %target.target.display(), failures = *failures,
+                            "Target filesystem is read-only: {}; check how the volume is mounted",
+                            target.target.display());
+        } else {
+            tracing::error!(source = %target.source.display(), target = %target.target.display(), failures = *failures,
+                            "Failed to copy target: {err:?}");
+        }
+
+        if self
+            .max_failures
+            .is_some_and(|max_failures| *failures >= max_failures)
+        {
+            tracing::error!(
+                source = %target.source.display(),
+                target = %target.target.display(),
+                failures = *failures,
+                "Copying has failed too many consecutive times; giving up"
+            );
+
+            return Err(err);
+        }
+
+        match target.options.on_error {
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Retry => {
+                let delay = RETRY_DELAY
+                    .saturating_mul(2u32.saturating_pow(*failures - 1))
+                    .min(MAX_RETRY_DELAY);
+                tracing::info!(target = %target.target.display(), delay_ms = delay.as_millis() as u64,
+                               "Retrying copy");
+                self.retries.insert(
+                    target.target.clone(),
+                    PendingRetry {
+                        deadline: Instant::now() + delay,
+                        target: target.clone(),
+                    },
+                );
+            }
+            ErrorPolicy::Fatal => {
+                tracing::error!(target = %target.target.display(),
+                                "Copying target failed and its 'on_error' is 'fatal'; giving up");
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy again any of the targets whose retry delay has elapsed.
+    fn copy_retries(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .retries
+            .iter()
+            .filter(|(_, retry)| retry.deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        for path in due {
+            if let Some(retry) = self.retries.remove(&path) {
+                self.copy_target(&retry.target);
+            }
+        }
+    }
+
+    // Wait for all of the copies that are in progress (or waiting for a permit) to complete.
+    async fn finish_copies(&mut self) -> std::io::Result<()> {
+        while let Some(joined) = self.copies.join_next().await {
+            self.finish_copy(joined)?;
+        }
+
+        self.finish_startup();
+        Ok(())
+    }
+
+    // Publish the status of each copyset for the health check: how many targets it has, which of
+    // their sources do not exist yet, and which targets failed to copy. We are only ready once the
+    // initial copies have completed and every target is in sync with its source.
+    fn publish_status(&self) {
+        if !health::is_serving() {
+            return;
+        }
+
+        let mut copysets = BTreeMap::<String, health::CopysetStatus>::new();
+        let mut roots = Vec::<&ResolvedTarget>::new();
+        for directory in self.directories.values().flatten() {
+            if !roots.iter().any(|root| root.is_same_watch(&directory.root)) {
+                roots.push(&directory.root);
+            }
+        }
+
+        let watched = self
+            .targets
+            .values()
+            .flatten()
+            .chain(self.polled.iter().map(|polled| &polled.target))
+            .chain(roots);
+        for target in watched {
+            let status = copysets
+                .entry(target.options.copyset.to_string())
+                .or_default();
+            status.targets += 1;
+        }
+
+        for target in self.missing.values().flatten().chain(&self.awaiting) {
+            let status = copysets
+                .entry(target.options.copyset.to_string())
+                .or_default();
+            status.targets += 1;
+            status.missing.push(target.source.clone());
+        }
+
+        for (path, (copyset, _)) in &self.failures {
+            let status = copysets.entry(copyset.to_string()).or_default();
+            status.failing.push(path.clone());
+        }
+
+        let ready = self.startup.is_none()
+            && copysets
+                .values()
+                .all(|status| status.missing.is_empty() && status.failing.is_empty());
+        health::set_status(health::Status { ready, copysets });
+    }
+
+    // Log the total time taken by the initial copies, once there are no more copies in progress.
+    fn finish_startup(&mut self) {
+        if !self.copies.is_empty() {
+            return;
+        }
+
+        if let Some((started, copies)) = self.startup.take() {
+            tracing::info!(copies, elapsed = ?started.elapsed(), "Initial copies complete");
+        }
+    }
+
+    // Record a copy of a templated target, and remove the oldest copies that we made beyond the
+    // target's 'keep' limit. Only copies that we made are ever removed. This is called from the
+    // event loop when each copy completes, and copies of the same target never overlap, so a copy
+    // is never removed while another copy is being made.
+    fn prune_history(&mut self, target: &ResolvedTarget, path: PathBuf, keep: usize) {
+        let history = self.history.entry(target.target.clone()).or_default();
+        history.push_back(path);
+
+        while history.len() > keep {
+            let Some(old) = history.pop_front() else {
+                break;
+            };
+
+            copy_info!(target, target = %old.display(), keep, "Removing old copy");
+            let sidecar = target
+                .options
+                .checksum_sidecar
+                .map(|algorithm| algorithm.sidecar_path(&old));
+            for path in std::iter::once(&old).chain(sidecar.as_ref()) {
+                match std::fs::remove_file(path) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        tracing::warn!(target = %path.display(), "Failed to remove old copy: {err:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    // Schedule the hook of a target that has just been copied, if it has one. Any further copies
+    // that trigger the same hook within its debounce window push it back again, so that a burst
+    // of copies only runs the hook once.
+    fn schedule_hook(&mut self, target: &ResolvedTarget, path: &Path) {
+        let deadline = Instant::now() + target.options.hook_debounce;
+        if let Some(command) = &target.options.on_batch {
+            let batch = self
+                .batches
+                .entry(target.options.copyset.clone())
+                .or_insert_with(|| PendingBatch {
+                    command: command.clone(),
+                    deadline,
+                    targets: Vec::new(),
+                });
+
+            batch.deadline = deadline;
+            if !batch.targets.iter().any(|copied| copied == path) {
+                batch.targets.push(path.to_owned());
+            }
+        }
+
+        let Some(command) = &target.options.on_copy else {
+            return;
+        };
+
+        self.hooks.insert(
+            command.clone(),
+            PendingHook {
+                deadline,
+                source: target.source.clone(),
+                target: path.to_owned(),
+            },
+        );
+    }
+
+    // Run the hooks whose debounce window has elapsed (or all of them, when shutting down). While
+    // running, hooks are run in the background so that they do not hold up event processing.
+    async fn run_hooks(&mut self, all: bool) {
+        let now = Instant::now();
+        let due = self
+            .hooks
+            .iter()
+            .filter(|(_, hook)| all || hook.deadline <= now)
+            .map(|(command, _)| command.clone())
+            .collect::<Vec<_>>();
+
+        for command in due {
+            let Some(hook) = self.hooks.remove(&command) else {
+                continue;
+            };
+
+            if self.dry_run {
+                tracing::info!(command = ?command, "Dry run: would run hook");
+                continue;
+            }
+
+            let env = vec![
+                ("CC_SOURCE", hook.source.into_os_string()),
+                ("CC_TARGET", hook.target.into_os_string()),
+            ];
+            let run = run_hook(command, env, None);
+            if all {
+                run.await;
+            } else {
+                tokio::spawn(run);
+            }
+        }
+
+        let due = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| all || batch.deadline <= now)
+            .map(|(copyset, _)| copyset.clone())
+            .collect::<Vec<_>>();
+
+        for copyset in due {
+            let Some(batch) = self.batches.remove(&copyset) else {
+                continue;
+            };
+
+            if self.dry_run {
+                tracing::info!(command = ?batch.command, copyset = %copyset,
+                               "Dry run: would run batch hook");
+                continue;
+            }
+
+            // Each of the targets is given on its own line on the standard input of the command.
+            let mut input = Vec::new();
+            for target in &batch.targets {
+                input.extend_from_slice(target.as_os_str().as_bytes());
+                input.push(b'\n');
+            }
+
+            let env = vec![("CC_COPYSET", OsString::from(copyset.as_ref()))];
+            let run = run_hook(batch.command, env, Some(input));
+            if all {
+                run.await;
+            } else {
+                tokio::spawn(run);
+            }
+        }
+    }
+
+    // Make an initial copy of the source as it is first watched, according to the target's
+    // 'copy_on_start' option.
+    fn initial_copy(&mut self, target: &ResolvedTarget) {
+        if !target.is_copyable() {
+            return;
+        }
+
+        match target.options.copy_on_start {
+            CopyOnStart::Never => {}
+            CopyOnStart::Always => {
+                tracing::info!(source = %target.source.display(), "  Copying on start");
+                self.copy_target(target);
+            }
+            // We do not check whether the target exists for other backends (such as S3), so their
+            // sources are always copied.
+            CopyOnStart::IfMissing if !target.options.backend.is_local() => {
+                tracing::info!(source = %target.source.display(), "  Copying on start");
+                self.copy_target(target);
+            }
+            CopyOnStart::IfMissing => {
+                // Check if the target file exists.
+                let target_exists = target
+                    .target
+                    .try_exists()
+                    .map_err(|err| {
+                        tracing::error!("  Failed to check if target exists: {err:?}");
+                        err
+                    })
+                    .unwrap_or(false);
+
+                if !target_exists {
+                    tracing::info!(target = %target.target.display(), "  Target does not exist; copying");
+                    self.copy_target(target);
+                }
+            }
+        }
+
+        if let Some(hook) = INITIAL_COPY_HOOK.get() {
+            hook(&target.source, &target.target);
+        }
+    }
+
+    // Remove the target of a source that has been deleted, along with any of the directories that
+    // we created to hold it that are now empty.
+    fn remove_target(&mut self, target: &ResolvedTarget) -> std::io::Result<()> {
+        // A copy that is in progress would put the target back, so we wait for it to complete.
+        if let Some(after) = self.in_flight.get_mut(&target.target) {
+            tracing::debug!(target = %target.target.display(),
+                            "Removing target once the copy in progress has completed");
+            *after = AfterCopy::Remove;
+            return Ok(());
+        }
+
+        tracing::info!(source = %target.source.display(), target = %target.target.display(),
+                       "Source was deleted; removing target");
+
+        if self.dry_run {
+            tracing::info!(target = %target.target.display(), "Dry run: would remove target");
+            return Ok(());
+        }
+
+        let sidecar = target
+            .options
+            .checksum_sidecar
+            .map(|algorithm| target.sidecar_path(algorithm));
+        for path in std::iter::once(&target.target).chain(sidecar.as_ref()) {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+
+                // The target is left in place, as with a failed copy, unless its failures are
+                // fatal.
+                Err(err) if err.kind() == std::io::ErrorKind::ReadOnlyFilesystem => {
+                    tracing::error!(target = %path.display(),
+                                    "Target filesystem is read-only: {}; check how the volume is mounted",
+                                    path.display());
+                    if target.options.on_error == ErrorPolicy::Fatal {
+                        return Err(err);
+                    }
+
+                    return Ok(());
+                }
+
+                Err(err) => {
+                    tracing::error!(target = %path.display(), "Failed to remove target: {err:?}");
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut parent = target.target.parent();
+        while let Some(directory) = parent {
+            if !self.created_directories.contains(directory) {
+                break;
+            }
+
+            match std::fs::remove_dir(directory) {
+                Ok(()) => {
+                    tracing::info!(directory = %directory.display(), "Removed empty directory")
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) if err.kind() == std::io::ErrorKind::DirectoryNotEmpty => break,
+                Err(err) => {
+                    tracing::warn!(directory = %directory.display(), "Failed to remove directory: {err:?}");
+                    break;
+                }
+            }
+
+            self.created_directories.remove(directory);
+            parent = directory.parent();
+        }
+
+        Ok(())
+    }
+
+    // Check each of the polled sources that is due, copying those whose modification time or size
+    // has changed since they were last checked. A source that has disappeared is treated in the
+    // same way as a deleted file.
+    fn poll_sources(&mut self) -> std::io::Result<()> {
+        let now = Instant::now();
+        let mut changed = Vec::new();
+        for polled in self
+            .polled
+            .iter_mut()
+            .filter(|polled| polled.next_poll <= now)
+        {
+            polled.next_poll = now + polled.target.options.poll_interval.unwrap_or_default();
+            let mut state = source_state(&polled.target.source);
+            if state.is_none() {
+                if let Some(fallback) = polled.target.fallback().cloned() {
+                    tracing::info!(source = %polled.target.source.display(), fallback = %fallback.display(),
+                                   "Source no longer exists; falling back to another source");
+                    polled.target.source = fallback;
+                    state = source_state(&polled.target.source);
+                }
+            }
+
+            if state != polled.state {
+                polled.state = state;
+                changed.push((polled.target.clone(), state.is_some()));
+            }
+        }
+
+        for (target, exists) in changed {
+            tracing::debug!(source = %target.source.display(), exists, "Polled source has changed");
+            if exists {
+                self.copy_target(&target);
+            } else if target.options.mirror_delete {
+                self.remove_target(&target)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy any debounced targets whose debounce window has elapsed.
+    fn copy_pending(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in due {
+            self.pending.remove(&key);
+            for target in self.event_targets(&key.0, key.1.as_deref()) {
+                self.copy_target(&target);
+            }
+        }
+    }
+
+    // Make an initial copy of the target if needed, and then add a watch for its source.
+    async fn add_target(&mut self, target: ResolvedTarget) -> std::io::Result<()> {
+        tracing::info!(source = %target.source.display(), target = %target.target.display(), "Adding target");
+
+        if target.options.recursive {
+            if !target.source.is_dir() {
+                tracing::error!("  Source of recursive target is not a directory");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("source {:?} is not a directory", target.source),
+                ));
+            }
+
+            let source = target.source.clone();
+            return self.add_directory(&target, source).await;
+        }
+
+        if target.options.glob.is_some() {
+            return self.add_glob(&target).await;
+        }
+
+        if target.source.is_dir() {
+            tracing::error!("  Source is a directory");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "source {:?} is a directory; set 'directory' or 'recursive' on the target to \
+                     copy its contents",
+                    target.source
+                ),
+            ));
+        }
+
+        // Opening a named pipe blocks until something writes to it, and a device may never reach
+        // the end of its contents, so we refuse to copy anything but regular files.
+        if let Some(kind) =
+            special_file_kind(&target.source).filter(|_| !target.is_preserved_symlink())
+        {
+            tracing::error!("  Source is a {kind}");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "source {:?} is a {kind}, which cannot be copied",
+                    target.source
+                ),
+            ));
+        }
+
+        // The source is watched (or its state recorded for polling) before it is first copied, so
+        // that a change made while the copy is being made is not missed. Any events for the change
+        // wait for the event loop, which then copies the target again once this copy completes.
+        if target.options.poll_interval.is_some() {
+            self.polled.push(PolledTarget::new(target.clone()));
+            self.initial_copy(&target);
+            return Ok(());
+        }
+
+        if target.options.bidirectional.is_some() && !target.source.exists() {
+            tracing::info!(source = %target.source.display(),
+                           "  Source does not exist yet; waiting for it to be copied");
+            self.initial_copy(&target);
+            self.awaiting.push(target);
+            return Ok(());
+        }
+
+        if !target.source.exists() && !target.source.is_symlink() {
+            return self.watch_missing(target);
+        }
+
+        // A source that cannot be watched is still copied, even though the target is reported as
+        // having failed to be added.
+        let watched = self.watch_file(target.clone());
+        self.initial_copy(&target);
+        watched
+    }
+
+    // Add the source of a single file target to inotify. The descriptor that we get back is
+    // stored against our 'ResolvedTarget' in the environment.
+    fn watch_file(&mut self, target: ResolvedTarget) -> std::io::Result<()> {
+        let wd = self
+            .add_watch(&target.source, target.watch_mask())
+            .map_err(|err| {
+                tracing::error!(source = %target.source.display(), mask = ?target.watch_mask(),
+                                "  Failed to add watch: {err:?}");
+                err
+            })?;
+
+        // A file moved into the place of the source is only reported to the directory that it was
+        // moved into. Failing to watch the directory only means that we may be late to notice.
+        if let Some(directory) = target.source.parent() {
+            if !self.parents.values().any(|parent| parent == directory) {
+                match self.add_watch(directory, WatchMask::MOVED_TO | WatchMask::ONLYDIR) {
+                    Ok(wd) => {
+                        self.parents.insert(wd, directory.to_owned());
+                    }
+                    Err(err) => {
+                        tracing::warn!(directory = %directory.display(),
+                                       "  Failed to watch directory of source: {err:?}");
+                    }
+                }
+            }
+        }
+
+        self.targets.entry(wd).or_default().push(target);
+        Ok(())
+    }
+
+    // Stop watching the directories that no longer contain the source of any watched file.
+    fn prune_parents(&mut self) {
+        let unused = self
+            .parents
+            .iter()
+            .filter(|(_, directory)| {
+                !self
+                    .targets
+                    .values()
+                    .flatten()
+                    .any(|target| target.source.parent() == Some(directory.as_path()))
+            })
+            .map(|(wd, _)| wd.clone())
+            .collect::<Vec<_>>();
+
+        for wd in unused {
+            self.parents.remove(&wd);
+            self.release_watch(wd);
+        }
+    }
+
+    // Watch the sources of any targets that were waiting for the given path to be copied to.
+    fn watch_awaiting(&mut self, path: &Path) {
+        let (ready, awaiting) = std::mem::take(&mut self.awaiting)
+            .into_iter()
+            .partition::<Vec<_>, _>(|target| target.source == path);
+        self.awaiting = awaiting;
+
+        for target in ready {
+            tracing::info!(source = %target.source.display(), "Source now exists; watching it");
+            let source = target.source.clone();
+            if let Err(err) = self.watch_file(target) {
+                tracing::error!(source = %source.display(), "Failed to watch source: {err:?}");
+            }
+        }
+    }
+
+    // Watch a directory within the source of a recursive target, along with all of its
+    // subdirectories, and make an initial copy of any files in them that are missing from the
+    // target. Each directory is watched before it is read, so that we do not miss any files that
+    // are created while we are reading it.
+    async fn add_directory(&mut self, root: &ResolvedTarget, path: PathBuf) -> std::io::Result<()> {
+        let mut stack = vec![path];
+        while let Some(path) = stack.pop() {
+            let wd = self.watch_directory(root, &path)?;
+
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+
+                // We don't follow symbolic links to directories, to avoid watching the same
+                // directory more than once (or even forever).
+                if entry.file_type()?.is_dir() {
+                    if !root.options.exclude.is_excluded(&entry.path(), true) {
+                        stack.push(entry.path());
+                    }
+                } else if let Some(target) = self
+                    .watched_directory(&wd, root)
+                    .and_then(|directory| directory.child(&entry.file_name()))
+                {
+                    self.initial_copy(&target);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Watch each of the directories that could contain files matching the glob of a glob target,
+    // and make an initial copy of any matching files that are missing from the target. Note that
+    // wildcards in the directory part of the glob are only expanded here: directories that are
+    // created later are not watched.
+    async fn add_glob(&mut self, root: &ResolvedTarget) -> std::io::Result<()> {
+        let parent = root.source.parent().unwrap_or(Path::new("/"));
+        let directories = glob::glob_with(&parent.to_string_lossy(), glob_match_options())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?
+            .filter_map(Result::ok)
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>();
+
+        if directories.is_empty() {
+            tracing::warn!(pattern = %parent.display(), "  No directories match");
+        }
+
+        for path in directories {
+            let wd = self.watch_directory(root, &path)?;
+
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                if let Some(target) = self
+                    .watched_directory(&wd, root)
+                    .and_then(|directory| directory.child(&entry.file_name()))
+                {
+                    self.initial_copy(&target);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Find the watched directory with the given descriptor that belongs to the given target.
+    fn watched_directory(
+        &self,
+        wd: &WatchDescriptor,
+        root: &ResolvedTarget,
+    ) -> Option<&WatchedDirectory> {
+        self.directories
+            .get(wd)?
+            .iter()
+            .find(|directory| directory.root.is_same_watch(root))
+    }
+
+    // Add a watch for the given path. If the path is already being watched (on behalf of another
+    // target, or for the configuration file), then inotify gives us the same watch descriptor, so
+    // we add our events to those of the existing watch rather than replacing them.
+    fn add_watch(&mut self, path: &Path, mask: WatchMask) -> std::io::Result<WatchDescriptor> {
+        // The error from inotify does not say which path it was for, so we add that (and the
+        // mask) to the error, keeping its kind so that callers can still tell why it failed.
+        let wd = self
+            .watches
+            .add(path, mask | WatchMask::MASK_ADD)
+            .map_err(|err| {
+                if err.raw_os_error() == Some(nix::libc::ENOSPC) {
+                    return self.watch_limit_error(path);
+                }
+
+                std::io::Error::new(
+                    err.kind(),
+                    format!("failed to watch {path:?} with mask {mask:?}: {err}"),
+                )
+            })?;
+        if self.is_watched(&wd) {
+            tracing::info!(path = %path.display(), "  Path is already being watched; sharing the watch");
+        }
+
+        Ok(wd)
+    }
+
+    // The error for a watch that could not be added because we have as many watches as the kernel
+    // allows, which it reports as the rather cryptic 'No space left on device'.
+    fn watch_limit_error(&self, path: &Path) -> std::io::Error {
+        let watches = self.watch_count();
+        let limit = std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+            .map_or_else(|_| "unknown".to_string(), |limit| limit.trim().to_string());
+
+        std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            format!(
+                "failed to watch {path:?}: the limit on the number of inotify watches \
+                 (fs.inotify.max_user_watches = {limit}) has been reached, after adding {watches} \
+                 watch(es); raise it with 'sysctl -w fs.inotify.max_user_watches=<count>', and \
+                 note that the limit is shared by every process of the same user"
+            ),
+        )
+    }
+
+    // The number of distinct inotify watches that we have, as several targets (and directories)
+    // can share one watch.
+    fn watch_count(&self) -> usize {
+        self.targets
+            .keys()
+            .chain(self.directories.keys())
+            .chain(self.missing.keys())
+            .chain(self.parents.keys())
+            .chain(self.config_watch.iter().map(|watch| &watch.wd))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    // Whether any target, directory or the configuration file is using the given watch.
+    fn is_watched(&self, wd: &WatchDescriptor) -> bool {
+        self.targets.contains_key(wd)
+            || self.directories.contains_key(wd)
+            || self.missing.contains_key(wd)
+            || self.parents.contains_key(wd)
+            || self
+                .config_watch
+                .as_ref()
+                .is_some_and(|watch| watch.wd == *wd)
+    }
+
+    // Remove the given watch, unless it is still being used by something else.
+    fn release_watch(&mut self, wd: WatchDescriptor) {
+        if self.is_watched(&wd) {
+            return;
+        }
+
+        if let Err(err) = self.watches.remove(wd) {
+            tracing::warn!("Failed to remove watch: {err:?}");
+        }
+    }
+
+    // Add a watch for a directory on behalf of a recursive or glob target.
+    fn watch_directory(
+        &mut self,
+        root: &ResolvedTarget,
+        path: &Path,
+    ) -> std::io::Result<WatchDescriptor> {
+        tracing::info!(directory = %path.display(), "  Watching directory");
+
+        let wd = self.add_watch(path, root.directory_mask()).map_err(|err| {
+            tracing::error!(directory = %path.display(), mask = ?root.directory_mask(),
+                            "  Failed to add watch: {err:?}");
+            err
+        })?;
+
+        if self.watched_directory(&wd, root).is_none() {
+            self.directories
+                .entry(wd.clone())
+                .or_default()
+                .push(WatchedDirectory {
+                    root: root.clone(),
+                    path: path.to_owned(),
+                });
+        }
+
+        Ok(wd)
+    }
+
+    /// Watch the directory that contains the configuration file, so that we can reload it when it
+    /// changes. We watch the directory rather than the file itself, as the file may be replaced
+    /// rather than modified: a Kubernetes ConfigMap, for example, is updated by swapping a symlink.
+    /// When the configuration is a directory of files, we watch that directory.
+    pub fn watch_config(&mut self, config_path: &Path) -> std::io::Result<()> {
+        let directory = match config_path.parent() {
+            _ if config_path.is_dir() => config_path,
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        tracing::info!(directory = %directory.display(), "Watching configuration directory");
+        let mask = WatchMask::CREATE
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_TO
+            | WatchMask::DELETE
+            | WatchMask::ONLYDIR;
+        let wd = self.add_watch(directory, mask).map_err(|err| {
+            tracing::error!(directory = %directory.display(),
+                            "Failed to watch configuration directory: {err:?}");
+            err
+        })?;
+
+        self.config_watch = Some(ConfigWatch {
+            wd,
+            contents: config_contents(config_path)?,
+            deadline: None,
+        });
+
+        Ok(())
+    }
+
+    // Reload the configuration file if something has changed in its directory and its contents
+    // are not the same as when it was last loaded.
+    async fn reload_if_changed(&mut self, config_path: &Path) -> std::io::Result<()> {
+        let Some(watch) = &mut self.config_watch else {
+            return Ok(());
+        };
+
+        match watch.deadline {
+            Some(deadline) if deadline <= Instant::now() => watch.deadline = None,
+            _ => return Ok(()),
+        }
+
+        let contents = match config_contents(config_path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                tracing::warn!(config_path = %config_path.display(),
+                               "Failed to read configuration file: {err:?}");
+                return Ok(());
+            }
+        };
+
+        if contents == watch.contents {
+            tracing::debug!("Configuration file is unchanged");
+            return Ok(());
+        }
+
+        tracing::info!("Configuration file has changed");
+        watch.contents = contents;
+        self.reload(config_path).await
+    }
+
+    // Reload the configuration file and reconcile the watched targets with those in the new
+    // configuration. Targets whose source, target and events are unchanged keep their existing
+    // watch (taking on any new copy options); any other targets are removed or added.
+    async fn reload(&mut self, config_path: &Path) -> std::io::Result<()> {
+        tracing::info!(config_path = %config_path.display(), "Reloading configuration");
+        let mut config = match Config::load(config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!(
+                    "Failed to reload configuration; keeping current configuration: {err:?}"
+                );
+                return Ok(());
+            }
+        };
+
+        config.select(&self.filter);
+        let mut desired = Vec::new();
+        for copyset in &config.copysets {
+            match copyset.resolve() {
+                Ok(targets) => desired.extend(targets),
+                Err(err) => {
+                    tracing::error!(
+                        copyset = copyset.name,
+                        "Failed to resolve copyset; keeping current configuration: {err:?}"
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        self.max_failures = config.max_consecutive_failures;
+        if config.resync() != self.resync {
+            self.resync = config.resync();
+            self.schedule_resync();
+        }
+
+        if config.buffer_size.0 != self.buffer_size {
+            tracing::warn!(
+                buffer_size = config.buffer_size.0,
+                "Changing 'buffer_size' only takes effect when container-copier is restarted"
+            );
+        }
+
+        if config.max_concurrent_copies != self.copy_limit_size {
+            // Copies that are already waiting keep to the previous limit.
+            self.copy_limit = Arc::new(Semaphore::new(config.max_concurrent_copies));
+            self.copy_limit_size = config.max_concurrent_copies;
+        }
+
+        // Any targets that are waiting for their source to be copied are added again, if they are
+        // still wanted, along with the other new targets.
+        self.awaiting.clear();
+        for wd in std::mem::take(&mut self.missing).into_keys() {
+            self.release_watch(wd);
+        }
+
+        // Remove any of the targets that we're currently watching that are no longer wanted. Any
+        // target that is still wanted is removed from 'desired', leaving only the new targets.
+        let mut removed = 0;
+        let mut unchanged = 0;
+        let watched = self.targets.keys().cloned().collect::<Vec<_>>();
+        for wd in watched {
+            let Some(targets) = self.targets.get_mut(&wd) else {
+                continue;
+            };
+
+            targets.retain_mut(|existing| {
+                let position = desired
+                    .iter()
+                    .position(|target| existing.is_same_watch(target));
+
+                if let Some(position) = position {
+                    existing.options = desired.swap_remove(position).options;
+                    unchanged += 1;
+                    return true;
+                }
+
+                tracing::info!(source = %existing.source.display(),
+                               target = %existing.target.display(), "Removing target");
+                removed += 1;
+                false
+            });
+
+            // Once none of the targets of a watched file are wanted, we stop watching it.
+            if targets.is_empty() {
+                self.targets.remove(&wd);
+                self.pending.remove(&(wd.clone(), None));
+                self.release_watch(wd);
+            }
+        }
+
+        self.prune_parents();
+
+        self.polled.retain_mut(|polled| {
+            let position = desired
+                .iter()
+                .position(|target| polled.target.is_same_watch(target));
+
+            if let Some(position) = position {
+                polled.target.options = desired.swap_remove(position).options;
+                unchanged += 1;
+                return true;
+            }
+
+            tracing::info!(source = %polled.target.source.display(),
+                           target = %polled.target.target.display(), "Removing target");
+            removed += 1;
+            false
+        });
+
+        // Recursive and glob targets can have any number of watched directories, all sharing the
+        // same root target, so we reconcile those by their root.
+        let mut roots = Vec::<ResolvedTarget>::new();
+        for directory in self.directories.values().flatten() {
+            if !roots.iter().any(|root| root.is_same_watch(&directory.root)) {
+                roots.push(directory.root.clone());
+            }
+        }
+
+        for root in roots {
+            let position = desired.iter().position(|target| root.is_same_watch(target));
+            if let Some(position) = position {
+                let target = desired.swap_remove(position);
+                for directory in self.directories.values_mut().flatten() {
+                    if directory.root.is_same_watch(&root) {
+                        directory.root.options = target.options.clone();
+                    }
+                }
+
+                unchanged += 1;
+                continue;
+            }
+
+            tracing::info!(source = %root.source.display(), "Removing target");
+            let watched = self.directories.keys().cloned().collect::<Vec<_>>();
+            for wd in watched {
+                let Some(directories) = self.directories.get_mut(&wd) else {
+                    continue;
+                };
+
+                directories.retain(|directory| !directory.root.is_same_watch(&root));
+                if directories.is_empty() {
+                    self.directories.remove(&wd);
+                    self.pending.retain(|(pending, _), _| *pending != wd);
+                    self.release_watch(wd);
+                }
+            }
+
+            removed += 1;
+        }
+
+        // Add the targets that are new in this configuration. A failure to add one target should
+        // not prevent the others from being added.
+        let mut added = 0;
+        let mut failed = 0;
+        for target in desired {
+            let source = target.source.clone();
+            match self.add_target(target).await {
+                Ok(()) => added += 1,
+                Err(err) => {
+                    tracing::error!(source = %source.display(), "Failed to add target: {err:?}");
+                    failed += 1;
+                }
+            }
+        }
+
+        // Any copies that are waiting to be retried use the options of their target from the new
+        // configuration, unless it has been removed.
+        if !self.retries.is_empty() {
+            let mut current = self
+                .all_targets()
+                .into_iter()
+                .map(|target| (target.target.clone(), target))
+                .collect::<HashMap<_, _>>();
+            self.retries
+                .retain(|path, retry| match current.remove(path) {
+                    Some(target) => {
+                        retry.target = target;
+                        true
+                    }
+                    None => false,
+                });
+        }
+
+        tracing::info!(added, removed, unchanged, failed, "Reloaded configuration");
+
+        Ok(())
+    }
+
+    // Add a new watch for a target whose previous watch was removed by the kernel, and copy the
+    // file that is now at the source path.
+    async fn rearm(&mut self, mut target: ResolvedTarget) -> std::io::Result<()> {
+        if !target.source.exists() {
+            if let Some(fallback) = target.fallback().cloned() {
+                tracing::info!(source = %target.source.display(), fallback = %fallback.display(),
+                               "Source no longer exists; falling back to another source");
+                target.source = fallback;
+            }
+        }
+
+        let wd = match self.add_watch(&target.source, target.watch_mask()) {
+            Ok(wd) => wd,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && target.options.bidirectional.is_some() =>
+            {
+                tracing::info!(source = %target.source.display(),
+                               "Source no longer exists; waiting for it to be copied");
+                self.awaiting.push(target);
+                return Ok(());
+            }
+            // As with a source that is missing at startup, we watch its directory until it is
+            // created again. Only if the directory has gone as well do we give up on it.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if target.options.mirror_delete {
+                    self.remove_target(&target)?;
+                }
+
+                let source = target.source.clone();
+                return match self.watch_missing(target) {
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        tracing::warn!(
+                            source = %source.display(),
+                            "Source no longer exists; it will no longer be watched"
+                        );
+                        Ok(())
+                    }
+                    result => result,
+                };
+            }
+            Err(err) => {
+                tracing::error!(source = %target.source.display(), mask = ?target.watch_mask(),
+                                "Failed to re-add watch: {err:?}");
+                return Err(err);
+            }
+        };
+
+        tracing::info!(source = %target.source.display(), "Re-armed watch");
+        self.copy_target(&target);
+
+        self.targets.entry(wd).or_default().push(target);
+        Ok(())
+    }
+}
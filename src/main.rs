@@ -1,17 +1,13 @@
-use std::{
-    collections::HashMap,
-    io::Read,
-    path::{Path, PathBuf},
-};
+use std::path::PathBuf;
 
 use clap::Parser;
-use futures_util::StreamExt;
-use inotify::{Inotify, WatchDescriptor, WatchMask};
-use serde::Deserialize;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use container_copier::{otel, Config, ConfigFormat, CopysetFilter, RunOptions};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, about, long_about = None)]
 struct Args {
     /// Enable logging ('-v' for debug logging, '-vv' for tracing).
     #[arg(short = 'v', long, action = clap::ArgAction::Count, global = true)]
@@ -24,261 +20,63 @@ struct Args {
     /// Path to configuration file.
     #[arg(long, default_value = "/config/container-copier.toml")]
     config: PathBuf,
-}
 
-#[derive(Debug, Copy, Clone, Deserialize)]
-enum NotifyEvent {
-    #[serde(alias = "ACCESS")]
-    Access,
-    #[serde(alias = "ATTRIB")]
-    Attrib,
-    #[serde(alias = "CLOSE_WRITE")]
-    CloseWrite,
-    #[serde(alias = "CLOSE_NOWRITE")]
-    CloseNoWrite,
-    #[serde(alias = "CREATE")]
-    Create,
-    #[serde(alias = "DELETE")]
-    Delete,
-    #[serde(alias = "DELETE_SELF")]
-    DeleteSelf,
-    #[serde(alias = "MODIFY")]
-    Modify,
-    #[serde(alias = "MOVE_SELF")]
-    MoveSelf,
-    #[serde(alias = "MOVED_FROM")]
-    MovedFrom,
-    #[serde(alias = "MOVED_TO")]
-    MovedTo,
-    #[serde(alias = "OPEN")]
-    Open,
-    #[serde(alias = "ALL")]
-    All,
-    #[serde(alias = "MOVE")]
-    Move,
-    #[serde(alias = "CLOSE")]
-    Close,
-    #[serde(alias = "DONT_FOLLOW")]
-    DontFollow,
-    #[serde(alias = "EXCL_UNLINK")]
-    ExclUnlink,
-    #[serde(alias = "ONESHOT")]
-    Oneshot,
-}
+    /// Path to a directory of configuration files, which are loaded in order of their names.
+    #[arg(long, value_name = "DIR", conflicts_with = "config")]
+    config_dir: Option<PathBuf>,
 
-impl From<NotifyEvent> for WatchMask {
-    fn from(value: NotifyEvent) -> Self {
-        match value {
-            NotifyEvent::Access => WatchMask::ACCESS,
-            NotifyEvent::Attrib => WatchMask::ATTRIB,
-            NotifyEvent::CloseWrite => WatchMask::CLOSE_WRITE,
-            NotifyEvent::CloseNoWrite => WatchMask::CLOSE_NOWRITE,
-            NotifyEvent::Create => WatchMask::CREATE,
-            NotifyEvent::Delete => WatchMask::DELETE,
-            NotifyEvent::DeleteSelf => WatchMask::DELETE_SELF,
-            NotifyEvent::Modify => WatchMask::MODIFY,
-            NotifyEvent::MoveSelf => WatchMask::MOVE_SELF,
-            NotifyEvent::MovedFrom => WatchMask::MOVED_FROM,
-            NotifyEvent::MovedTo => WatchMask::MOVED_TO,
-            NotifyEvent::Open => WatchMask::OPEN,
-            NotifyEvent::All => WatchMask::ALL_EVENTS,
-            NotifyEvent::Move => WatchMask::MOVE,
-            NotifyEvent::Close => WatchMask::CLOSE,
-            NotifyEvent::DontFollow => WatchMask::DONT_FOLLOW,
-            NotifyEvent::ExclUnlink => WatchMask::EXCL_UNLINK,
-            NotifyEvent::Oneshot => WatchMask::ONESHOT,
-        }
-    }
-}
+    /// Log the copies that would be made, without touching the filesystem.
+    #[arg(long)]
+    dry_run: bool,
 
-#[derive(Deserialize)]
-struct Config {
-    copysets: Vec<Copyset>,
-}
-
-impl Config {
-    fn default_events() -> Vec<NotifyEvent> {
-        vec![
-            NotifyEvent::Create,
-            NotifyEvent::Delete,
-            NotifyEvent::Modify,
-        ]
-    }
+    /// Check the configuration for problems and exit, without watching anything.
+    #[arg(long)]
+    check: bool,
 
-    // Load TOML config from the given path.
-    fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let path = path.as_ref();
-        let mut file = std::fs::File::open(path)?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
-        toml::from_str(&buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-    }
-
-    async fn setup(&self) -> std::io::Result<Env> {
-        tracing::info!("Creating inotify");
-        let inotify = Inotify::init()?;
-        let mut env = Env {
-            notify: inotify,
-            targets: HashMap::new(),
-        };
+    /// Watch the configuration file, and reload it whenever it changes.
+    #[arg(long)]
+    watch_config: bool,
 
-        tracing::info!("Setting up inotify watches");
-        for copyset in &self.copysets {
-            copyset.add_to_watch(&mut env).await.map_err(|err| {
-                tracing::error!(
-                    "Failed to add copyset {:?} to inotify: {err:?}",
-                    copyset.name
-                );
-                err
-            })?;
-        }
+    /// Copy every source to its target once and then exit, without watching for changes.
+    #[arg(long, conflicts_with = "watch_config")]
+    once: bool,
 
-        Ok(env)
-    }
-}
+    /// Only run the copyset with this name (can be given more than once).
+    #[arg(long, value_name = "NAME")]
+    only: Vec<String>,
 
-#[derive(Deserialize)]
-struct Copyset {
-    name: String,
-    #[serde(default = "Config::default_events")]
-    events: Vec<NotifyEvent>,
-    source: PathBuf,
-    target: PathBuf,
-    targets: Vec<Target>,
-}
+    /// Skip the copyset with this name (can be given more than once).
+    #[arg(long = "exclude", value_name = "NAME")]
+    exclude: Vec<String>,
 
-impl Copyset {
-    async fn add_to_watch(&self, env: &mut Env) -> std::io::Result<()> {
-        tracing::info!(source = ?self.source, target = ?self.target,
-                       "Adding watch for copyset {:?}", self.name);
-
-        for target_spec in &self.targets {
-            tracing::info!(target_spec = ?target_spec,
-                           "Adding target: {:?}", target_spec.source);
-
-            // Append the target's paths (source and target) to the corresponding paths in the
-            // copyset. Note that if the 'target' path is not specified, we assume the value of the
-            // 'source' path.
-            let source = self.source.join(&target_spec.source);
-            let target = self
-                .target
-                .join(target_spec.target.as_ref().unwrap_or(&target_spec.source));
-
-            // Check if the target file exists.
-            let target_exists = target
-                .try_exists()
-                .map_err(|err| {
-                    tracing::error!("  Failed to check if target exists: {err:?}");
-                    err
-                })
-                .unwrap_or(false);
-
-            // Create the 'ResolvedTarget' and then check if we need to make an initial copy of the
-            // file.
-            let target = ResolvedTarget::new(source.clone(), target);
-            if source.is_file() && !target_exists {
-                tracing::info!("  Target does not exist; copying");
-                target.copy().await.map_err(|err| {
-                    tracing::error!("  Failed to copy: {err:?}");
-                    err
-                })?;
-            }
-
-            // If the target specifies it's own events then we want to use those events instead of
-            // those of the copyset; otherwise the events are those of the copyset.
-            let events: WatchMask = if let Some(events) = &target_spec.events {
-                events.iter()
-            } else {
-                self.events.iter()
-            }
-            .copied()
-            .map(WatchMask::from)
-            .collect();
-
-            // Add the source with the computed evens to inotify. The descriptor that we get back is
-            // stored against our 'ResolvedTarget' in the environment.
-            let wd = env.notify.watches().add(&source, events).map_err(|err| {
-                tracing::error!("  Failed to add watch: {err:?}");
-                err
-            })?;
-
-            env.targets.insert(wd, target);
-        }
+    /// The format of the log output.
+    #[arg(long, value_enum, env = "LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 
-        Ok(())
-    }
-}
+    /// Print the effective configuration, with all of the defaults filled in, and exit.
+    #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1,
+          default_missing_value = "toml")]
+    print_config: Option<ConfigFormat>,
 
-#[derive(Debug, Deserialize)]
-struct Target {
-    events: Option<Vec<NotifyEvent>>,
-    source: PathBuf,
-    target: Option<PathBuf>,
+    /// The flavor of the async runtime.
+    #[arg(long, value_enum, default_value_t = RuntimeFlavor::MultiThread)]
+    runtime: RuntimeFlavor,
 }
 
-struct Env {
-    notify: Inotify,
-    targets: HashMap<WatchDescriptor, ResolvedTarget>,
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum RuntimeFlavor {
+    /// Run all of the tasks on the main thread, which uses the least memory.
+    CurrentThread,
+    /// Run the tasks on a pool of worker threads, one for each CPU.
+    MultiThread,
 }
 
-struct ResolvedTarget {
-    source: PathBuf,
-    target: PathBuf,
-}
-
-impl ResolvedTarget {
-    fn new(source: PathBuf, target: PathBuf) -> Self {
-        Self { source, target }
-    }
-
-    async fn copy(&self) -> std::io::Result<()> {
-        tracing::info!("Copying {:?} to {:?}", self.source, self.target);
-
-        // Make sure that the parent directory of the target exists. If it does not exist, then
-        // create it.
-        let parent = self.target.parent().unwrap();
-        if !parent.exists() {
-            tracing::info!("Creating parent directory {:?}", parent);
-            std::fs::create_dir_all(parent).map_err(|err| {
-                tracing::error!(parent = ?parent, "Failed to create directory: {err:?}");
-                err
-            })?;
-        }
-
-        // Copy the source to the target.
-        std::fs::copy(&self.source, &self.target).map_err(|err| {
-            tracing::error!(source = ?self.source, target = ?self.target,
-                          "Failed to copy from source to target: {err:?}");
-            err
-        })?;
-
-        Ok(())
-    }
-}
-
-impl Env {
-    async fn run(self) -> std::io::Result<()> {
-        let Env { notify, targets } = self;
-
-        let mut buffer = [0; 1024];
-        let mut stream = notify.into_event_stream(&mut buffer)?;
-
-        tracing::info!("Processing inotify events");
-        while let Some(event_or_error) = stream.next().await {
-            let event = event_or_error?;
-            if let Some(target) = targets.get(&event.wd) {
-                target.copy().await.map_err(|err| {
-                    tracing::error!("Failed to copy target: {err:?}");
-                    err
-                })?;
-            } else {
-                tracing::warn!("Unknown watch descriptor {:?}", event.wd);
-            }
-        }
-
-        tracing::info!("Inotify stream ended");
-        Ok(())
-    }
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Human readable text.
+    Text,
+    /// One JSON object per line, with each field as a separate key.
+    Json,
 }
 
 fn print_version() {
@@ -291,8 +89,7 @@ fn print_version() {
     );
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
     if args.version {
@@ -300,24 +97,114 @@ async fn main() -> std::io::Result<()> {
         return Ok(());
     }
 
+    // A directory of configuration files is loaded in the same way as a single file.
+    let config_path = args.config_dir.as_ref().unwrap_or(&args.config);
+    let otel = Config::load_otel(config_path);
     {
-        let fmt = tracing_subscriber::fmt::layer()
-            .with_target(false)
-            .without_time();
-        let sub = tracing_subscriber::registry()
-            .with(tracing_subscriber::EnvFilter::new(match args.verbose {
+        // The effective configuration is printed to stdout so that it can be redirected to a
+        // file, so the log goes to stderr instead.
+        let to_stderr = args.print_config.is_some();
+        let writer = || match to_stderr {
+            true => BoxMakeWriter::new(std::io::stderr),
+            false => BoxMakeWriter::new(std::io::stdout),
+        };
+
+        // The level only applies to the log, as the spans are exported to OpenTelemetry whatever
+        // the level.
+        let filter = || {
+            tracing_subscriber::EnvFilter::new(match args.verbose {
                 0 => std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
                 1 => "debug".into(),
                 _ => "trace".into(),
-            }))
-            .with(fmt);
+            })
+        };
+
+        let text = (args.log_format == LogFormat::Text).then(|| {
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .without_time()
+                .with_writer(writer())
+                .with_filter(filter())
+        });
+        let json = (args.log_format == LogFormat::Json).then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_target(false)
+                .with_writer(writer())
+                .with_filter(filter())
+        });
+        let sub = tracing_subscriber::registry()
+            .with(otel.as_ref().map(otel::layer).transpose()?)
+            .with(text)
+            .with(json);
         sub.init();
     }
 
-    tracing::info!(config_path = ?args.config, "Loading configuration");
-    let config = Config::load(&args.config)?;
+    // The version is always logged, so that it is in any log that is shared with us.
+    tracing::info!(
+        version = env!("CARGO_PKG_VERSION"),
+        build = env!("CARGO_BUILD_INFO"),
+        "Starting container-copier"
+    );
+
+    if let Some(otel) = &otel {
+        otel::started(otel);
+    }
+
+    tracing::info!(config_path = %config_path.display(), "Loading configuration");
+    let mut config = Config::load(config_path)?;
+    let filter = CopysetFilter {
+        only: args.only.clone(),
+        exclude: args.exclude.clone(),
+    };
+    config.select(&filter);
+    config.log_summary();
+
+    if let Some(format) = args.print_config {
+        println!("{}", config.effective(format)?);
+        if !args.check {
+            return Ok(());
+        }
+    }
 
-    config.setup().await?.run().await?;
+    if args.check {
+        let problems = config.check();
+        for problem in &problems {
+            println!("{problem}");
+        }
+
+        if !problems.is_empty() {
+            println!(
+                "Found {} problem(s) in configuration {:?}",
+                problems.len(),
+                config_path
+            );
+            std::process::exit(1);
+        }
+
+        println!("Configuration {config_path:?} is OK");
+        return Ok(());
+    }
 
-    Ok(())
+    // Copies are made on the blocking thread pool either way, so the event loop only needs more than
+    // the one thread when there is a lot of work to do besides copying.
+    let mut runtime = match args.runtime {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+    };
+
+    let options = RunOptions {
+        dry_run: args.dry_run,
+        once: args.once,
+        watch_config: args.watch_config,
+        filter,
+    };
+    let result = runtime
+        .enable_all()
+        .build()?
+        .block_on(config.run(config_path, options));
+
+    otel::shutdown();
+    result
 }
@@ -0,0 +1,149 @@
+//! Prometheus metrics, which are served over HTTP when the `metrics` feature is enabled and a
+//! `[metrics]` section is given in the configuration. Without the feature, recording a metric
+//! does nothing.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/// The `[metrics]` section of the configuration.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MetricsConfig {
+    /// The address on which to serve the metrics, such as `0.0.0.0:9090`.
+    pub listen: SocketAddr,
+}
+
+#[cfg(feature = "metrics")]
+pub use enabled::*;
+
+#[cfg(not(feature = "metrics"))]
+pub use disabled::*;
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use std::{sync::OnceLock, time::Duration};
+
+    use http_body_util::Full;
+    use hyper::{
+        body::{Bytes, Incoming},
+        Request, Response, StatusCode,
+    };
+    use prometheus_client::{
+        encoding::text::encode,
+        metrics::{
+            counter::Counter,
+            gauge::Gauge,
+            histogram::{exponential_buckets, Histogram},
+        },
+        registry::Registry,
+    };
+
+    use super::MetricsConfig;
+    use crate::http;
+
+    struct Metrics {
+        registry: Registry,
+        copies: Counter,
+        failures: Counter,
+        bytes: Counter,
+        duration: Histogram,
+        watches: Gauge,
+    }
+
+    impl Metrics {
+        fn new() -> Self {
+            let mut registry = Registry::with_prefix("container_copier");
+            let copies = Counter::default();
+            let failures = Counter::default();
+            let bytes = Counter::default();
+            let duration = Histogram::new(exponential_buckets(0.001, 2.0, 16));
+            let watches = Gauge::default();
+
+            registry.register("copies", "Number of files copied", copies.clone());
+            registry.register("copy_failures", "Number of failed copies", failures.clone());
+            registry.register("copied_bytes", "Number of bytes copied", bytes.clone());
+            registry.register(
+                "copy_duration_seconds",
+                "Time taken to copy a file",
+                duration.clone(),
+            );
+            registry.register("watches", "Number of active watches", watches.clone());
+
+            Self {
+                registry,
+                copies,
+                failures,
+                bytes,
+                duration,
+                watches,
+            }
+        }
+    }
+
+    fn metrics() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(Metrics::new)
+    }
+
+    /// Record a successful copy of the given number of bytes.
+    pub fn copy_succeeded(bytes: u64, duration: Duration) {
+        let metrics = metrics();
+        metrics.copies.inc();
+        metrics.bytes.inc_by(bytes);
+        metrics.duration.observe(duration.as_secs_f64());
+    }
+
+    /// Record a failed copy.
+    pub fn copy_failed() {
+        metrics().failures.inc();
+    }
+
+    /// Record the number of watches that are currently active.
+    pub fn set_watches(watches: usize) {
+        metrics().watches.set(watches as i64);
+    }
+
+    /// Start serving the metrics at the configured address. The listener is bound before this
+    /// returns, so that a bad address is reported straight away; connections are then handled in
+    /// the background.
+    pub async fn serve(config: &MetricsConfig) -> std::io::Result<()> {
+        let listener = http::bind("metrics", config.listen).await?;
+        tokio::spawn(http::serve(listener, handle));
+        Ok(())
+    }
+
+    fn handle(_: &Request<Incoming>) -> Response<Full<Bytes>> {
+        let mut body = String::new();
+        if let Err(err) = encode(&mut body, &metrics().registry) {
+            tracing::error!("Failed to encode metrics: {err:?}");
+        }
+
+        http::response(
+            StatusCode::OK,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            body,
+        )
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use std::time::Duration;
+
+    use super::MetricsConfig;
+
+    pub fn copy_succeeded(_bytes: u64, _duration: Duration) {}
+
+    pub fn copy_failed() {}
+
+    pub fn set_watches(_watches: usize) {}
+
+    pub async fn serve(config: &MetricsConfig) -> std::io::Result<()> {
+        tracing::warn!(
+            listen = ?config.listen,
+            "Metrics are configured, but this build does not include the 'metrics' feature"
+        );
+
+        Ok(())
+    }
+}
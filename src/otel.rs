@@ -0,0 +1,117 @@
+//! Exporting the spans of copies to an OpenTelemetry collector over OTLP, when the `otel` feature
+//! is enabled and an `[otel]` section is given in the configuration. The spans are sent over HTTP
+//! in batches from a background thread, so that a slow collector never holds up a copy.
+
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{Layer, Registry};
+
+/// The `[otel]` section of the configuration.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OtelConfig {
+    /// The OTLP/HTTP endpoint to which spans are sent, such as `http://collector:4318/v1/traces`.
+    pub endpoint: String,
+    /// The name of the service that the spans are reported as coming from.
+    #[serde(default = "OtelConfig::default_service_name")]
+    pub service_name: String,
+}
+
+impl OtelConfig {
+    fn default_service_name() -> String {
+        "container-copier".to_string()
+    }
+}
+
+/// The layer added to the subscriber, which is boxed so that it has the same type whether or not
+/// it is enabled.
+pub type OtelLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[cfg(feature = "otel")]
+pub use enabled::*;
+
+#[cfg(not(feature = "otel"))]
+pub use disabled::*;
+
+#[cfg(feature = "otel")]
+mod enabled {
+    use std::sync::OnceLock;
+
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use tracing_subscriber::{
+        filter::{LevelFilter, Targets},
+        Layer,
+    };
+
+    use super::{OtelConfig, OtelLayer};
+
+    // The provider that exports the spans, which is kept so that it can be flushed when we exit.
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    /// Build the layer that exports spans to the configured collector. The spans of copies are
+    /// recorded at the 'debug' level, so that they do not clutter the log, and are exported
+    /// regardless of the level of the log.
+    pub fn layer(config: &OtelConfig) -> std::io::Result<OtelLayer> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.endpoint)
+            .build()
+            .map_err(std::io::Error::other)?;
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build();
+
+        let tracer = provider.tracer("container-copier");
+        let _ = PROVIDER.set(provider);
+
+        let filter = Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::DEBUG);
+        Ok(tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter)
+            .boxed())
+    }
+
+    /// Log where the spans are being exported to, once the log has been set up.
+    pub fn started(config: &OtelConfig) {
+        tracing::info!(
+            endpoint = config.endpoint,
+            service_name = config.service_name,
+            "Exporting spans to OpenTelemetry collector"
+        );
+    }
+
+    /// Export any spans that have not been sent yet, before we exit.
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            if let Err(err) = provider.shutdown() {
+                tracing::warn!("Failed to export remaining spans: {err:?}");
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod disabled {
+    use tracing_subscriber::{layer::Identity, Layer};
+
+    use super::{OtelConfig, OtelLayer};
+
+    pub fn layer(_config: &OtelConfig) -> std::io::Result<OtelLayer> {
+        Ok(Identity::new().boxed())
+    }
+
+    pub fn started(config: &OtelConfig) {
+        tracing::warn!(
+            endpoint = config.endpoint,
+            "OpenTelemetry is configured, but this build does not include the 'otel' feature"
+        );
+    }
+
+    pub fn shutdown() {}
+}
@@ -0,0 +1,109 @@
+//! Uploading targets to S3, for targets with an `s3://bucket/key` path. Uploads are only possible
+//! when the `s3` feature is enabled. The credentials and region are found in the same way as the
+//! AWS CLI, such as from the `AWS_ACCESS_KEY_ID` and `AWS_REGION` environment variables.
+
+#[cfg(feature = "s3")]
+pub use enabled::*;
+
+#[cfg(not(feature = "s3"))]
+pub use disabled::*;
+
+#[cfg(feature = "s3")]
+mod enabled {
+    use std::sync::Arc;
+
+    use aws_sdk_s3::{primitives::ByteStream, Client};
+    use futures_util::future::BoxFuture;
+    use tokio::sync::OnceCell;
+
+    use crate::{backend::Backend, CopyOutcome, ResolvedTarget};
+
+    // The client is created when it is first needed, and then shared by all of the targets.
+    static CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+    async fn client() -> &'static Client {
+        CLIENT
+            .get_or_init(|| async {
+                let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+                Client::new(&config)
+            })
+            .await
+    }
+
+    // Split an 's3://bucket/key' URL into its bucket and key.
+    fn parse(url: &str) -> std::io::Result<(&str, &str)> {
+        url.strip_prefix("s3://")
+            .and_then(|path| path.split_once('/'))
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid S3 target {url:?}: expected 's3://bucket/key'"),
+                )
+            })
+    }
+
+    /// Uploads targets to S3 objects.
+    #[derive(Debug)]
+    pub struct S3;
+
+    impl Backend for S3 {
+        fn copy<'a>(
+            &'a self,
+            target: &'a ResolvedTarget,
+        ) -> BoxFuture<'a, std::io::Result<CopyOutcome>> {
+            Box::pin(async move {
+                let url = target.target.to_string_lossy();
+                let (bucket, key) = parse(&url)?;
+                if target.is_too_large()? {
+                    return Ok(CopyOutcome::default());
+                }
+
+                tracing::info!(source = %target.source.display(), bucket, key, "Uploading");
+                let body = ByteStream::from_path(&target.source).await.map_err(|err| {
+                    tracing::error!(source = %target.source.display(), "Failed to read source: {err:?}");
+                    std::io::Error::other(err)
+                })?;
+
+                let bytes = body.size_hint().0;
+                client()
+                    .await
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|err| {
+                        tracing::error!(bucket, key, "Failed to upload to S3: {err:?}");
+                        std::io::Error::other(err)
+                    })?;
+
+                Ok(CopyOutcome {
+                    bytes: Some(bytes),
+                    ..CopyOutcome::default()
+                })
+            })
+        }
+    }
+
+    /// The backend for a target with an 's3://' path.
+    pub fn backend(url: &str) -> std::io::Result<Arc<dyn Backend>> {
+        parse(url)?;
+        Ok(Arc::new(S3))
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+mod disabled {
+    use std::sync::Arc;
+
+    use crate::backend::Backend;
+
+    pub fn backend(url: &str) -> std::io::Result<Arc<dyn Backend>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("cannot copy to {url:?}: S3 targets require the 's3' feature"),
+        ))
+    }
+}
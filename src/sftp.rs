@@ -0,0 +1,455 @@
+//! Uploading targets to a remote host over SFTP, for targets with an `sftp://user@host/path` path.
+//! Uploads are only possible when the `sftp` feature is enabled. One connection is kept open to
+//! each host, and shared by all of the targets that are uploaded to it.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// The `sftp` options of a copyset, which control how we connect to the hosts of its targets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SftpOptions {
+    /// The private key used to authenticate. When this is not given, the first of the usual keys
+    /// in `~/.ssh` that exists is used.
+    pub identity: Option<PathBuf>,
+    /// The known hosts file, defaulting to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+    /// How to check the key of the host against the known hosts file.
+    pub host_key_checking: HostKeyChecking,
+    /// The number of times to try to connect to a host before giving up on a copy.
+    pub connect_attempts: u32,
+    /// How long to wait between attempts to connect.
+    pub retry_delay_ms: u64,
+}
+
+impl Default for SftpOptions {
+    fn default() -> Self {
+        Self {
+            identity: None,
+            known_hosts: None,
+            host_key_checking: HostKeyChecking::default(),
+            connect_attempts: 3,
+            retry_delay_ms: 1000,
+        }
+    }
+}
+
+/// How to check the key of a host that we connect to.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyChecking {
+    /// Only connect to hosts whose key is in the known hosts file.
+    #[default]
+    Strict,
+    /// Add the key of a host that is not in the known hosts file, but refuse to connect to a host
+    /// whose key has changed.
+    AcceptNew,
+    /// Connect to any host without checking its key.
+    Off,
+}
+
+#[cfg(feature = "sftp")]
+pub use enabled::*;
+
+#[cfg(not(feature = "sftp"))]
+pub use disabled::*;
+
+#[cfg(feature = "sftp")]
+mod enabled {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::{Arc, LazyLock},
+        time::Duration,
+    };
+
+    use futures_util::future::BoxFuture;
+    use nix::unistd::{Uid, User};
+    use russh::{
+        client::{Handle, Handler},
+        keys::{PrivateKeyWithHashAlg, PublicKeyOrCertificate},
+    };
+    use russh_sftp::client::SftpSession;
+    use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+    use super::{HostKeyChecking, SftpOptions};
+    use crate::{backend::Backend, temp_path, CopyOutcome, ResolvedTarget};
+
+    // The keys that are tried, in order, when a copyset does not give an 'identity'.
+    const DEFAULT_IDENTITIES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+    /// A host that we connect to, along with the options that we connect with.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Endpoint {
+        user: String,
+        host: String,
+        port: u16,
+        options: SftpOptions,
+    }
+
+    struct Connection {
+        // The SSH session, which must be kept alive for as long as the SFTP session is used.
+        handle: Handle<HostKeys>,
+        sftp: SftpSession,
+    }
+
+    // The open connection to each host.
+    static CONNECTIONS: LazyLock<Mutex<HashMap<Endpoint, Arc<Connection>>>> =
+        LazyLock::new(Default::default);
+
+    // Split an 'sftp://user@host:port/path' URL into the host to connect to and the path on the
+    // host. The user defaults to the user that we are running as, and the port to 22.
+    fn parse(url: &str, options: &SftpOptions) -> std::io::Result<(Endpoint, String)> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid SFTP target {url:?}: expected 'sftp://user@host/path'"),
+            )
+        };
+
+        let rest = url.strip_prefix("sftp://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (user, host) = match authority.split_once('@') {
+            Some((user, host)) => (user.to_owned(), host),
+            None => (current_user()?, authority),
+        };
+
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (host, 22),
+        };
+
+        if user.is_empty() || host.is_empty() || path.is_empty() {
+            return Err(invalid());
+        }
+
+        let endpoint = Endpoint {
+            user,
+            host: host.to_owned(),
+            port,
+            options: options.clone(),
+        };
+
+        Ok((endpoint, format!("/{path}")))
+    }
+
+    fn current_user() -> std::io::Result<String> {
+        match User::from_uid(Uid::current())? {
+            Some(user) => Ok(user.name),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "cannot find the name of the current user",
+            )),
+        }
+    }
+
+    fn home_dir() -> PathBuf {
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/"))
+    }
+
+    /// Checks the key of the host that we are connecting to, according to 'host_key_checking'.
+    struct HostKeys {
+        host: String,
+        port: u16,
+        known_hosts: PathBuf,
+        checking: HostKeyChecking,
+    }
+
+    impl Handler for HostKeys {
+        type Error = russh::Error;
+
+        async fn check_server_key(
+            &mut self,
+            key: &PublicKeyOrCertificate,
+        ) -> Result<bool, Self::Error> {
+            if self.checking == HostKeyChecking::Off {
+                return Ok(true);
+            }
+
+            let key = key.public_key();
+            let known =
+                russh::keys::check_known_hosts_path(&self.host, self.port, &key, &self.known_hosts);
+
+            match known {
+                Ok(true) => Ok(true),
+                Ok(false) if self.checking == HostKeyChecking::AcceptNew => {
+                    tracing::info!(host = self.host, known_hosts = %self.known_hosts.display(),
+                                   "Adding host key to known hosts");
+                    russh::keys::known_hosts::learn_known_hosts_path(
+                        &self.host,
+                        self.port,
+                        &key,
+                        &self.known_hosts,
+                    )?;
+                    Ok(true)
+                }
+                Ok(false) => {
+                    tracing::error!(host = self.host, known_hosts = %self.known_hosts.display(),
+                                    "Host key is not in known hosts");
+                    Ok(false)
+                }
+                Err(err) => {
+                    tracing::error!(host = self.host, known_hosts = %self.known_hosts.display(),
+                                    "Host key does not match known hosts: {err}");
+                    Ok(false)
+                }
+            }
+        }
+    }
+
+    async fn connect(endpoint: &Endpoint) -> std::io::Result<Connection> {
+        let options = &endpoint.options;
+        let identity = match &options.identity {
+            Some(identity) => identity.clone(),
+            None => {
+                let ssh = home_dir().join(".ssh");
+                DEFAULT_IDENTITIES
+                    .iter()
+                    .map(|name| ssh.join(name))
+                    .find(|path| path.exists())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("no 'identity' was given, and there are no keys in {ssh:?}"),
+                        )
+                    })?
+            }
+        };
+
+        let key = russh::keys::load_secret_key(&identity, None).map_err(|err| {
+            tracing::error!(identity = %identity.display(), "Failed to load private key: {err}");
+            std::io::Error::other(err)
+        })?;
+
+        let handler = HostKeys {
+            host: endpoint.host.clone(),
+            port: endpoint.port,
+            known_hosts: options
+                .known_hosts
+                .clone()
+                .unwrap_or_else(|| home_dir().join(".ssh").join("known_hosts")),
+            checking: options.host_key_checking,
+        };
+
+        let config = Arc::new(russh::client::Config::default());
+        let mut handle =
+            russh::client::connect(config, (endpoint.host.as_str(), endpoint.port), handler)
+                .await
+                .map_err(std::io::Error::other)?;
+
+        let hash = handle
+            .best_supported_rsa_hash()
+            .await
+            .map_err(std::io::Error::other)?
+            .flatten();
+        let auth = handle
+            .authenticate_publickey(
+                &endpoint.user,
+                PrivateKeyWithHashAlg::new(Arc::new(key), hash),
+            )
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if !auth.success() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "failed to authenticate as {:?} with {identity:?}",
+                    endpoint.user
+                ),
+            ));
+        }
+
+        let channel = handle
+            .channel_open_session()
+            .await
+            .map_err(std::io::Error::other)?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .map_err(std::io::Error::other)?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .map_err(std::io::Error::other)?;
+
+        Ok(Connection { handle, sftp })
+    }
+
+    // Get the open connection to the host, connecting to it if needed. If the host cannot be
+    // reached, then we try again up to 'connect_attempts' times. The lock is held while we
+    // connect, so that concurrent copies to the same host share the one connection.
+    async fn connection(endpoint: &Endpoint) -> std::io::Result<Arc<Connection>> {
+        let mut connections = CONNECTIONS.lock().await;
+        if let Some(connection) = connections.get(endpoint) {
+            if !connection.handle.is_closed() {
+                return Ok(connection.clone());
+            }
+
+            tracing::info!(host = endpoint.host, "Connection was closed; reconnecting");
+            connections.remove(endpoint);
+        }
+
+        let attempts = endpoint.options.connect_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            tracing::info!(
+                host = endpoint.host,
+                port = endpoint.port,
+                user = endpoint.user,
+                attempt,
+                "Connecting to SFTP host"
+            );
+            match connect(endpoint).await {
+                Ok(connection) => {
+                    let connection = Arc::new(connection);
+                    connections.insert(endpoint.clone(), connection.clone());
+                    return Ok(connection);
+                }
+                Err(err) if attempt < attempts => {
+                    tracing::warn!(
+                        host = endpoint.host,
+                        attempt,
+                        "Failed to connect to SFTP host; retrying: {err:?}"
+                    );
+                    tokio::time::sleep(Duration::from_millis(endpoint.options.retry_delay_ms))
+                        .await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    tracing::error!(
+                        host = endpoint.host,
+                        attempt,
+                        "Failed to connect to SFTP host: {err:?}"
+                    );
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    // Upload the source to a temporary file next to the path on the host, and then rename it into
+    // place. SFTP cannot rename over an existing file, so the existing file is removed first.
+    async fn upload(sftp: &SftpSession, source: &Path, path: &str) -> std::io::Result<u64> {
+        let path = Path::new(path);
+        let mut missing = Vec::new();
+        for directory in path.ancestors().skip(1) {
+            let name = directory.to_string_lossy();
+            if name.is_empty() || name == "/" || sftp.try_exists(name).await.unwrap_or(false) {
+                break;
+            }
+
+            missing.push(directory);
+        }
+
+        for directory in missing.into_iter().rev() {
+            tracing::info!(directory = %directory.display(), "Creating remote directory");
+            sftp.create_dir(directory.to_string_lossy())
+                .await
+                .map_err(std::io::Error::other)?;
+        }
+
+        let temp = temp_path(path).to_string_lossy().into_owned();
+        let path = path.to_string_lossy().into_owned();
+        let result = async {
+            let mut local = tokio::fs::File::open(source).await?;
+            let mut remote = sftp.create(&temp).await.map_err(std::io::Error::other)?;
+            let bytes = tokio::io::copy(&mut local, &mut remote).await?;
+            remote.shutdown().await?;
+
+            if sftp.rename(&temp, &path).await.is_err() {
+                let _ = sftp.remove_file(&path).await;
+                sftp.rename(&temp, &path)
+                    .await
+                    .map_err(std::io::Error::other)?;
+            }
+
+            Ok(bytes)
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = sftp.remove_file(&temp).await;
+        }
+
+        result
+    }
+
+    /// Uploads targets to a remote host over SFTP.
+    #[derive(Debug)]
+    pub struct Sftp {
+        options: SftpOptions,
+    }
+
+    impl Backend for Sftp {
+        fn copy<'a>(
+            &'a self,
+            target: &'a ResolvedTarget,
+        ) -> BoxFuture<'a, std::io::Result<CopyOutcome>> {
+            Box::pin(async move {
+                let (endpoint, path) = parse(&target.target.to_string_lossy(), &self.options)?;
+                if target.is_too_large()? {
+                    return Ok(CopyOutcome::default());
+                }
+
+                tracing::info!(source = %target.source.display(), host = endpoint.host, path,
+                               "Uploading");
+
+                // If the connection has been dropped since it was last used, then the upload
+                // fails, and we try once more with a new connection.
+                let mut retried = false;
+                loop {
+                    let connection = connection(&endpoint).await?;
+                    match upload(&connection.sftp, &target.source, &path).await {
+                        Ok(bytes) => {
+                            return Ok(CopyOutcome {
+                                bytes: Some(bytes),
+                                ..CopyOutcome::default()
+                            })
+                        }
+                        Err(err) if !retried && connection.handle.is_closed() => {
+                            tracing::warn!(
+                                host = endpoint.host,
+                                "Connection was lost during upload; retrying: {err:?}"
+                            );
+                            retried = true;
+                        }
+                        Err(err) => {
+                            tracing::error!(
+                                host = endpoint.host,
+                                path,
+                                "Failed to upload over SFTP: {err:?}"
+                            );
+                            return Err(err);
+                        }
+                    }
+                }
+            })
+        }
+    }
+
+    /// The backend for a target with an 'sftp://' path.
+    pub fn backend(url: &str, options: &SftpOptions) -> std::io::Result<Arc<dyn Backend>> {
+        parse(url, options)?;
+        Ok(Arc::new(Sftp {
+            options: options.clone(),
+        }))
+    }
+}
+
+#[cfg(not(feature = "sftp"))]
+mod disabled {
+    use std::sync::Arc;
+
+    use super::SftpOptions;
+    use crate::backend::Backend;
+
+    pub fn backend(url: &str, _options: &SftpOptions) -> std::io::Result<Arc<dyn Backend>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("cannot copy to {url:?}: SFTP targets require the 'sftp' feature"),
+        ))
+    }
+}
@@ -0,0 +1,72 @@
+//! Readiness and watchdog notifications for systemd, for running as a `Type=notify` service. The
+//! notifications are only sent when the `sd-notify` feature is enabled and the `NOTIFY_SOCKET`
+//! environment variable is set by the service manager.
+
+#[cfg(feature = "sd-notify")]
+pub use enabled::*;
+
+#[cfg(not(feature = "sd-notify"))]
+pub use disabled::*;
+
+#[cfg(feature = "sd-notify")]
+mod enabled {
+    use std::time::Duration;
+
+    use sd_notify::NotifyState;
+
+    // Whether we are running under a service manager that is expecting notifications.
+    fn is_enabled() -> bool {
+        std::env::var_os("NOTIFY_SOCKET").is_some()
+    }
+
+    fn notify(state: NotifyState) {
+        if !is_enabled() {
+            return;
+        }
+
+        if let Err(err) = sd_notify::notify(&[state]) {
+            tracing::warn!("Failed to notify systemd: {err:?}");
+        }
+    }
+
+    /// Tell systemd that the watches have been set up and the event loop is running.
+    pub fn ready() {
+        notify(NotifyState::Ready);
+    }
+
+    /// Tell systemd that we are shutting down.
+    pub fn stopping() {
+        notify(NotifyState::Stopping);
+    }
+
+    /// Tell systemd that the event loop is still alive.
+    pub fn watchdog() {
+        notify(NotifyState::Watchdog);
+    }
+
+    /// The interval at which to send watchdog notifications, if systemd has enabled the watchdog
+    /// by setting `WATCHDOG_USEC`. This is half of the watchdog timeout, as recommended by
+    /// sd_watchdog_enabled(3), so that a late wakeup does not cause systemd to restart us.
+    pub fn watchdog_interval() -> Option<Duration> {
+        if !is_enabled() {
+            return None;
+        }
+
+        sd_notify::watchdog_enabled().map(|timeout| timeout / 2)
+    }
+}
+
+#[cfg(not(feature = "sd-notify"))]
+mod disabled {
+    use std::time::Duration;
+
+    pub fn ready() {}
+
+    pub fn stopping() {}
+
+    pub fn watchdog() {}
+
+    pub fn watchdog_interval() -> Option<Duration> {
+        None
+    }
+}
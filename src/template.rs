@@ -0,0 +1,115 @@
+//! Placeholders in target paths, which are expanded each time the target is copied so that each
+//! copy can go to a different file. Any text in braces that is not a known placeholder is left as
+//! it is.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const PLACEHOLDERS: &[&str] = &["{date}", "{time}", "{unix}", "{basename}", "{n}"];
+
+/// Whether the path contains any placeholders.
+pub fn is_templated(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    PLACEHOLDERS
+        .iter()
+        .any(|placeholder| path.contains(placeholder))
+}
+
+/// Whether the path contains the `{n}` counter, which needs to check which paths already exist.
+pub fn has_counter(path: &Path) -> bool {
+    path.to_string_lossy().contains("{n}")
+}
+
+/// Expand the placeholders in a target path for a copy of the given source made at the given
+/// time. The `{n}` counter is replaced with one more than the highest number of the existing
+/// copies, starting from 1.
+pub fn expand(path: &Path, source: &Path, now: SystemTime) -> PathBuf {
+    let unix = now
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days((unix / 86400) as i64);
+    let seconds = unix % 86400;
+
+    let expanded = path
+        .to_string_lossy()
+        .replace("{date}", &format!("{year:04}-{month:02}-{day:02}"))
+        .replace(
+            "{time}",
+            &format!(
+                "{:02}{:02}{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            ),
+        )
+        .replace("{unix}", &unix.to_string())
+        .replace(
+            "{basename}",
+            &source.file_name().unwrap_or_default().to_string_lossy(),
+        );
+
+    let Some((prefix, suffix)) = expanded.split_once("{n}") else {
+        return PathBuf::from(expanded);
+    };
+
+    let n = next_counter(prefix, suffix);
+    PathBuf::from(expanded.replace("{n}", &n.to_string()))
+}
+
+// Find the next counter for a path that has been split around its first '{n}'. This is one more
+// than the highest counter of the existing files in the directory, so that the counters keep
+// increasing even as older copies are removed. When the counter is in the name of a directory
+// rather than of the file, we use the lowest counter that is not in use instead.
+fn next_counter(prefix: &str, suffix: &str) -> u64 {
+    let directory_and_name = prefix.rsplit_once('/').filter(|_| !suffix.contains('/'));
+    let Some((directory, name_prefix)) = directory_and_name else {
+        return (1..)
+            .find(|n| {
+                let path = format!("{prefix}{n}{suffix}").replace("{n}", &n.to_string());
+                !Path::new(&path).exists()
+            })
+            .unwrap();
+    };
+
+    let directory = if directory.is_empty() { "/" } else { directory };
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return 1;
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let digits = name.strip_prefix(name_prefix)?.strip_suffix(suffix)?;
+            if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+
+            digits.parse::<u64>().ok()
+        })
+        .max()
+        .map_or(1, |n| n + 1)
+}
+
+// Convert a number of days since 1970-01-01 into a (year, month, day) in the proleptic Gregorian
+// calendar, using Howard Hinnant's 'civil_from_days' algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
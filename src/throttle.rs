@@ -0,0 +1,84 @@
+//! Bandwidth throttling for copies, using a token bucket that is shared by all of the copies that
+//! are subject to the same limit.
+
+use std::{
+    io::{Read, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The size of each chunk that is read from the source and written to the target when copying
+/// through a rate limiter. Files that are smaller than this are not throttled.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A token bucket that limits the number of bytes per second. The bucket holds up to one second
+/// of tokens, so that a copy can briefly run faster than the limit after a quiet period.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take the given number of bytes from the bucket, blocking the thread until they are
+    /// available. This is only called from the blocking thread pool.
+    fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+            bucket.updated = now;
+            bucket.tokens -= bytes as f64;
+
+            // The bucket can go into debt, in which case we wait for it to be repaid.
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Copy everything from the reader to the writer, a chunk at a time, at no more than the rate
+    /// of this limiter. Returns the number of bytes that were copied.
+    pub fn copy<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> std::io::Result<u64> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            let count = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            self.acquire(count);
+            writer.write_all(&buffer[..count])?;
+            copied += count as u64;
+        }
+
+        writer.flush()?;
+        Ok(copied)
+    }
+}
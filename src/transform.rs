@@ -0,0 +1,98 @@
+//! Transformations of the contents of a source on their way to the target, such as decoding a
+//! Kubernetes secret that is mounted as base64. The contents are streamed through the transform,
+//! rather than being read into memory.
+
+use std::io::{Read, Write};
+
+use base64::{engine::general_purpose::STANDARD, read::DecoderReader, write::EncoderWriter};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::throttle::RateLimiter;
+
+/// A transform that is applied to the contents of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transform {
+    /// Decode base64, ignoring any whitespace (such as line breaks) in the source.
+    Base64Decode,
+    /// Encode the source as base64, without any line breaks.
+    Base64Encode,
+    /// Decompress a gzipped source.
+    Gunzip,
+    /// Compress the source with gzip.
+    Gzip,
+}
+
+impl Transform {
+    /// Copy the contents of the reader through the transform to the writer, through the rate
+    /// limiter (if any).
+    pub fn copy(
+        self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        limiter: Option<&RateLimiter>,
+    ) -> std::io::Result<()> {
+        match self {
+            Transform::Base64Decode => {
+                let mut decoder = DecoderReader::new(SkipWhitespace(reader), &STANDARD);
+                pipe(&mut decoder, writer, limiter)?;
+            }
+            Transform::Base64Encode => {
+                let mut encoder = EncoderWriter::new(writer, &STANDARD);
+                pipe(reader, &mut encoder, limiter)?;
+                encoder.finish()?;
+            }
+            Transform::Gunzip => {
+                let mut decoder = MultiGzDecoder::new(reader);
+                pipe(&mut decoder, writer, limiter)?;
+            }
+            Transform::Gzip => {
+                let mut encoder = GzEncoder::new(writer, Compression::default());
+                pipe(reader, &mut encoder, limiter)?;
+                encoder.finish()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Copy the contents of the reader to the writer as they are, through the rate limiter (if any).
+pub fn pipe(
+    mut reader: &mut dyn Read,
+    mut writer: &mut dyn Write,
+    limiter: Option<&RateLimiter>,
+) -> std::io::Result<u64> {
+    match limiter {
+        Some(limiter) => limiter.copy(&mut reader, &mut writer),
+        None => std::io::copy(&mut reader, &mut writer),
+    }
+}
+
+// A reader that leaves out any ASCII whitespace, such as the line breaks in wrapped base64 or the
+// newline at the end of a file.
+struct SkipWhitespace<R>(R);
+
+impl<R: Read> Read for SkipWhitespace<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.0.read(buf)?;
+            if read == 0 {
+                return Ok(0);
+            }
+
+            let mut kept = 0;
+            for index in 0..read {
+                if !buf[index].is_ascii_whitespace() {
+                    buf[kept] = buf[index];
+                    kept += 1;
+                }
+            }
+
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Tests for single files that are copied into a directory under their own name.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn trailing_slash_places_file_in_directory() {
+    let dir = test_dir("trailing_slash_places_file_in_directory");
+    write_source(&dir, "certs/app.crt", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"certs/app.crt\"\ntarget = \"tls/\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "tls/app.crt").as_deref(), Some("app"));
+}
+
+#[test]
+fn existing_directory_places_file_in_directory() {
+    let dir = test_dir("existing_directory_places_file_in_directory");
+    write_source(&dir, "app.crt", "app");
+    fs::create_dir_all(dir.join("target/tls")).unwrap();
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"tls\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "tls/app.crt").as_deref(), Some("app"));
+}
@@ -0,0 +1,57 @@
+//! Tests for checking a configuration for problems with `--check`.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use common::{test_dir, write_config, write_source};
+
+// Check the given configuration file, returning whether it passed and what was printed.
+fn check(config_path: &Path) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(config_path)
+        .arg("--check")
+        .output()
+        .unwrap();
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    )
+}
+
+#[test]
+fn source_watched_by_two_copysets_fails() {
+    let dir = test_dir("source_watched_by_two_copysets_fails");
+    write_source(&dir, "app.crt", "app");
+
+    let copyset = |name: &str| {
+        format!(
+            "[[copysets]]\nname = \"{name}\"\nsource = \"{}\"\ntarget = \"{}\"\n\n\
+             [[copysets.targets]]\nsource = \"app.crt\"\n\n",
+            dir.join("source").display(),
+            dir.join(name).display()
+        )
+    };
+
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, copyset("first") + &copyset("second")).unwrap();
+
+    let (passed, stdout) = check(&config_path);
+    assert!(!passed, "{stdout}");
+    let message = format!(
+        "copyset \"second\": source {:?} is also watched by copyset \"first\"",
+        dir.join("source/app.crt")
+    );
+    assert!(stdout.contains(&message), "{stdout}");
+
+    // Targets of the one copyset can share a source.
+    let config_path = write_config(
+        &dir,
+        "",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"copy.crt\"\n",
+    );
+    let (passed, stdout) = check(&config_path);
+    assert!(passed, "{stdout}");
+}
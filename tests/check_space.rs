@@ -0,0 +1,28 @@
+//! Tests for skipping copies of sources that would not fit on the filesystem of the target.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn source_too_large_for_filesystem_is_skipped() {
+    let dir = test_dir("source_too_large_for_filesystem_is_skipped");
+    write_source(&dir, "small.txt", "small");
+
+    // A sparse file takes no space itself, but claims to be larger than the filesystem.
+    let large = fs::File::create(dir.join("source/large.img")).unwrap();
+    large.set_len(1 << 43).unwrap();
+
+    let output = run_once(
+        &dir,
+        "check_space = true\n\n[[copysets.targets]]\nsource = \"large.img\"\n\n\
+         [[copysets.targets]]\nsource = \"small.txt\"\n",
+    );
+
+    assert!(output.status.success(), "{output:?}");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("not enough space for target"));
+    assert_eq!(read_target(&dir, "small.txt").as_deref(), Some("small"));
+    assert!(!dir.join("target/large.img").exists());
+}
@@ -0,0 +1,127 @@
+//! Helpers shared by the integration tests, which run `container-copier` with `--once` so that
+//! each source is copied and the process exits, or in the background to watch for changes.
+
+// Each test file only uses some of the helpers.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    time::{Duration, Instant},
+};
+
+// Create an empty directory for a test, under the temporary directory that Cargo gives us.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("source")).unwrap();
+    dir
+}
+
+// Write a file under the source directory, creating any directories that it needs.
+pub fn write_source(dir: &Path, path: &str, contents: &str) {
+    let path = dir.join("source").join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+// Write a configuration with the given top-level options and a single copyset with the given
+// options and targets, returning the path of the configuration file.
+pub fn write_config(dir: &Path, options: &str, targets: &str) -> PathBuf {
+    let config = format!(
+        "{options}[[copysets]]\nname = \"test\"\nsource = \"{}\"\ntarget = \"{}\"\n{targets}",
+        dir.join("source").display(),
+        dir.join("target").display()
+    );
+
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, config).unwrap();
+    config_path
+}
+
+// Run a single copyset with the given options and targets once, returning the output of the
+// process.
+pub fn run_once(dir: &Path, targets: &str) -> Output {
+    run_config_once(&write_config(dir, "", targets))
+}
+
+// Run the given configuration file once, returning the output of the process.
+pub fn run_config_once(config_path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(config_path)
+        .arg("--once")
+        .output()
+        .unwrap()
+}
+
+/// A copier that is watching in the background, which is killed when it is dropped.
+pub struct Watcher(Child);
+
+impl Watcher {
+    /// Send a signal, such as `USR1`, to the copier.
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{signal}"))
+            .arg(self.0.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Count the inotify watches that the copier holds in the kernel, from the `fdinfo` of its
+    /// file descriptors.
+    pub fn kernel_watches(&self) -> usize {
+        fs::read_dir(format!("/proc/{}/fdinfo", self.0.id()))
+            .unwrap()
+            .filter_map(|entry| fs::read_to_string(entry.unwrap().path()).ok())
+            .map(|info| {
+                info.lines()
+                    .filter(|line| line.starts_with("inotify wd:"))
+                    .count()
+            })
+            .sum()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Start watching a single copyset with the given options and targets in the background, waiting
+// until the initial copies have been made.
+pub fn watch(dir: &Path, targets: &str) -> Watcher {
+    watch_config(&write_config(dir, "", targets))
+}
+
+// Start watching the given configuration file in the background.
+pub fn watch_config(config_path: &Path) -> Watcher {
+    let child = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(config_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // There is no signal for when the watches are ready, so we give it a moment.
+    std::thread::sleep(Duration::from_millis(500));
+    Watcher(child)
+}
+
+// Wait for a condition to become true, failing the test if it takes too long.
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for condition");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+pub fn read_target(dir: &Path, path: &str) -> Option<String> {
+    fs::read_to_string(dir.join("target").join(path)).ok()
+}
@@ -0,0 +1,93 @@
+//! Tests for compressing the target on its way from the source.
+
+mod common;
+
+use std::{fs, io::Read, path::Path};
+
+use common::{run_once, test_dir, wait_until, watch, write_source};
+use flate2::read::GzDecoder;
+
+// Decompress a gzipped target, if it exists.
+fn gunzip(path: &Path) -> Option<String> {
+    let compressed = fs::read(path).ok()?;
+    let mut contents = String::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_string(&mut contents)
+        .ok()?;
+    Some(contents)
+}
+
+#[test]
+fn gzip_adds_suffix() {
+    let dir = test_dir("gzip_adds_suffix");
+    write_source(&dir, "app.log", "some log lines");
+    write_source(&dir, "other.log", "other log lines");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.log\"\ncompress = \"gzip\"\ncompress_level = 9\n\
+         verify = true\n\n\
+         [[copysets.targets]]\nsource = \"other.log\"\ntarget = \"other.log.gz\"\n\
+         compress = \"gzip\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert!(!dir.join("target/app.log").exists());
+    assert_eq!(
+        gunzip(&dir.join("target/app.log.gz")).as_deref(),
+        Some("some log lines")
+    );
+
+    // A target that already has the suffix is left as it is.
+    assert_eq!(
+        gunzip(&dir.join("target/other.log.gz")).as_deref(),
+        Some("other log lines")
+    );
+}
+
+#[test]
+fn gzip_recompresses_on_change() {
+    let dir = test_dir("gzip_recompresses_on_change");
+    write_source(&dir, "logs/app.log", "first");
+
+    let _watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"logs\"\nrecursive = true\ncompress = \"gzip\"\n",
+    );
+
+    let target = dir.join("target/logs/app.log.gz");
+    wait_until(|| gunzip(&target).as_deref() == Some("first"));
+
+    write_source(&dir, "logs/app.log", "second");
+    wait_until(|| gunzip(&target).as_deref() == Some("second"));
+}
+
+#[test]
+fn invalid_level_fails() {
+    let dir = test_dir("invalid_level_fails");
+    write_source(&dir, "app.log", "some log lines");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.log\"\ncompress = \"gzip\"\ncompress_level = 10\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    assert!(!dir.join("target/app.log.gz").exists());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_adds_suffix() {
+    let dir = test_dir("zstd_adds_suffix");
+    write_source(&dir, "app.log", "some log lines");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.log\"\ncompress = \"zstd\"\ncompress_level = 19\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    let compressed = fs::read(dir.join("target/app.log.zst")).unwrap();
+    assert_eq!(
+        zstd::decode_all(&compressed[..]).unwrap(),
+        b"some log lines"
+    );
+}
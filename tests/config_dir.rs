@@ -0,0 +1,181 @@
+//! Tests for loading every configuration file in a directory with `--config-dir`.
+
+mod common;
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+use common::{read_target, test_dir, write_source};
+
+// Write a configuration file into the 'conf.d' directory, with a single copyset of the given name
+// and targets, followed by any other settings.
+fn write_file(dir: &Path, name: &str, copyset: &str, targets: &str) {
+    let conf = dir.join("conf.d");
+    fs::create_dir_all(&conf).unwrap();
+    fs::write(
+        conf.join(name),
+        format!(
+            "[[copysets]]\nname = \"{copyset}\"\nsource = \"{}\"\ntarget = \"{}\"\n{targets}",
+            dir.join("source").display(),
+            dir.join("target").display()
+        ),
+    )
+    .unwrap();
+}
+
+// Run the configuration files in the 'conf.d' directory once.
+fn run_dir_once(dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config-dir")
+        .arg(dir.join("conf.d"))
+        .arg("--once")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn copysets_of_each_file_are_merged() {
+    let dir = test_dir("copysets_of_each_file_are_merged");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "key");
+    write_file(
+        &dir,
+        "10-certs.toml",
+        "certs",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    write_file(
+        &dir,
+        "20-keys.toml",
+        "keys",
+        "[[copysets.targets]]\nsource = \"app.key\"\n",
+    );
+
+    // Files with other extensions are left alone.
+    fs::write(dir.join("conf.d/README"), "not a configuration file").unwrap();
+
+    let output = run_dir_once(&dir);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert_eq!(read_target(&dir, "app.key").as_deref(), Some("key"));
+}
+
+#[test]
+fn files_are_loaded_in_different_formats() {
+    let dir = test_dir("files_are_loaded_in_different_formats");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "key");
+    write_file(
+        &dir,
+        "certs.toml",
+        "certs",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+
+    let keys = serde_json::json!({
+        "copysets": [{
+            "name": "keys",
+            "source": dir.join("source"),
+            "target": dir.join("target"),
+            "targets": [{ "source": "app.key" }],
+        }],
+    });
+    fs::write(dir.join("conf.d/keys.json"), keys.to_string()).unwrap();
+
+    let output = run_dir_once(&dir);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert_eq!(read_target(&dir, "app.key").as_deref(), Some("key"));
+}
+
+#[test]
+fn duplicate_copyset_names_fail() {
+    let dir = test_dir("duplicate_copyset_names_fail");
+    write_source(&dir, "app.crt", "app");
+    write_file(
+        &dir,
+        "a.toml",
+        "certs",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    write_file(
+        &dir,
+        "b.toml",
+        "certs",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+
+    let output = run_dir_once(&dir);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let (copyset, other) = stderr.split_once("is also defined in").expect(&stderr);
+    assert!(copyset.contains("conf.d/b.toml"), "{stderr}");
+    assert!(other.contains("conf.d/a.toml"), "{stderr}");
+    assert!(read_target(&dir, "app.crt").is_none());
+}
+
+#[test]
+fn included_file_is_loaded_once() {
+    let dir = test_dir("included_file_is_loaded_once");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "key");
+    write_file(
+        &dir,
+        "a.toml",
+        "certs",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    write_file(
+        &dir,
+        "b.toml",
+        "keys",
+        "[[copysets.targets]]\nsource = \"app.key\"\n",
+    );
+
+    // The first file includes the second, which is then not loaded again in its own right.
+    let first = fs::read_to_string(dir.join("conf.d/a.toml")).unwrap();
+    fs::write(
+        dir.join("conf.d/a.toml"),
+        format!("include = [\"b.toml\"]\n\n{first}"),
+    )
+    .unwrap();
+
+    let output = run_dir_once(&dir);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.key").as_deref(), Some("key"));
+}
+
+#[test]
+fn settings_in_later_file_fail() {
+    let dir = test_dir("settings_in_later_file_fail");
+    write_source(&dir, "app.crt", "app");
+    write_file(
+        &dir,
+        "a.toml",
+        "certs",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+
+    let path = dir.join("conf.d/b.toml");
+    fs::write(&path, "max_consecutive_failures = 5\n").unwrap();
+
+    let output = run_dir_once(&dir);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("can only be set in the first configuration file"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn empty_directory_fails() {
+    let dir = test_dir("empty_directory_fails");
+    fs::create_dir_all(dir.join("conf.d")).unwrap();
+
+    let output = run_dir_once(&dir);
+    assert!(!output.status.success(), "{output:?}");
+}
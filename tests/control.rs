@@ -0,0 +1,171 @@
+//! Tests for the commands accepted on the control socket, and for copying everything again on
+//! request.
+
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use common::{read_target, test_dir, wait_until, watch, write_config, write_source};
+
+// Send a command to the control socket, returning the response.
+fn command(socket: &Path, command: &str) -> serde_json::Value {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    writeln!(stream, "{command}").unwrap();
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[test]
+fn list_describes_watches() {
+    let dir = test_dir("list_describes_watches");
+    write_source(&dir, "app.crt", "app");
+    let socket = dir.join("cc.sock");
+
+    let _watcher = watch(
+        &dir,
+        &format!(
+            "[[copysets.targets]]\nsource = \"app.crt\"\n\n[control]\nsocket = \"{}\"\n",
+            socket.display()
+        ),
+    );
+
+    let watches = command(&socket, "list");
+    let watches = watches.as_array().unwrap();
+    assert_eq!(watches.len(), 2, "{watches:?}");
+    assert_eq!(watches[0]["kind"], "file");
+    assert_eq!(
+        watches[0]["path"],
+        dir.join("source/app.crt").display().to_string()
+    );
+
+    // The directory of the source is watched for files moved into its place.
+    assert_eq!(watches[1]["kind"], "parent");
+    assert_eq!(watches[1]["path"], dir.join("source").display().to_string());
+
+    let target = &watches[0]["targets"][0];
+    assert_eq!(
+        target["target"],
+        dir.join("target/app.crt").display().to_string()
+    );
+    assert_eq!(
+        target["events"],
+        serde_json::json!(["CREATE", "DELETE", "MODIFY"])
+    );
+}
+
+#[test]
+fn sync_copies_target() {
+    let dir = test_dir("sync_copies_target");
+    write_source(&dir, "app.crt", "app");
+    let socket = dir.join("cc.sock");
+
+    let _watcher = watch(
+        &dir,
+        &format!(
+            "[[copysets.targets]]\nsource = \"app.crt\"\n\n[control]\nsocket = \"{}\"\n",
+            socket.display()
+        ),
+    );
+
+    wait_until(|| read_target(&dir, "app.crt").is_some());
+    std::fs::remove_file(dir.join("target/app.crt")).unwrap();
+
+    let source = dir.join("source/app.crt");
+    let response = command(&socket, &format!("sync {}", source.display()));
+    assert_eq!(response["copying"], 1, "{response:?}");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("app"));
+
+    let response = command(&socket, "sync /nonexistent");
+    assert!(response["error"].is_string(), "{response:?}");
+}
+
+#[test]
+fn sync_without_source_copies_all_targets() {
+    let dir = test_dir("sync_without_source_copies_all_targets");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "ca.crt", "ca");
+    let socket = dir.join("cc.sock");
+
+    let _watcher = watch(
+        &dir,
+        &format!(
+            "[[copysets.targets]]\nsource = \"app.crt\"\n\n[[copysets.targets]]\nsource = \"ca.crt\"\n\n\
+             [control]\nsocket = \"{}\"\n",
+            socket.display()
+        ),
+    );
+
+    wait_until(|| read_target(&dir, "ca.crt").is_some());
+    std::fs::remove_file(dir.join("target/app.crt")).unwrap();
+    std::fs::remove_file(dir.join("target/ca.crt")).unwrap();
+
+    let response = command(&socket, "sync");
+    assert_eq!(response["copying"], 2, "{response:?}");
+    wait_until(|| {
+        read_target(&dir, "app.crt").as_deref() == Some("app")
+            && read_target(&dir, "ca.crt").as_deref() == Some("ca")
+    });
+}
+
+#[test]
+fn sigusr1_copies_all_targets() {
+    let dir = test_dir("sigusr1_copies_all_targets");
+    write_source(&dir, "app.crt", "app");
+
+    let watcher = watch(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    wait_until(|| read_target(&dir, "app.crt").is_some());
+    std::fs::remove_file(dir.join("target/app.crt")).unwrap();
+
+    watcher.signal("USR1");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("app"));
+}
+
+#[test]
+fn existing_file_at_socket_path_is_kept() {
+    let dir = test_dir("existing_file_at_socket_path_is_kept");
+    write_source(&dir, "app.crt", "app");
+    let socket = dir.join("cc.sock");
+    std::fs::write(&socket, "not a socket").unwrap();
+
+    let config_path = write_config(
+        &dir,
+        "",
+        &format!(
+            "[[copysets.targets]]\nsource = \"app.crt\"\n\n[control]\nsocket = \"{}\"\n",
+            socket.display()
+        ),
+    );
+
+    // The control socket is only served while watching, which stops on the failure to bind it.
+    let mut child = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(&config_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while child.try_wait().unwrap().is_none() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let _ = child.kill();
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("already exists and is not a socket"),
+        "{stderr}"
+    );
+    assert!(stderr.contains(&socket.display().to_string()), "{stderr}");
+    assert_eq!(std::fs::read_to_string(&socket).unwrap(), "not a socket");
+}
@@ -0,0 +1,33 @@
+//! Tests for the mode of the directories that are created to hold targets.
+
+mod common;
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use common::{read_target, run_once, test_dir, write_source};
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[test]
+fn dir_mode_applies_to_created_directories() {
+    let dir = test_dir("dir_mode_applies_to_created_directories");
+    write_source(&dir, "secrets/prod/app.key", "key");
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::set_permissions(dir.join("target"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = run_once(
+        &dir,
+        "dir_mode = \"0700\"\n[[copysets.targets]]\nsource = \"secrets/prod/app.key\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        read_target(&dir, "secrets/prod/app.key").as_deref(),
+        Some("key")
+    );
+
+    assert_eq!(mode(&dir.join("target/secrets")), 0o700);
+    assert_eq!(mode(&dir.join("target/secrets/prod")), 0o700);
+    assert_eq!(mode(&dir.join("target")), 0o755);
+}
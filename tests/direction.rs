@@ -0,0 +1,63 @@
+//! Tests for targets that are copied back to their source, or in both directions.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, run_once, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn target_to_source_copies_target_back() {
+    let dir = test_dir("target_to_source_copies_target_back");
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join("target/state.json"), "state").unwrap();
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"state.json\"\ndirection = \"target-to-source\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        fs::read_to_string(dir.join("source/state.json"))
+            .ok()
+            .as_deref(),
+        Some("state")
+    );
+}
+
+#[test]
+fn both_copies_changes_either_way() {
+    let dir = test_dir("both_copies_changes_either_way");
+    write_source(&dir, "state.json", "one");
+
+    let _watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"state.json\"\ndirection = \"both\"\n",
+    );
+
+    wait_until(|| read_target(&dir, "state.json").as_deref() == Some("one"));
+
+    fs::write(dir.join("target/state.json"), "two").unwrap();
+    wait_until(|| {
+        fs::read_to_string(dir.join("source/state.json"))
+            .ok()
+            .as_deref()
+            == Some("two")
+    });
+
+    write_source(&dir, "state.json", "three");
+    wait_until(|| read_target(&dir, "state.json").as_deref() == Some("three"));
+}
+
+#[test]
+fn both_with_mirror_delete_is_rejected() {
+    let dir = test_dir("both_with_mirror_delete_is_rejected");
+    write_source(&dir, "state.json", "one");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"state.json\"\ndirection = \"both\"\nmirror_delete = true\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "state.json"), None);
+}
@@ -0,0 +1,43 @@
+//! Tests for copysets and targets that are disabled in the configuration.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn disabled_target_is_skipped() {
+    let dir = test_dir("disabled_target_is_skipped");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "ca.crt", "ca");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"ca.crt\"\nenabled = false\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert_eq!(read_target(&dir, "ca.crt"), None);
+}
+
+#[test]
+fn disabled_copyset_is_skipped() {
+    let dir = test_dir("disabled_copyset_is_skipped");
+    write_source(&dir, "app.crt", "app");
+
+    // The source of the disabled copyset does not exist, which would fail the run if it were
+    // watched.
+    let output = run_once(
+        &dir,
+        &format!(
+            "[[copysets.targets]]\nsource = \"app.crt\"\n\n[[copysets]]\nname = \"disabled\"\n\
+             enabled = false\nsource = \"{}\"\ntarget = \"{}\"\n\n\
+             [[copysets.targets]]\nsource = \"app.crt\"\n",
+            dir.join("missing").display(),
+            dir.join("other").display()
+        ),
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert!(!dir.join("other").exists());
+}
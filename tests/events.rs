@@ -0,0 +1,102 @@
+//! Tests for the events that are watched, and those that are watched when a copyset does not give
+//! its own.
+
+mod common;
+
+use std::{fs, os::unix::fs::PermissionsExt, time::Duration};
+
+use common::{
+    read_target, run_once, test_dir, wait_until, watch_config, write_config, write_source,
+};
+
+#[test]
+fn copysets_inherit_default_events() {
+    let dir = test_dir("copysets_inherit_default_events");
+    write_source(&dir, "app.crt", "one");
+
+    let config_path = write_config(
+        &dir,
+        "default_events = [\"ATTRIB\"]\n\n",
+        "copy_on_start = \"never\"\n\n[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+
+    let _watcher = watch_config(&config_path);
+
+    // Modifying the source is not one of the default events, so it is not copied.
+    write_source(&dir, "app.crt", "two");
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(read_target(&dir, "app.crt"), None);
+
+    let source = dir.join("source/app.crt");
+    fs::set_permissions(&source, fs::Permissions::from_mode(0o600)).unwrap();
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("two"));
+}
+
+#[test]
+fn unknown_event_in_string_fails() {
+    let dir = test_dir("unknown_event_in_string_fails");
+    write_source(&dir, "app.crt", "one");
+
+    let output = run_once(
+        &dir,
+        "events = \"create|bogus\"\n[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(error.contains("unknown event"), "{error}");
+}
+
+#[test]
+fn flag_in_events_fails() {
+    let dir = test_dir("flag_in_events_fails");
+    write_source(&dir, "app.crt", "one");
+
+    let output = run_once(
+        &dir,
+        "events = [\"MODIFY\", \"ONESHOT\"]\n[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(error.contains("give it in 'flags'"), "{error}");
+}
+
+#[test]
+fn empty_events_fails() {
+    let dir = test_dir("empty_events_fails");
+    write_source(&dir, "app.crt", "one");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\nevents = []\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(error.contains("at least one event"), "{error}");
+}
+
+#[test]
+fn shared_source_watches_events_of_all_targets() {
+    let dir = test_dir("shared_source_watches_events_of_all_targets");
+    write_source(&dir, "app.crt", "one");
+
+    let config_path = write_config(
+        &dir,
+        "",
+        "copy_on_start = \"never\"\n\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"written/app.crt\"\n\
+         events = [\"CLOSE_WRITE\"]\n\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"attributes/app.crt\"\n\
+         events = [\"ATTRIB\"]\n",
+    );
+
+    let _watcher = watch_config(&config_path);
+
+    // Both targets share the one watch on the source, which listens for the events of both.
+    let source = dir.join("source/app.crt");
+    fs::set_permissions(&source, fs::Permissions::from_mode(0o600)).unwrap();
+    wait_until(|| read_target(&dir, "attributes/app.crt").is_some());
+    assert_eq!(read_target(&dir, "written/app.crt"), None);
+
+    write_source(&dir, "app.crt", "two");
+    wait_until(|| read_target(&dir, "written/app.crt").as_deref() == Some("two"));
+}
@@ -0,0 +1,65 @@
+//! Tests for where targets with nested source paths are placed.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn nested_source_is_mirrored_by_default() {
+    let dir = test_dir("nested_source_is_mirrored_by_default");
+    write_source(&dir, "certs/prod/app.crt", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"certs/prod/app.crt\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        read_target(&dir, "certs/prod/app.crt").as_deref(),
+        Some("app")
+    );
+}
+
+#[test]
+fn flatten_places_file_in_target_directory() {
+    let dir = test_dir("flatten_places_file_in_target_directory");
+    write_source(&dir, "certs/prod/app.crt", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"certs/prod/app.crt\"\nflatten = true\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert!(!dir.join("target/certs").exists());
+}
+
+#[test]
+fn flatten_places_glob_matches_in_target_directory() {
+    let dir = test_dir("flatten_places_glob_matches_in_target_directory");
+    write_source(&dir, "certs/prod/app.crt", "app");
+    write_source(&dir, "certs/prod/ca.crt", "ca");
+    write_source(&dir, "certs/prod/app.key", "key");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"certs/prod/*.crt\"\nflatten = true\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert_eq!(read_target(&dir, "ca.crt").as_deref(), Some("ca"));
+    assert_eq!(read_target(&dir, "app.key"), None);
+}
+
+#[test]
+fn flatten_with_explicit_target_is_rejected() {
+    let dir = test_dir("flatten_with_explicit_target_is_rejected");
+    write_source(&dir, "certs/prod/app.crt", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"certs/prod/app.crt\"\ntarget = \"app.crt\"\nflatten = true\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt"), None);
+}
@@ -0,0 +1,72 @@
+//! Tests for the health check, which is only ready once every target is in sync with its source.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+};
+
+use common::{read_target, test_dir, wait_until, watch, write_source};
+
+// Find a port that is free to listen on.
+fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+// Send a GET request for the given path, returning the status code and the body of the response.
+fn get(address: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, body.to_string())
+}
+
+#[test]
+fn not_ready_until_source_exists() {
+    let dir = test_dir("not_ready_until_source_exists");
+    write_source(&dir, "app.crt", "app");
+    let address = free_address();
+
+    let _watcher = watch(
+        &dir,
+        &format!(
+            "[[copysets.targets]]\nsource = \"app.crt\"\n\n\
+             [[copysets.targets]]\nsource = \"app.key\"\n\n\
+             [health]\nlisten = \"{address}\"\n"
+        ),
+    );
+
+    assert_eq!(get(address, "/livez").0, 200);
+    assert_eq!(get(address, "/healthz").0, 503);
+
+    let (status, body) = get(address, "/status");
+    assert_eq!(status, 200);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["copysets"]["test"]["targets"], 2);
+    assert_eq!(
+        body["copysets"]["test"]["missing"],
+        serde_json::json!([dir.join("source/app.key").display().to_string()])
+    );
+
+    write_source(&dir, "app.key", "key");
+    wait_until(|| read_target(&dir, "app.key").as_deref() == Some("key"));
+    wait_until(|| get(address, "/healthz").0 == 200);
+
+    let (_, body) = get(address, "/status");
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["copysets"]["test"]["missing"], serde_json::json!([]));
+}
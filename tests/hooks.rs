@@ -0,0 +1,64 @@
+//! Tests for the paths that are given to hook commands, and for hooks that are run once for a
+//! batch of copies.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn hook_receives_awkward_paths_unchanged() {
+    let dir = test_dir("hook_receives_awkward_paths_unchanged");
+    let name = "my cert\n$(touch pwned) ünïcode;'\".crt";
+    write_source(&dir, name, "cert");
+
+    // The hook writes the target path that it was given to a file named by its first argument.
+    let output = run_once(
+        &dir,
+        &format!(
+            "[[copysets.targets]]\nsource = {name:?}\non_copy = [\"sh\", \"-c\", 'printf %s \"$CC_TARGET\" > \"$0\"', {:?}]\n",
+            dir.join("hook.out").display().to_string()
+        ),
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, name).as_deref(), Some("cert"));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("hook.out")).unwrap(),
+        dir.join("target").join(name).display().to_string()
+    );
+    // The hook runs in our working directory, where the substitution would have created the file.
+    assert!(!std::path::Path::new("pwned").exists());
+}
+
+#[test]
+fn batch_hook_runs_once_with_all_targets() {
+    let dir = test_dir("batch_hook_runs_once_with_all_targets");
+    for name in ["a.crt", "b.crt", "c.crt"] {
+        write_source(&dir, name, name);
+    }
+
+    // Each run of the hook appends the targets that it was given, followed by the copyset.
+    let output = run_once(
+        &dir,
+        &format!(
+            "on_batch = [\"sh\", \"-c\", 'cat >> \"$0\"; echo \"copyset $CC_COPYSET\" >> \"$0\"', {:?}]\n\
+             [[copysets.targets]]\nsource = \"a.crt\"\n\n\
+             [[copysets.targets]]\nsource = \"b.crt\"\n\n\
+             [[copysets.targets]]\nsource = \"c.crt\"\n",
+            dir.join("hook.out").display().to_string()
+        ),
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let hook = std::fs::read_to_string(dir.join("hook.out")).unwrap();
+    let mut lines = hook.lines().collect::<Vec<_>>();
+    assert_eq!(lines.pop(), Some("copyset test"), "{hook}");
+    lines.sort();
+    let expected = ["a.crt", "b.crt", "c.crt"].map(|name| dir.join("target").join(name));
+    assert_eq!(
+        lines,
+        expected
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect::<Vec<_>>()
+    );
+}
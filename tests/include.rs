@@ -0,0 +1,60 @@
+//! Tests for including the copysets of other configuration files.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use common::{run_config_once, test_dir, write_source};
+
+// Write a fragment defining a copyset with each of the given names, all copying the same source.
+fn write_fragment(dir: &Path, name: &str, copysets: &[&str]) {
+    let fragment = copysets
+        .iter()
+        .map(|copyset| {
+            format!(
+                "[[copysets]]\nname = \"{copyset}\"\nsource = \"{}\"\ntarget = \"{}\"\n\n\
+                 [[copysets.targets]]\nsource = \"app.crt\"\n\n",
+                dir.join("source").display(),
+                dir.join("target").display()
+            )
+        })
+        .collect::<String>();
+    fs::write(dir.join(name), fragment).unwrap();
+}
+
+#[test]
+fn file_matched_twice_is_included_once() {
+    let dir = test_dir("file_matched_twice_is_included_once");
+    write_source(&dir, "app.crt", "app");
+    write_fragment(&dir, "frag.toml", &["certs"]);
+
+    // The main file matches the second pattern as well, and is not included in itself.
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, "include = [\"frag.toml\", \"*.toml\"]\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(&config_path)
+        .args(["--print-config", "json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config["copysets"].as_array().unwrap().len(), 1, "{config}");
+}
+
+#[test]
+fn duplicate_name_in_fragment_fails() {
+    let dir = test_dir("duplicate_name_in_fragment_fails");
+    write_source(&dir, "app.crt", "app");
+    write_fragment(&dir, "frag.toml", &["certs", "certs"]);
+
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, "include = [\"frag.toml\"]\n").unwrap();
+
+    let output = run_config_once(&config_path);
+    assert!(!output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is defined more than once in"), "{stderr}");
+}
@@ -0,0 +1,130 @@
+//! Tests for using the copier as a library, rather than running the binary, which set up a
+//! configuration and then process the events from inotify in the same process.
+
+mod common;
+
+use std::{
+    future::Future,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use container_copier::{Config, RunOptions};
+
+use common::{read_target, test_dir, write_config, write_source};
+
+// Parse a configuration with a single copyset with the given options and targets.
+fn config(dir: &Path, targets: &str) -> Config {
+    std::fs::read_to_string(write_config(dir, "", targets))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+// Set up the configuration and process its events until the given test has completed.
+async fn run_while(dir: &Path, config: Config, test: impl Future<Output = ()>) {
+    let config_path = dir.join("container-copier.toml");
+    let env = config.setup(false, Default::default()).await.unwrap();
+    tokio::select! {
+        result = env.run(&config_path, None) => {
+            panic!("stopped processing events: {result:?}")
+        }
+        _ = test => {}
+    }
+}
+
+// Wait for the target to have the given contents, failing the test if it takes too long.
+async fn wait_for_target(dir: &Path, path: &str, contents: &str) {
+    let wait = async {
+        while read_target(dir, path).as_deref() != Some(contents) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {path:?} to contain {contents:?}"));
+}
+
+#[tokio::test]
+async fn config_parsed_from_str_is_copied_once() {
+    let dir = test_dir("config_parsed_from_str_is_copied_once");
+    write_source(&dir, "app.crt", "app");
+
+    let config = config(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    assert!(config.check().is_empty());
+
+    let options = RunOptions {
+        once: true,
+        ..Default::default()
+    };
+    config
+        .run(&dir.join("container-copier.toml"), options)
+        .await
+        .unwrap();
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+}
+
+#[tokio::test]
+async fn missing_target_is_copied_on_setup() {
+    let dir = test_dir("missing_target_is_copied_on_setup");
+    write_source(&dir, "app.crt", "app");
+
+    let config = config(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    run_while(&dir, config, wait_for_target(&dir, "app.crt", "app")).await;
+}
+
+#[tokio::test]
+async fn modified_source_is_copied() {
+    let dir = test_dir("modified_source_is_copied");
+    write_source(&dir, "app.crt", "one");
+
+    let config = config(
+        &dir,
+        "copy_on_start = \"never\"\n\n[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    run_while(&dir, config, async {
+        // Nothing is copied until the source changes.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(read_target(&dir, "app.crt"), None);
+
+        write_source(&dir, "app.crt", "two");
+        wait_for_target(&dir, "app.crt", "two").await;
+
+        write_source(&dir, "app.crt", "three");
+        wait_for_target(&dir, "app.crt", "three").await;
+    })
+    .await;
+}
+
+// Change the source of the 'source_modified_during_setup' test once its initial copy has been
+// made, before the setup goes on to the next target.
+fn change_source_after_copy(source: &Path, target: &Path) {
+    if !source.ends_with("source_modified_during_setup/source/app.crt") {
+        return;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while std::fs::read_to_string(target).ok().as_deref() != Some("one") {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting for initial copy"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    std::fs::write(source, "two").unwrap();
+}
+
+// The copies run on another worker while the setup waits in the hook, so that the source is
+// changed after it has been copied. The change is only seen if the source was already being
+// watched.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn source_modified_during_setup_is_copied_again() {
+    let dir = test_dir("source_modified_during_setup");
+    write_source(&dir, "app.crt", "one");
+    container_copier::set_initial_copy_hook(change_source_after_copy);
+
+    let config = config(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    run_while(&dir, config, wait_for_target(&dir, "app.crt", "two")).await;
+}
@@ -0,0 +1,33 @@
+//! Tests for skipping sources that are larger than the 'max_size' of their target.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn source_larger_than_max_size_is_skipped() {
+    let dir = test_dir("source_larger_than_max_size_is_skipped");
+    write_source(&dir, "small.log", "small");
+    write_source(&dir, "large.log", &"x".repeat(2000));
+
+    let output = run_once(
+        &dir,
+        "max_size = \"1KB\"\n[[copysets.targets]]\nsource = \"*.log\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "small.log").as_deref(), Some("small"));
+    assert_eq!(read_target(&dir, "large.log"), None);
+}
+
+#[test]
+fn target_max_size_overrides_copyset() {
+    let dir = test_dir("target_max_size_overrides_copyset");
+    write_source(&dir, "large.log", &"x".repeat(2000));
+
+    let output = run_once(
+        &dir,
+        "max_size = \"1KB\"\n[[copysets.targets]]\nsource = \"large.log\"\nmax_size = \"1MiB\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "large.log").map(|s| s.len()), Some(2000));
+}
@@ -0,0 +1,36 @@
+//! Tests for cloning or linking the source rather than copying it.
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn links_target_to_source() {
+    let dir = test_dir("links_target_to_source");
+    write_source(&dir, "image.iso", "contents");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"image.iso\"\nmethod = \"hardlink\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let source = std::fs::metadata(dir.join("source/image.iso")).unwrap();
+    let target = std::fs::metadata(dir.join("target/image.iso")).unwrap();
+    assert_eq!(source.ino(), target.ino());
+}
+
+#[test]
+fn rejects_mode_on_hard_link() {
+    let dir = test_dir("rejects_mode_on_hard_link");
+    write_source(&dir, "image.iso", "contents");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"image.iso\"\nmethod = \"hardlink\"\nmode = \"0600\"\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "image.iso"), None);
+}
@@ -0,0 +1,44 @@
+//! Tests for targets whose source does not exist when the copier starts.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn copies_source_once_created() {
+    let dir = test_dir("copies_source_once_created");
+    let _watcher = watch(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    assert_eq!(read_target(&dir, "app.crt"), None);
+
+    write_source(&dir, "app.crt", "one");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("one"));
+
+    // Once the source exists, it is watched directly.
+    write_source(&dir, "app.crt", "two");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("two"));
+}
+
+#[test]
+fn copies_source_once_created_again() {
+    let dir = test_dir("copies_source_once_created_again");
+    write_source(&dir, "app.crt", "one");
+    let _watcher = watch(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("one"));
+
+    // A source that is deleted while we are running is waited for in the same way as one that is
+    // missing at startup.
+    std::fs::remove_file(dir.join("source/app.crt")).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    write_source(&dir, "app.crt", "two");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("two"));
+
+    write_source(&dir, "app.crt", "three");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("three"));
+}
+
+#[test]
+fn once_fails_for_missing_source() {
+    let dir = test_dir("once_fails_for_missing_source");
+    let output = run_once(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    assert!(!output.status.success(), "{output:?}");
+}
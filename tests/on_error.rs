@@ -0,0 +1,56 @@
+//! Tests for what happens when copying a target fails, according to its 'on_error' policy.
+
+mod common;
+
+use std::{fs, process::Command, time::Duration};
+
+use common::{read_target, run_once, test_dir, write_config, write_source};
+
+#[test]
+fn retry_copies_target_again() {
+    let dir = test_dir("retry_copies_target_again");
+    write_source(&dir, "app.crt", "app");
+
+    // The target cannot be copied while a file is in the way of its directory.
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join("target/certs"), "").unwrap();
+    let blocker = dir.join("target/certs");
+    let unblock = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        fs::remove_file(blocker).unwrap();
+    });
+
+    let output = run_once(
+        &dir,
+        "on_error = \"retry\"\n\n[[copysets.targets]]\nsource = \"app.crt\"\n\
+         target = \"certs/app.crt\"\n",
+    );
+    unblock.join().unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "certs/app.crt").as_deref(), Some("app"));
+}
+
+#[test]
+fn fatal_stops_watching() {
+    let dir = test_dir("fatal_stops_watching");
+    write_source(&dir, "app.crt", "app");
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join("target/certs"), "").unwrap();
+
+    let config_path = write_config(
+        &dir,
+        "",
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"certs/app.crt\"\n\
+         on_error = \"fatal\"\n",
+    );
+
+    // Without '--once' the copier would keep watching, so it only exits because of the failure.
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(&config_path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("'on_error' is 'fatal'"));
+}
@@ -0,0 +1,41 @@
+//! Tests for targets that are only copied on the first event.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{read_target, test_dir, wait_until, watch, write_source};
+
+fn copied_once(name: &str, options: &str) {
+    let dir = test_dir(name);
+    write_source(&dir, "app.crt", "one");
+
+    let watcher = watch(
+        &dir,
+        &format!(
+            "events = [\"CLOSE_WRITE\"]\nflags = [\"ONESHOT\"]\ncopy_on_start = \"never\"\n{options}\
+             [[copysets.targets]]\nsource = \"app.crt\"\n"
+        ),
+    );
+
+    write_source(&dir, "app.crt", "two");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("two"));
+
+    // Neither the source nor the directory that it is in are watched any longer.
+    wait_until(|| watcher.kernel_watches() == 0);
+
+    // So further changes are not copied.
+    write_source(&dir, "app.crt", "three");
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("two"));
+}
+
+#[test]
+fn oneshot_is_only_copied_once() {
+    copied_once("oneshot_is_only_copied_once", "");
+}
+
+#[test]
+fn debounced_oneshot_is_copied() {
+    copied_once("debounced_oneshot_is_copied", "debounce_ms = 100\n");
+}
@@ -0,0 +1,94 @@
+//! Tests for the 'only_if' condition on targets, which decides whether to copy over a target that
+//! already exists.
+
+mod common;
+
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use common::{read_target, run_once, test_dir, write_source};
+
+fn write_target(dir: &Path, path: &str, contents: &str, modified: SystemTime) {
+    let path = dir.join("target").join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, contents).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+fn targets(only_if: &str) -> String {
+    format!(
+        "copy_on_start = \"always\"\n[[copysets.targets]]\nsource = \"app.conf\"\nonly_if = \"{only_if}\"\n"
+    )
+}
+
+#[test]
+fn always_copies_over_newer_target() {
+    let dir = test_dir("always_copies_over_newer_target");
+    write_source(&dir, "app.conf", "source");
+    write_target(
+        &dir,
+        "app.conf",
+        "target",
+        SystemTime::now() + Duration::from_secs(60),
+    );
+
+    let output = run_once(&dir, &targets("always"));
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.conf").as_deref(), Some("source"));
+}
+
+#[test]
+fn newer_keeps_newer_target() {
+    let dir = test_dir("newer_keeps_newer_target");
+    write_source(&dir, "app.conf", "source");
+    write_target(
+        &dir,
+        "app.conf",
+        "target",
+        SystemTime::now() + Duration::from_secs(60),
+    );
+
+    let output = run_once(&dir, &targets("newer"));
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.conf").as_deref(), Some("target"));
+}
+
+#[test]
+fn newer_copies_over_older_target() {
+    let dir = test_dir("newer_copies_over_older_target");
+    write_source(&dir, "app.conf", "source");
+    write_target(
+        &dir,
+        "app.conf",
+        "target",
+        SystemTime::now() - Duration::from_secs(60),
+    );
+
+    let output = run_once(&dir, &targets("newer"));
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.conf").as_deref(), Some("source"));
+}
+
+#[test]
+fn missing_keeps_existing_target() {
+    let dir = test_dir("missing_keeps_existing_target");
+    write_source(&dir, "app.conf", "source");
+    write_target(
+        &dir,
+        "app.conf",
+        "target",
+        SystemTime::now() - Duration::from_secs(60),
+    );
+
+    let output = run_once(&dir, &targets("missing"));
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.conf").as_deref(), Some("target"));
+}
@@ -0,0 +1,25 @@
+//! Tests for targets that copy to the same place as each other.
+
+mod common;
+
+use common::{run_once, test_dir, write_source};
+
+#[test]
+fn warns_about_overlapping_targets() {
+    let dir = test_dir("warns_about_overlapping_targets");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "ca.crt", "ca");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"tls.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"ca.crt\"\ntarget = \"tls.crt\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let logs = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        logs.contains("Target is also copied to by another target"),
+        "{logs}"
+    );
+}
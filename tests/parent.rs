@@ -0,0 +1,24 @@
+//! Tests for the directory that holds a target.
+
+mod common;
+
+use std::fs;
+
+use common::{run_once, test_dir, write_source};
+
+#[test]
+fn parent_that_is_a_file_fails() {
+    let dir = test_dir("parent_that_is_a_file_fails");
+    write_source(&dir, "app.crt", "app");
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join("target/certs"), "not a directory").unwrap();
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"certs/nested/app.crt\"\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+
+    let logs = String::from_utf8_lossy(&output.stdout);
+    assert!(logs.contains("Target parent is not a directory"), "{logs}");
+}
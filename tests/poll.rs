@@ -0,0 +1,37 @@
+//! Tests for copysets whose sources are polled for changes rather than watched with inotify.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn polled_source_is_copied_when_changed() {
+    let dir = test_dir("polled_source_is_copied_when_changed");
+    write_source(&dir, "app.crt", "app");
+
+    let _watcher = watch(
+        &dir,
+        "watch_mode = \"poll\"\npoll_interval_ms = 100\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\nmirror_delete = true\n",
+    );
+
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("app"));
+    write_source(&dir, "app.crt", "changed");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("changed"));
+
+    std::fs::remove_file(dir.join("source/app.crt")).unwrap();
+    wait_until(|| read_target(&dir, "app.crt").is_none());
+}
+
+#[test]
+fn polled_recursive_target_is_rejected() {
+    let dir = test_dir("polled_recursive_target_is_rejected");
+    write_source(&dir, "certs/app.crt", "app");
+
+    let output = run_once(
+        &dir,
+        "watch_mode = \"poll\"\n[[copysets.targets]]\nsource = \"certs\"\nrecursive = true\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "certs/app.crt"), None);
+}
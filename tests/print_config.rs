@@ -0,0 +1,62 @@
+//! Tests for printing the effective configuration.
+
+mod common;
+
+use std::{path::Path, process::Command};
+
+use common::{test_dir, write_config};
+
+fn print_config(config_path: &Path, format: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(config_path)
+        .arg(format!("--print-config={format}"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn prints_inherited_options() {
+    let dir = test_dir("prints_inherited_options");
+    let config_path = write_config(
+        &dir,
+        "",
+        "events = \"close_write|attrib\"\nmirror_delete = true\ndir_mode = \"0750\"\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\nmode = \"0600\"\nmax_size = \"1KiB\"\n",
+    );
+
+    let config: serde_json::Value =
+        serde_json::from_str(&print_config(&config_path, "json")).unwrap();
+    let target = &config["copysets"][0]["targets"][0];
+    assert_eq!(
+        target["events"],
+        serde_json::json!(["CLOSE_WRITE", "ATTRIB"])
+    );
+    assert_eq!(target["mirror_delete"], true);
+    assert_eq!(target["atomic"], true);
+    assert_eq!(target["mode"], "0600");
+    assert_eq!(target["dir_mode"], "0750");
+    assert_eq!(target["max_size"], 1024);
+    assert_eq!(
+        config["default_events"],
+        serde_json::json!(["CREATE", "DELETE", "MODIFY"])
+    );
+}
+
+#[test]
+fn printed_config_can_be_loaded() {
+    let dir = test_dir("printed_config_can_be_loaded");
+    let config_path = write_config(
+        &dir,
+        "",
+        "events = [\"CLOSE_WRITE\"]\n[[copysets.targets]]\nsource = \"app.crt\"\n\
+         target = [\"one.crt\", \"two.crt\"]\nowner = 0\n",
+    );
+
+    let printed = print_config(&config_path, "toml");
+    let printed_path = dir.join("printed.toml");
+    std::fs::write(&printed_path, &printed).unwrap();
+    assert_eq!(print_config(&printed_path, "toml"), printed);
+}
@@ -0,0 +1,29 @@
+//! Tests for targets whose copies are not logged.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn quiet_target_does_not_log_copies() {
+    let dir = test_dir("quiet_target_does_not_log_copies");
+    write_source(&dir, "noisy.log", "noisy");
+    write_source(&dir, "app.crt", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"noisy.log\"\nquiet = true\n\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "noisy.log").as_deref(), Some("noisy"));
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+
+    let log = String::from_utf8_lossy(&output.stdout);
+    let copied = log
+        .lines()
+        .filter(|line| line.contains("Copied"))
+        .collect::<Vec<_>>();
+    assert_eq!(copied.len(), 1, "{log}");
+    assert!(copied[0].contains("app.crt"), "{log}");
+}
@@ -0,0 +1,35 @@
+//! Tests for copying only part of the source.
+
+mod common;
+
+use common::{read_target, run_once, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn copies_only_the_range() {
+    let dir = test_dir("copies_only_the_range");
+    write_source(&dir, "blob", "headerBODYtrailer");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"blob\"\ntarget = \"body\"\noffset = 6\nlength = 4\n\n\
+         [[copysets.targets]]\nsource = \"blob\"\ntarget = \"tail\"\noffset = 10\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "body").as_deref(), Some("BODY"));
+    assert_eq!(read_target(&dir, "tail").as_deref(), Some("trailer"));
+}
+
+#[test]
+fn waits_for_source_to_grow() {
+    let dir = test_dir("waits_for_source_to_grow");
+    write_source(&dir, "app.log", "short");
+
+    let _watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.log\"\noffset = 6\nlength = 3\n",
+    );
+    assert_eq!(read_target(&dir, "app.log"), None);
+
+    write_source(&dir, "app.log", "short longer");
+    wait_until(|| read_target(&dir, "app.log").as_deref() == Some("lon"));
+}
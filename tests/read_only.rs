@@ -0,0 +1,88 @@
+//! Tests for targets on a filesystem that is mounted read-only.
+
+mod common;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use common::{read_target, test_dir, wait_until, watch, write_config, write_source};
+
+// A read-only filesystem mounted at the target, which is unmounted when it is dropped.
+struct ReadOnlyMount(PathBuf);
+
+impl ReadOnlyMount {
+    // Mount an empty read-only filesystem at the path, or return 'None' if we are not permitted to
+    // mount filesystems (such as when the tests are not run as root).
+    fn new(path: &Path) -> Option<Self> {
+        fs::create_dir_all(path).unwrap();
+        let status = Command::new("mount")
+            .args(["-t", "tmpfs", "-o", "ro", "tmpfs"])
+            .arg(path)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .ok()?;
+        status.success().then(|| Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for ReadOnlyMount {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.0).status();
+    }
+}
+
+#[test]
+fn read_only_target_is_reported() {
+    let dir = test_dir("read_only_target_is_reported");
+    write_source(&dir, "app.crt", "app");
+
+    let Some(_mount) = ReadOnlyMount::new(&dir.join("target")) else {
+        eprintln!("skipping, as mounting a filesystem is not permitted");
+        return;
+    };
+
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(write_config(
+            &dir,
+            "",
+            "[[copysets.targets]]\nsource = \"app.crt\"\n",
+        ))
+        .arg("--once")
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{output:?}");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let message = format!(
+        "Target filesystem is read-only: {}",
+        dir.join("target/app.crt").display()
+    );
+    assert!(stdout.contains(&message), "{stdout}");
+}
+
+#[test]
+fn read_only_target_is_skipped() {
+    let dir = test_dir("read_only_target_is_skipped");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "key");
+
+    let Some(_mount) = ReadOnlyMount::new(&dir.join("target/certs")) else {
+        eprintln!("skipping, as mounting a filesystem is not permitted");
+        return;
+    };
+
+    // The copier keeps running after failing to copy to the read-only target, and goes on to copy
+    // the other target when its source changes.
+    let _watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"certs/app.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"app.key\"\n",
+    );
+
+    write_source(&dir, "app.key", "new key");
+    wait_until(|| read_target(&dir, "app.key").as_deref() == Some("new key"));
+}
@@ -0,0 +1,37 @@
+//! Tests for relative paths that are resolved against the directory of the configuration file.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, run_config_once, test_dir, write_source};
+
+#[test]
+fn resolves_paths_relative_to_config() {
+    let dir = test_dir("resolves_paths_relative_to_config");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "key");
+
+    // The included copyset's paths are relative to the directory of the file that it is in.
+    fs::create_dir_all(dir.join("conf.d")).unwrap();
+    fs::write(
+        dir.join("conf.d/keys.toml"),
+        "[[copysets]]\nname = \"keys\"\nsource = \"../source\"\ntarget = \"../target\"\n\n\
+         [[copysets.targets]]\nsource = \"app.key\"\n",
+    )
+    .unwrap();
+
+    let config_path = dir.join("config.toml");
+    fs::write(
+        &config_path,
+        "relative_to_config = true\ninclude = [\"conf.d/*.toml\"]\n\n[[copysets]]\n\
+         name = \"certs\"\nsource = \"source\"\ntarget = \"target\"\n\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\n",
+    )
+    .unwrap();
+
+    let output = run_config_once(&config_path);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+    assert_eq!(read_target(&dir, "app.key").as_deref(), Some("key"));
+}
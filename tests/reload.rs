@@ -0,0 +1,48 @@
+//! Tests for reloading the configuration while watching.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{read_target, test_dir, wait_until, watch, write_config, write_source};
+
+#[test]
+fn removed_targets_release_their_watches() {
+    let dir = test_dir("removed_targets_release_their_watches");
+    write_source(&dir, "app.crt", "one");
+    write_source(&dir, "keys/app.key", "key");
+
+    // The first two targets have the same source, and so share a watch. Each source is watched
+    // along with the directory that it is in.
+    let watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"a.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"b.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"keys/app.key\"\n",
+    );
+    wait_until(|| read_target(&dir, "b.crt").as_deref() == Some("one"));
+    assert_eq!(watcher.kernel_watches(), 4);
+
+    write_config(
+        &dir,
+        "",
+        "[[copysets.targets]]\nsource = \"app.crt\"\ntarget = \"a.crt\"\n",
+    );
+    watcher.signal("HUP");
+
+    // The watches of the key and its directory are released, while the remaining target keeps the
+    // watch that it shared.
+    wait_until(|| watcher.kernel_watches() == 2);
+    write_source(&dir, "app.crt", "two");
+    wait_until(|| read_target(&dir, "a.crt").as_deref() == Some("two"));
+    assert_eq!(read_target(&dir, "b.crt").as_deref(), Some("one"));
+
+    // Without any copysets, nothing is watched at all.
+    std::fs::write(dir.join("config.toml"), "").unwrap();
+    watcher.signal("HUP");
+    wait_until(|| watcher.kernel_watches() == 0);
+
+    write_source(&dir, "app.crt", "three");
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(read_target(&dir, "a.crt").as_deref(), Some("two"));
+}
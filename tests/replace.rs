@@ -0,0 +1,70 @@
+//! Tests for sources that are replaced by moving another file into their place.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn source_replaced_by_move_is_copied() {
+    let dir = test_dir("source_replaced_by_move_is_copied");
+    write_source(&dir, "app.crt", "one");
+
+    let _watcher = watch(&dir, "[[copysets.targets]]\nsource = \"app.crt\"\n");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("one"));
+
+    // Keeping the replaced file open means that the kernel does not remove its watch, so only the
+    // move into place tells us that the source has changed.
+    let _replaced = fs::File::open(dir.join("source/app.crt")).unwrap();
+    write_source(&dir, "app.crt.tmp", "two");
+    fs::rename(dir.join("source/app.crt.tmp"), dir.join("source/app.crt")).unwrap();
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("two"));
+
+    // The watch follows the file that was moved into place.
+    write_source(&dir, "app.crt", "three");
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("three"));
+}
+
+#[test]
+fn source_moved_and_deleted_is_not_fatal() {
+    let dir = test_dir("source_moved_and_deleted_is_not_fatal");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "one");
+
+    let watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\n\n[[copysets.targets]]\nsource = \"app.key\"\n",
+    );
+    wait_until(|| read_target(&dir, "app.key").as_deref() == Some("one"));
+
+    // The copier is stopped while the source is moved and deleted, so that the watch on the moved
+    // file is gone by the time that we hear about the move, and so cannot be removed. We carry on
+    // watching the other sources regardless.
+    watcher.signal("STOP");
+    fs::rename(dir.join("source/app.crt"), dir.join("moved.crt")).unwrap();
+    fs::remove_file(dir.join("moved.crt")).unwrap();
+    watcher.signal("CONT");
+    write_source(&dir, "app.key", "two");
+    wait_until(|| read_target(&dir, "app.key").as_deref() == Some("two"));
+}
+
+#[test]
+fn directory_moved_and_deleted_is_not_fatal() {
+    let dir = test_dir("directory_moved_and_deleted_is_not_fatal");
+    write_source(&dir, "logs/old/app.log", "app");
+    write_source(&dir, "logs/app.log", "one");
+
+    let watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"logs\"\nrecursive = true\n",
+    );
+    wait_until(|| read_target(&dir, "logs/app.log").as_deref() == Some("one"));
+
+    watcher.signal("STOP");
+    fs::rename(dir.join("source/logs/old"), dir.join("old")).unwrap();
+    fs::remove_dir_all(dir.join("old")).unwrap();
+    watcher.signal("CONT");
+    write_source(&dir, "logs/app.log", "two");
+    wait_until(|| read_target(&dir, "logs/app.log").as_deref() == Some("two"));
+}
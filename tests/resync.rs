@@ -0,0 +1,26 @@
+//! Tests for periodically copying every target that has drifted from its source.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, test_dir, wait_until, watch_config, write_config, write_source};
+
+#[test]
+fn resync_restores_changed_target() {
+    let dir = test_dir("resync_restores_changed_target");
+    write_source(&dir, "app.crt", "app");
+
+    let config_path = write_config(
+        &dir,
+        "resync_interval_ms = 200\nresync_jitter_ms = 100\n\n",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+
+    let _watcher = watch_config(&config_path);
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("app"));
+
+    // Only the source is watched, so nothing but the resync notices the target changing.
+    fs::write(dir.join("target/app.crt"), "drifted").unwrap();
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("app"));
+}
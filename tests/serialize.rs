@@ -0,0 +1,38 @@
+//! Tests for events that arrive while a target is being copied.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{read_target, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn deletion_during_copy_removes_target() {
+    let dir = test_dir("deletion_during_copy_removes_target");
+    write_source(&dir, "app.bin", &"x".repeat(192 * 1024));
+
+    // The copy is throttled so that it is still in progress when the source is deleted.
+    let _watcher = watch(
+        &dir,
+        "rate_limit = \"64KiB\"\n\
+         [[copysets.targets]]\nsource = \"app.bin\"\nmirror_delete = true\n",
+    );
+
+    std::fs::remove_file(dir.join("source/app.bin")).unwrap();
+    std::thread::sleep(Duration::from_secs(3));
+    wait_until(|| read_target(&dir, "app.bin").is_none());
+}
+
+#[test]
+fn change_during_copy_copies_again() {
+    let dir = test_dir("change_during_copy_copies_again");
+    write_source(&dir, "app.bin", &"x".repeat(192 * 1024));
+
+    let _watcher = watch(
+        &dir,
+        "rate_limit = \"64KiB\"\n[[copysets.targets]]\nsource = \"app.bin\"\n",
+    );
+
+    write_source(&dir, "app.bin", "changed");
+    wait_until(|| read_target(&dir, "app.bin").as_deref() == Some("changed"));
+}
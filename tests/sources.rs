@@ -0,0 +1,86 @@
+//! Tests for copysets with several sources, where each target is copied from the first of them
+//! that has its source.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use common::{read_target, run_config_once, test_dir, wait_until, watch_config};
+
+// Write a configuration with a copyset whose sources are the 'primary' and 'fallback' directories,
+// in that order.
+fn write_config(dir: &Path, targets: &str) -> std::path::PathBuf {
+    fs::create_dir_all(dir.join("primary")).unwrap();
+    fs::create_dir_all(dir.join("fallback")).unwrap();
+
+    let config = format!(
+        "[[copysets]]\nname = \"test\"\nsource = [\"{}\", \"{}\"]\ntarget = \"{}\"\n{targets}",
+        dir.join("primary").display(),
+        dir.join("fallback").display(),
+        dir.join("target").display()
+    );
+
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, config).unwrap();
+    config_path
+}
+
+#[test]
+fn copies_from_first_source_that_exists() {
+    let dir = test_dir("copies_from_first_source_that_exists");
+    let config = write_config(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"ca.crt\"\n",
+    );
+    fs::write(dir.join("primary/app.crt"), "primary app").unwrap();
+    fs::write(dir.join("fallback/app.crt"), "fallback app").unwrap();
+    fs::write(dir.join("fallback/ca.crt"), "fallback ca").unwrap();
+
+    let output = run_config_once(&config);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("primary app"));
+    assert_eq!(read_target(&dir, "ca.crt").as_deref(), Some("fallback ca"));
+}
+
+#[test]
+fn falls_back_when_source_is_removed() {
+    let dir = test_dir("falls_back_when_source_is_removed");
+    let config = write_config(
+        &dir,
+        "mirror_delete = true\n\n[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+    fs::write(dir.join("primary/app.crt"), "primary").unwrap();
+    fs::write(dir.join("fallback/app.crt"), "fallback").unwrap();
+
+    let _watcher = watch_config(&config);
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("primary"));
+
+    fs::remove_file(dir.join("primary/app.crt")).unwrap();
+    wait_until(|| read_target(&dir, "app.crt").as_deref() == Some("fallback"));
+}
+
+#[test]
+fn empty_list_of_sources_fails() {
+    let dir = test_dir("empty_list_of_sources_fails");
+    let config_path = dir.join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "[[copysets]]\nname = \"test\"\nsource = []\ntarget = \"{}\"\n\n\
+             [[copysets.targets]]\nsource = \"app.crt\"\n",
+            dir.join("target").display()
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--check")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("has an empty list of sources"), "{stderr}");
+}
@@ -0,0 +1,38 @@
+//! Tests for sources that are special files, such as named pipes.
+
+mod common;
+
+use std::process::Command;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+fn mkfifo(path: &std::path::Path) {
+    let status = Command::new("mkfifo").arg(path).status().unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn named_pipe_source_is_refused() {
+    let dir = test_dir("named_pipe_source_is_refused");
+    mkfifo(&dir.join("source/app.pipe"));
+
+    let output = run_once(&dir, "[[copysets.targets]]\nsource = \"app.pipe\"\n");
+    assert!(!output.status.success(), "{output:?}");
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert!(log.contains("named pipe"), "{log}");
+}
+
+#[test]
+fn named_pipe_in_directory_is_skipped() {
+    let dir = test_dir("named_pipe_in_directory_is_skipped");
+    write_source(&dir, "certs/app.crt", "cert");
+    mkfifo(&dir.join("source/certs/app.pipe"));
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"certs\"\nrecursive = true\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "certs/app.crt").as_deref(), Some("cert"));
+    assert!(!dir.join("target/certs/app.pipe").exists());
+}
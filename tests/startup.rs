@@ -0,0 +1,25 @@
+//! Tests for delaying startup before the initial copies are made.
+
+mod common;
+
+use std::time::Instant;
+
+use common::{read_target, run_config_once, test_dir, write_config, write_source};
+
+#[test]
+fn initial_copies_are_delayed() {
+    let dir = test_dir("initial_copies_are_delayed");
+    write_source(&dir, "app.crt", "app");
+
+    let config_path = write_config(
+        &dir,
+        "startup_delay_ms = 300\nstartup_jitter_ms = 200\n\n",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n",
+    );
+
+    let started = Instant::now();
+    let output = run_config_once(&config_path);
+    assert!(output.status.success(), "{output:?}");
+    assert!(started.elapsed().as_millis() >= 300);
+    assert_eq!(read_target(&dir, "app.crt").as_deref(), Some("app"));
+}
@@ -0,0 +1,76 @@
+//! Tests for the summaries that are logged on startup and on exit.
+
+mod common;
+
+use std::{path::Path, process::Command};
+
+use common::{test_dir, write_config, write_source};
+
+// Run the configuration once with JSON logs, returning each line of the log.
+fn run_json(config_path: &Path) -> Vec<serde_json::Value> {
+    let output = Command::new(env!("CARGO_BIN_EXE_container-copier"))
+        .arg("--config")
+        .arg(config_path)
+        .arg("--once")
+        .env("LOG_FORMAT", "json")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+// Find the line of the log with the given message.
+fn find<'a>(log: &'a [serde_json::Value], message: &str) -> &'a serde_json::Value {
+    log.iter()
+        .find(|line| line["message"] == message)
+        .unwrap_or_else(|| panic!("no {message:?} in {log:?}"))
+}
+
+#[test]
+fn logs_version_and_configuration_on_startup() {
+    let dir = test_dir("logs_version_and_configuration_on_startup");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "secret");
+
+    let config_path = write_config(
+        &dir,
+        "",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"app.key\"\n",
+    );
+
+    let log = run_json(&config_path);
+    let started = find(&log, "Starting container-copier");
+    assert_eq!(started["version"], env!("CARGO_PKG_VERSION"), "{started}");
+
+    let loaded = find(&log, "Loaded configuration");
+    assert_eq!(loaded["copysets"], 1, "{loaded}");
+    assert_eq!(loaded["targets"], 2, "{loaded}");
+
+    // Each source is watched, along with the directory that they share.
+    assert_eq!(loaded["watches"], 3, "{loaded}");
+}
+
+#[test]
+fn logs_summary_on_exit() {
+    let dir = test_dir("logs_summary_on_exit");
+    write_source(&dir, "app.crt", "app");
+    write_source(&dir, "app.key", "secret");
+
+    let config_path = write_config(
+        &dir,
+        "",
+        "[[copysets.targets]]\nsource = \"app.crt\"\n\n\
+         [[copysets.targets]]\nsource = \"app.key\"\n",
+    );
+
+    let log = run_json(&config_path);
+    let summary = find(&log, "Run summary");
+    assert_eq!(summary["copies"], 2, "{summary}");
+    assert_eq!(summary["failures"], 0, "{summary}");
+    assert_eq!(summary["bytes"], 9, "{summary}");
+}
@@ -0,0 +1,116 @@
+//! Tests for targets with placeholders in their paths, which are expanded for each copy.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, run_once, test_dir, wait_until, watch, write_source};
+
+#[test]
+fn counter_gives_each_copy_a_new_file() {
+    let dir = test_dir("counter_gives_each_copy_a_new_file");
+    let targets = "copy_on_start = \"always\"\n[[copysets.targets]]\nsource = \"app.bin\"\n\
+                   target = \"history/{basename}.{n}\"\nlatest = \"history/latest\"\n";
+
+    write_source(&dir, "app.bin", "first");
+    let output = run_once(&dir, targets);
+    assert!(output.status.success(), "{output:?}");
+
+    write_source(&dir, "app.bin", "second");
+    let output = run_once(&dir, targets);
+    assert!(output.status.success(), "{output:?}");
+
+    assert_eq!(
+        read_target(&dir, "history/app.bin.1").as_deref(),
+        Some("first")
+    );
+    assert_eq!(
+        read_target(&dir, "history/app.bin.2").as_deref(),
+        Some("second")
+    );
+    assert_eq!(
+        read_target(&dir, "history/latest").as_deref(),
+        Some("second")
+    );
+    assert_eq!(
+        fs::read_link(dir.join("target/history/latest")).unwrap(),
+        std::path::Path::new("app.bin.2")
+    );
+}
+
+#[test]
+fn date_is_expanded() {
+    let dir = test_dir("date_is_expanded");
+    write_source(&dir, "app.bin", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.bin\"\ntarget = \"app-{date}.bin\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let names = fs::read_dir(dir.join("target"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names.len(), 1, "{names:?}");
+
+    // The name should be 'app-YYYY-MM-DD.bin'.
+    let date = names[0]
+        .strip_prefix("app-")
+        .and_then(|name| name.strip_suffix(".bin"))
+        .unwrap();
+    assert_eq!(date.len(), 10, "{date}");
+    assert!(date
+        .chars()
+        .enumerate()
+        .all(|(index, c)| if index == 4 || index == 7 {
+            c == '-'
+        } else {
+            c.is_ascii_digit()
+        }));
+}
+
+#[test]
+fn templated_target_cannot_mirror_deletions() {
+    let dir = test_dir("templated_target_cannot_mirror_deletions");
+    write_source(&dir, "app.bin", "app");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.bin\"\ntarget = \"app-{unix}.bin\"\nmirror_delete = true\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+}
+
+#[test]
+fn keep_removes_older_copies() {
+    let dir = test_dir("keep_removes_older_copies");
+    write_source(&dir, "app.bin", "v0");
+    fs::create_dir_all(dir.join("target")).unwrap();
+    fs::write(dir.join("target/app.bin.1"), "not ours").unwrap();
+
+    let _watcher = watch(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.bin\"\ntarget = \"app.bin.{n}\"\n\
+         latest = \"app.bin\"\nkeep = 2\n",
+    );
+
+    for version in ["v1", "v2", "v3"] {
+        write_source(&dir, "app.bin", version);
+        wait_until(|| read_target(&dir, "app.bin").as_deref() == Some(version));
+    }
+
+    // Only the two most recent copies that we made are kept, along with the file that was already
+    // there.
+    let copies = |dir: &std::path::Path| {
+        fs::read_dir(dir.join("target"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "app.bin" && name != "app.bin.1")
+            .count()
+    };
+
+    wait_until(|| copies(&dir) == 2);
+    assert_eq!(read_target(&dir, "app.bin.1").as_deref(), Some("not ours"));
+}
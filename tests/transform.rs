@@ -0,0 +1,65 @@
+//! Tests for transforming the contents of the source on their way to the target.
+
+mod common;
+
+use std::fs;
+
+use common::{read_target, run_once, test_dir, write_source};
+
+#[test]
+fn base64_round_trip() {
+    let dir = test_dir("base64_round_trip");
+    write_source(&dir, "secret.b64", "c2VjcmV0\nIHZhbHVl\n");
+    write_source(&dir, "plain.txt", "secret value");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"secret.b64\"\ntarget = \"secret\"\n\
+         transform = \"base64-decode\"\nverify = true\n\n\
+         [[copysets.targets]]\nsource = \"plain.txt\"\ntransform = \"base64-encode\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(read_target(&dir, "secret").as_deref(), Some("secret value"));
+    assert_eq!(
+        read_target(&dir, "plain.txt").as_deref(),
+        Some("c2VjcmV0IHZhbHVl")
+    );
+}
+
+#[test]
+fn gzip_round_trip() {
+    let dir = test_dir("gzip_round_trip");
+    write_source(&dir, "app.log", "some log lines");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.log\"\ntarget = \"app.log.gz\"\ntransform = \"gzip\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    let compressed = fs::read(dir.join("target/app.log.gz")).unwrap();
+    assert_eq!(compressed[..2], [0x1f, 0x8b]);
+
+    // Decompress the copy by copying it back through 'gunzip'.
+    fs::copy(dir.join("target/app.log.gz"), dir.join("source/app.log.gz")).unwrap();
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"app.log.gz\"\ntarget = \"app.txt\"\ntransform = \"gunzip\"\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        read_target(&dir, "app.txt").as_deref(),
+        Some("some log lines")
+    );
+}
+
+#[test]
+fn invalid_base64_fails() {
+    let dir = test_dir("invalid_base64_fails");
+    write_source(&dir, "secret.b64", "not base64!");
+
+    let output = run_once(
+        &dir,
+        "[[copysets.targets]]\nsource = \"secret.b64\"\ntransform = \"base64-decode\"\n",
+    );
+    assert!(!output.status.success(), "{output:?}");
+}
@@ -0,0 +1,75 @@
+//! Tests for reading targets back after they are copied.
+
+mod common;
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    sync::{atomic::AtomicUsize, atomic::Ordering, Mutex},
+};
+
+use container_copier::{Config, RunOptions};
+
+use common::{read_target, test_dir, write_config, write_source};
+
+// The number of times that the target has been written, and everything that has been logged.
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+static LOGS: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        LOGS.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Change the target each time it is written, so that it never matches its source.
+fn corrupt_target(target: &Path) {
+    WRITES.fetch_add(1, Ordering::SeqCst);
+    fs::write(target, "corrupted").unwrap();
+}
+
+#[tokio::test]
+async fn mismatched_target_fails() {
+    let dir = test_dir("mismatched_target_fails");
+    write_source(&dir, "app.key", "key");
+
+    container_copier::set_target_written_hook(corrupt_target);
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(|| LogWriter)
+        .init();
+
+    let config_path = write_config(
+        &dir,
+        "",
+        "[[copysets.targets]]\nsource = \"app.key\"\nverify = true\n",
+    );
+    let config = Config::load(&config_path).unwrap();
+    let options = RunOptions {
+        once: true,
+        ..Default::default()
+    };
+    assert!(config.run(&config_path, options).await.is_err());
+
+    // The target is copied once more after the first mismatch, and then given up on.
+    assert_eq!(WRITES.load(Ordering::SeqCst), 2);
+    assert_eq!(read_target(&dir, "app.key").as_deref(), Some("corrupted"));
+
+    let logs = String::from_utf8(LOGS.lock().unwrap().clone()).unwrap();
+    assert!(
+        logs.contains("Target does not match the source after copying; copying again"),
+        "{logs}"
+    );
+    assert!(
+        logs.contains("Target still does not match the source after copying again"),
+        "{logs}"
+    );
+}